@@ -0,0 +1,84 @@
+//! Physical addresses of the pages handed out by the allocator
+
+use core::fmt;
+
+/**
+ * Size in bytes of a 4Kb frame
+ */
+pub const FRAME_SIZE: u64 = 4096;
+/**
+ * Size in bytes of a 2Mb big page
+ */
+pub const BIG_PAGE_SIZE: u64 = 512 * FRAME_SIZE;
+/**
+ * Size in bytes of a 1Gb huge page
+ */
+pub const HUGE_PAGE_SIZE: u64 = 512 * BIG_PAGE_SIZE;
+
+/**
+ * Physical memory address
+ */
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct PhysAddr(u64);
+
+impl PhysAddr {
+    pub const fn new(addr: u64) -> Self {
+        PhysAddr(addr)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /**
+     * Return true if the address is a multiple of `align` (a power of two)
+     */
+    pub const fn is_aligned(self, align: u64) -> bool {
+        assert!(align.is_power_of_two());
+        self.0 & (align - 1) == 0
+    }
+
+    /**
+     * Round the address down to a multiple of `align` (a power of two)
+     */
+    pub const fn align_down(self, align: u64) -> Self {
+        assert!(align.is_power_of_two());
+        PhysAddr(self.0 & !(align - 1))
+    }
+
+    /**
+     * Round the address up to a multiple of `align` (a power of two)
+     */
+    pub const fn align_up(self, align: u64) -> Self {
+        assert!(align.is_power_of_two());
+        PhysAddr((self.0 + align - 1) & !(align - 1))
+    }
+}
+
+impl From<PhysAddr> for u64 {
+    fn from(addr: PhysAddr) -> u64 {
+        addr.0
+    }
+}
+
+impl fmt::Debug for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PhysAddr({:#x})", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alignment() {
+        let addr = PhysAddr::new(0x4020_1000);
+        assert!(addr.is_aligned(FRAME_SIZE));
+        assert!(!addr.is_aligned(BIG_PAGE_SIZE));
+        assert_eq!(addr.align_down(BIG_PAGE_SIZE), PhysAddr::new(0x4020_0000));
+        assert_eq!(addr.align_up(BIG_PAGE_SIZE), PhysAddr::new(0x4040_0000));
+        assert_eq!(addr.align_down(HUGE_PAGE_SIZE), PhysAddr::new(0x4000_0000));
+        assert_eq!(addr.align_up(FRAME_SIZE), addr);
+    }
+}
@@ -1,40 +1,509 @@
 //! Custom buddy allocator to allocate Intel x86-64 page tables (4Kb, 2Mb and 1Gb)
-
-use std::arch::asm;
-
+//!
+//! The crate is `no_std` when the `std` feature is disabled, trees are then placed in memory
+//! provided by the caller. The `alloc` feature keeps heap allocated trees without `std`.
+//!
+//! Pages are handed out as `Frame<S>` handles by `allocate::<S>()`, see `frame.rs` for the raw
+//! frame index API.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+mod addr;
+mod cache;
+#[cfg(feature = "alloc")]
+mod concurrent;
+mod contiguous;
+mod counters;
+mod error;
+mod frame;
+mod hotplug;
+mod integrity;
+#[cfg(feature = "alloc")]
+mod numa;
+#[cfg(feature = "alloc")]
+mod occupancy;
+mod owner;
+mod policy;
+mod quota;
+mod ranges;
+mod refcount;
+mod reserve;
+mod snapshot;
+mod split;
+mod storage;
+mod zone;
+
+use core::arch::asm;
+
+#[cfg(feature = "alloc")]
+use alloc::{vec, vec::Vec};
+
+pub use crate::addr::{PhysAddr, BIG_PAGE_SIZE, FRAME_SIZE, HUGE_PAGE_SIZE};
+pub use crate::cache::{
+    CacheBackend, CacheStats, FrameCache, BIG_PAGE_CACHE_SIZE, FRAME_CACHE_SIZE,
+};
+#[cfg(feature = "alloc")]
+pub use crate::concurrent::ConcurrentBuddyAllocator;
+pub use crate::error::{
+    AllocError, DeallocError, NumaError, OwnerAllocError, RefCountError, SnapshotError,
+};
+pub use crate::frame::{Frame, PageSize, Size1G, Size2M, Size4K};
+pub use crate::integrity::{Violation, ViolationKind};
+#[cfg(feature = "alloc")]
+pub use crate::numa::NumaAllocator;
+#[cfg(feature = "alloc")]
+pub use crate::occupancy::Occupancy;
+pub use crate::policy::{BestFit, Candidates, FirstFitHigh, FirstFitLow, PlacementPolicy, Random};
+pub use crate::ranges::RangeKind;
+use crate::storage::{TreeStorage, Trees};
+pub use crate::zone::Zone;
+
+#[cfg(feature = "alloc")]
 const NB_GB: usize = 512;
+#[cfg(feature = "alloc")]
 const NB_PAGES: usize = 512 * 512 * NB_GB;
-const TREE_1GB_SIZE: usize = 8;
-const TREE_2MB_SIZE: usize = TREE_1GB_SIZE + NB_GB * 512 / 64;
-const TREE_4KB_SIZE: usize = TREE_2MB_SIZE + NB_GB * 512 * 512 / 64;
+const MAX_GB: usize = 512 * 512;
+const MAX_PAGES: usize = 512 * 512 * MAX_GB;
+const LEVEL0_SIZE: usize = 8;
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TreeType {
     Tree4kb,
     Tree2mb,
     Tree1gb,
 }
 
-#[derive(Copy, Clone, PartialEq)]
+impl TreeType {
+    /**
+     * Return the number of 4Kb frames of a page of this size
+     */
+    pub(crate) fn nb_frames(self) -> usize {
+        match self {
+            TreeType::Tree4kb => 1,
+            TreeType::Tree2mb => 512,
+            TreeType::Tree1gb => 512 * 512,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Level {
+    Level0,
     Level1,
     Level2,
     Level3,
 }
 
-pub struct BuddyAllocator {
-    tree_4kb: Box<[u64; TREE_4KB_SIZE]>,
-    tree_2mb: Box<[u64; TREE_2MB_SIZE]>,
-    tree_1gb: Box<[u64; TREE_1GB_SIZE]>,
+/**
+ * Kind of a physical memory region reported by the firmware (e820/UEFI memory map)
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MemoryKind {
+    Usable,
+    Reserved,
+    Acpi,
+    Mmio,
+}
+
+/**
+ * Physical memory region reported by the firmware, `start` and `length` are in bytes
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub length: usize,
+    pub kind: MemoryKind,
+}
+
+/**
+ * Buddy allocator, `P` chooses where pages are placed (see `with_policy`)
+ */
+pub struct BuddyAllocator<P = FirstFitLow> {
+    tree_4kb: TreeStorage,
+    tree_2mb: TreeStorage,
+    tree_1gb: TreeStorage,
+    counters: TreeStorage,
+    offline: TreeStorage,
+    holes: TreeStorage,
+    refcounts: Option<TreeStorage>,
+    owners: Option<TreeStorage>,
+    quotas: Option<TreeStorage>,
+    current_owner: u16,
+    nb_gb: usize,
+    nb_pages: usize,
+    base: PhysAddr,
+    zone_free: [usize; 3],
+    free_pages: [usize; 3],
+    policy: P,
+}
+
+#[cfg(feature = "alloc")]
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BuddyAllocator {
+    /**
+     * Create an allocator managing 512Gb, see `with_capacity` for up to 256Tb
+     */
+    #[cfg(feature = "alloc")]
     pub fn new() -> Self {
-        assert!(NB_GB <= 512);
+        Self::with_capacity(NB_PAGES)
+    }
+
+    /**
+     * Create an allocator managing `num_frames` 4Kb frames
+     * trees are sized to the number of 1Gb blocks needed to cover them, frames of the last
+     * 1Gb block beyond `num_frames` are never handed out
+     */
+    #[cfg(feature = "alloc")]
+    pub fn with_capacity(num_frames: usize) -> Self {
+        let (tree_4kb, tree_2mb, tree_1gb, counters, offline, holes) =
+            Self::boxed_trees(num_frames);
+        Self::with_capacity_from_trees(
+            num_frames, tree_4kb, tree_2mb, tree_1gb, counters, offline, holes,
+        )
+    }
+
+    /**
+     * Same as `with_capacity` but trees are placed in `storage`
+     * `storage` must hold at least `storage_size(num_frames)` words
+     */
+    pub fn with_capacity_in(num_frames: usize, storage: &'static mut [u64]) -> Self {
+        let (tree_4kb, tree_2mb, tree_1gb, counters, offline, holes) =
+            Self::borrowed_trees(num_frames, storage);
+        Self::with_capacity_from_trees(
+            num_frames, tree_4kb, tree_2mb, tree_1gb, counters, offline, holes,
+        )
+    }
+
+    fn with_capacity_from_trees(
+        num_frames: usize,
+        tree_4kb: TreeStorage,
+        tree_2mb: TreeStorage,
+        tree_1gb: TreeStorage,
+        counters: TreeStorage,
+        offline: TreeStorage,
+        holes: TreeStorage,
+    ) -> Self {
+        let mut allocator = Self::with_empty_trees(
+            num_frames, tree_4kb, tree_2mb, tree_1gb, counters, offline, holes,
+        );
+        allocator.set_level3_range(0, num_frames, true);
+        allocator.build_summary_levels();
+        allocator.init_free_counters();
+
+        allocator
+    }
+
+    /**
+     * Create an allocator from a firmware memory map
+     * only 4Kb frames entirely contained in a usable region are free, any other region
+     * (reserved, ACPI, MMIO) takes precedence over an overlapping usable one
+     * frames of the holes look allocated as 4Kb frames but are reported out of range
+     * the allocator covers `memory_map_capacity(regions)` frames
+     */
+    #[cfg(feature = "alloc")]
+    pub fn from_memory_map(regions: &[MemoryRegion]) -> Self {
+        let (tree_4kb, tree_2mb, tree_1gb, counters, offline, holes) =
+            Self::boxed_trees(Self::memory_map_capacity(regions));
+        Self::from_memory_map_trees(
+            regions, tree_4kb, tree_2mb, tree_1gb, counters, offline, holes,
+        )
+    }
+
+    /**
+     * Same as `from_memory_map` but trees are placed in `storage`
+     * `storage` must hold at least `storage_size(memory_map_capacity(regions))` words
+     */
+    pub fn from_memory_map_in(regions: &[MemoryRegion], storage: &'static mut [u64]) -> Self {
+        let (tree_4kb, tree_2mb, tree_1gb, counters, offline, holes) =
+            Self::borrowed_trees(Self::memory_map_capacity(regions), storage);
+        Self::from_memory_map_trees(
+            regions, tree_4kb, tree_2mb, tree_1gb, counters, offline, holes,
+        )
+    }
+
+    fn from_memory_map_trees(
+        regions: &[MemoryRegion],
+        tree_4kb: TreeStorage,
+        tree_2mb: TreeStorage,
+        tree_1gb: TreeStorage,
+        counters: TreeStorage,
+        offline: TreeStorage,
+        holes: TreeStorage,
+    ) -> Self {
+        let num_frames = Self::memory_map_capacity(regions);
+        let mut allocator = Self::with_empty_trees(
+            num_frames, tree_4kb, tree_2mb, tree_1gb, counters, offline, holes,
+        );
+        for region in regions
+            .iter()
+            .filter(|region| region.kind == MemoryKind::Usable)
+        {
+            let start = region.start.div_ceil(4096).min(num_frames);
+            let end = (region.start.saturating_add(region.length) / 4096).min(num_frames);
+            allocator.set_level3_range(start, end, true);
+        }
+        for region in regions
+            .iter()
+            .filter(|region| region.kind != MemoryKind::Usable)
+        {
+            let start = (region.start / 4096).min(num_frames);
+            let end = region
+                .start
+                .saturating_add(region.length)
+                .div_ceil(4096)
+                .min(num_frames);
+            allocator.set_level3_range(start, end, false);
+        }
+        // frames left allocated are holes, they are never freed
+        let first_block_l3 = allocator.compute_first_block_index(0, 0, Level::Level3);
+        for i in 0..allocator.holes.len() {
+            allocator.holes[i] = !allocator.tree_4kb[first_block_l3 + i];
+        }
+        if !num_frames.is_multiple_of(64) {
+            *allocator.holes.last_mut().unwrap() &= (1u64 << (num_frames % 64)) - 1;
+        }
+        allocator.build_summary_levels();
+        allocator.init_free_counters();
+
+        allocator
+    }
+
+    /**
+     * Return the number of 4Kb frames covered by an allocator built from a memory map,
+     * memory is covered up to the end of the highest usable region
+     */
+    pub fn memory_map_capacity(regions: &[MemoryRegion]) -> usize {
+        regions
+            .iter()
+            .filter(|region| region.kind == MemoryKind::Usable)
+            .map(|region| region.start.saturating_add(region.length) / 4096)
+            .max()
+            .unwrap_or(0)
+            .min(MAX_PAGES)
+    }
+
+    /**
+     * Return the number of 64 bits words needed to store the trees, the free counters, the
+     * offline 1Gb blocks and the memory map holes of `num_frames` frames
+     */
+    pub fn storage_size(num_frames: usize) -> usize {
+        let (tree_4kb_size, tree_2mb_size, tree_1gb_size) = Self::trees_size(num_frames);
+        tree_4kb_size
+            + tree_2mb_size
+            + tree_1gb_size
+            + Self::counters_size(num_frames)
+            + Self::offline_size(num_frames)
+            + Self::holes_size(num_frames)
+    }
+
+    /**
+     * Return the number of 64 bits words of the memory map holes of `num_frames` frames
+     */
+    fn holes_size(num_frames: usize) -> usize {
+        num_frames.div_ceil(64)
+    }
+
+    /**
+     * Return the number of 64 bits words of each tree in the following order (4kb, 2mb, 1gb)
+     */
+    fn trees_size(num_frames: usize) -> (usize, usize, usize) {
+        let nb_gb = num_frames.div_ceil(512 * 512);
+        let tree_1gb_size = LEVEL0_SIZE + nb_gb.div_ceil(512) * 512 / 64;
+        let tree_2mb_size = tree_1gb_size + nb_gb * 512 / 64;
+        let tree_4kb_size = tree_2mb_size + nb_gb * 512 * 512 / 64;
+        (tree_4kb_size, tree_2mb_size, tree_1gb_size)
+    }
+
+    /**
+     * Return the trees, the free counters, the offline 1Gb blocks and the memory map holes, in
+     * the following order (4kb, 2mb, 1gb, counters, offline, holes)
+     */
+    #[cfg(feature = "alloc")]
+    fn boxed_trees(num_frames: usize) -> Trees {
+        let (tree_4kb_size, tree_2mb_size, tree_1gb_size) = Self::trees_size(num_frames);
+        (
+            TreeStorage::boxed(tree_4kb_size),
+            TreeStorage::boxed(tree_2mb_size),
+            TreeStorage::boxed(tree_1gb_size),
+            TreeStorage::boxed(Self::counters_size(num_frames)),
+            TreeStorage::boxed(Self::offline_size(num_frames)),
+            TreeStorage::boxed(Self::holes_size(num_frames)),
+        )
+    }
+
+    fn borrowed_trees(num_frames: usize, storage: &'static mut [u64]) -> Trees {
+        let (tree_4kb_size, tree_2mb_size, tree_1gb_size) = Self::trees_size(num_frames);
+        assert!(storage.len() >= Self::storage_size(num_frames));
+        let (tree_4kb, storage) = storage.split_at_mut(tree_4kb_size);
+        let (tree_2mb, storage) = storage.split_at_mut(tree_2mb_size);
+        let (tree_1gb, storage) = storage.split_at_mut(tree_1gb_size);
+        let (counters, storage) = storage.split_at_mut(Self::counters_size(num_frames));
+        let (offline, storage) = storage.split_at_mut(Self::offline_size(num_frames));
+        let (holes, _) = storage.split_at_mut(Self::holes_size(num_frames));
+        (
+            TreeStorage::borrowed(tree_4kb),
+            TreeStorage::borrowed(tree_2mb),
+            TreeStorage::borrowed(tree_1gb),
+            TreeStorage::borrowed(counters),
+            TreeStorage::borrowed(offline),
+            TreeStorage::borrowed(holes),
+        )
+    }
+
+    /**
+     * Create an allocator for `num_frames` 4Kb frames where every frame is marked as used
+     */
+    fn with_empty_trees(
+        num_frames: usize,
+        tree_4kb: TreeStorage,
+        tree_2mb: TreeStorage,
+        tree_1gb: TreeStorage,
+        counters: TreeStorage,
+        offline: TreeStorage,
+        holes: TreeStorage,
+    ) -> Self {
+        assert!(0 < num_frames && num_frames <= MAX_PAGES);
         Self {
-            tree_4kb: Box::new([0xFFFFFFFFFFFFFFFF; TREE_4KB_SIZE]),
-            tree_2mb: Box::new([0xFFFFFFFFFFFFFFFF; TREE_2MB_SIZE]),
-            tree_1gb: Box::new([0xFFFFFFFFFFFFFFFF; TREE_1GB_SIZE]),
+            tree_4kb,
+            tree_2mb,
+            tree_1gb,
+            counters,
+            offline,
+            holes,
+            refcounts: None,
+            owners: None,
+            quotas: None,
+            current_owner: 0,
+            nb_gb: num_frames.div_ceil(512 * 512),
+            nb_pages: num_frames,
+            base: PhysAddr::new(0),
+            zone_free: [0; 3],
+            free_pages: [0; 3],
+            policy: FirstFitLow,
+        }
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Set the physical address of the first managed frame (frame index 0)
+     * base must be 1Gb aligned so that huge pages are physically aligned
+     */
+    pub fn with_base_address(mut self, base: PhysAddr) -> Self {
+        assert!(base.is_aligned(HUGE_PAGE_SIZE));
+        self.base = base;
+        // zones depend on physical addresses
+        self.init_free_counters();
+        self
+    }
+
+    /**
+     * Replace the placement policy, pages already allocated stay in place
+     */
+    pub fn with_policy<Q: PlacementPolicy>(self, policy: Q) -> BuddyAllocator<Q> {
+        BuddyAllocator {
+            tree_4kb: self.tree_4kb,
+            tree_2mb: self.tree_2mb,
+            tree_1gb: self.tree_1gb,
+            counters: self.counters,
+            offline: self.offline,
+            holes: self.holes,
+            refcounts: self.refcounts,
+            owners: self.owners,
+            quotas: self.quotas,
+            current_owner: self.current_owner,
+            nb_gb: self.nb_gb,
+            nb_pages: self.nb_pages,
+            base: self.base,
+            zone_free: self.zone_free,
+            free_pages: self.free_pages,
+            policy,
+        }
+    }
+
+    /**
+     * Return the placement policy
+     */
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+
+    /**
+     * Return the physical address of the first managed frame
+     */
+    pub fn base_address(&self) -> PhysAddr {
+        self.base
+    }
+
+    /**
+     * Return the number of 4Kb frames managed by the allocator
+     */
+    pub fn capacity(&self) -> usize {
+        self.nb_pages
+    }
+
+    /**
+     * Set (free == true) or clear level 3 bits of the 4Kb tree for frames in [start, end)
+     * level 3 blocks are stored contiguously, so the word of frame i is at offset i / 64
+     */
+    fn set_level3_range(&mut self, start: usize, end: usize, free: bool) {
+        let first_block_l3 = self.compute_first_block_index(0, 0, Level::Level3);
+        let mut i = start;
+        while i < end {
+            let nb_bits = (64 - i % 64).min(end - i);
+            let mask = (!0u64 >> (64 - nb_bits)) << (i % 64);
+            if free {
+                self.tree_4kb[first_block_l3 + i / 64] |= mask;
+            } else {
+                self.tree_4kb[first_block_l3 + i / 64] &= !mask;
+            }
+            i += nb_bits;
+        }
+    }
+
+    /**
+     * Compute levels 0 to 2 of the three trees from level 3 of the 4Kb tree
+     * must only be called when no 2Mb or 1Gb page is allocated
+     */
+    fn build_summary_levels(&mut self) {
+        for l1_idx in 0..self.nb_gb {
+            let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+            for l2_idx in 0..512 {
+                let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+                let l2_bit = 1u64 << (l2_idx % 64);
+                if self
+                    .search_first_bit_set(TreeType::Tree4kb, first_block_l3)
+                    .is_some()
+                {
+                    self.tree_4kb[first_block_l2 + l2_idx / 64] |= l2_bit;
+                } else {
+                    self.tree_4kb[first_block_l2 + l2_idx / 64] &= !l2_bit;
+                }
+                if self.all_free(TreeType::Tree4kb, first_block_l3) {
+                    self.tree_2mb[first_block_l2 + l2_idx / 64] |= l2_bit;
+                } else {
+                    self.tree_2mb[first_block_l2 + l2_idx / 64] &= !l2_bit;
+                }
+            }
+
+            let free_4kb = self
+                .search_first_bit_set(TreeType::Tree4kb, first_block_l2)
+                .is_some();
+            let free_2mb = self
+                .search_first_bit_set(TreeType::Tree2mb, first_block_l2)
+                .is_some();
+            let free_1gb = self.all_free(TreeType::Tree2mb, first_block_l2);
+            self.set_level1_bit(TreeType::Tree4kb, l1_idx, free_4kb);
+            self.set_level1_bit(TreeType::Tree2mb, l1_idx, free_2mb);
+            self.set_level1_bit(TreeType::Tree1gb, l1_idx, free_1gb);
         }
     }
 
@@ -78,129 +547,111 @@ impl BuddyAllocator {
     }
 
     /**
-     * Allocate 4kb page
-     * return None if allocation fails
+     * Allocate 4kb page, charged to the current owner (see `set_current_owner`)
+     * return None if allocation fails or if the owner would exceed its hard limit
      */
     pub fn allocate_frame(&mut self) -> Option<usize> {
+        self.try_allocate_frame().ok()
+    }
+
+    /**
+     * Same as `allocate_frame` but return an error telling whether no frame is free or the
+     * current owner would exceed its hard limit
+     */
+    pub fn try_allocate_frame(&mut self) -> Result<usize, OwnerAllocError> {
+        self.allocate_charged(TreeType::Tree4kb, self.current_owner, Self::claim_frame)
+    }
+
+    /**
+     * Take a free 4kb page in the trees
+     */
+    fn claim_frame(&mut self) -> Option<usize> {
         // First level search
-        let l1_idx_found = self.search_first_bit_set(TreeType::Tree4kb, 0);
-        if l1_idx_found.is_none() {
-            return None;
-        }
-        let l1_idx = l1_idx_found.unwrap();
-        if l1_idx >= NB_GB {
+        let l1_idx = self.select_level1(TreeType::Tree4kb)?;
+        if l1_idx >= self.nb_gb {
             return None;
         }
 
         // Second level search
-        let first_block_l2 = Self::compute_first_block_index(l1_idx, 0, Level::Level2);
-        let l2_idx_found = self.search_first_bit_set(TreeType::Tree4kb, first_block_l2);
+        let l2_idx_found = self.select_block(TreeType::Tree4kb, Level::Level2, l1_idx, 0);
         assert!(l2_idx_found.is_some());
         let l2_idx = l2_idx_found.unwrap();
 
         // Third level search
-        let first_block_l3 = Self::compute_first_block_index(l1_idx, l2_idx, Level::Level3);
-        let l3_idx_found = self.search_first_bit_set(TreeType::Tree4kb, first_block_l3);
+        let l3_idx_found = self.select_block(TreeType::Tree4kb, Level::Level3, l1_idx, l2_idx);
         assert!(l3_idx_found.is_some());
         let l3_idx = l3_idx_found.unwrap();
 
-        // 4Kb tree: set bits to 0
-        self.tree_4kb[first_block_l3 + l3_idx / 64] &= !(1u64 << (l3_idx % 64));
-        // if block is full set upper level to 0
-        if self
-            .search_first_bit_set(TreeType::Tree4kb, first_block_l3)
-            .is_none()
-        {
-            self.tree_4kb[first_block_l2 + l2_idx / 64] &= !(1u64 << (l2_idx % 64));
-        }
-        if self
-            .search_first_bit_set(TreeType::Tree4kb, first_block_l2)
-            .is_none()
-        {
-            self.tree_4kb[l1_idx / 64] &= !(1u64 << (l1_idx % 64));
-        }
-
-        // 2Mb tree: set bits to 0
-        self.tree_2mb[first_block_l2 + l2_idx / 64] &= !(1u64 << (l2_idx % 64));
-        if self
-            .search_first_bit_set(TreeType::Tree2mb, first_block_l2)
-            .is_none()
-        {
-            self.tree_2mb[l1_idx / 64] &= !(1u64 << (l1_idx % 64))
-        }
-
-        // 1Gb tree: set bit to 0
-        self.tree_1gb[l1_idx / 64] &= !(1u64 << (l1_idx % 64));
+        self.mark_frame_allocated(l1_idx, l2_idx, l3_idx);
 
         // Memory index is built as follow: |l1 l1 l1 l1 l1 l1 l1 l1 l1|l2 l2 l2 l2 l2 l2 l2 l2 l2|l3 l3 l3 l3 l3 l3 l3 l3 l3|
         Some((l1_idx << 18) + (l2_idx << 9) + l3_idx)
     }
 
     /**
-     * Allocate 2Mb page
-     * return None if allocation fails
+     * Allocate 2Mb page, charged to the current owner (see `set_current_owner`)
+     * return None if allocation fails or if the owner would exceed its hard limit
      */
     pub fn allocate_big_page(&mut self) -> Option<usize> {
+        self.try_allocate_big_page().ok()
+    }
+
+    /**
+     * Same as `allocate_big_page` but return an error telling whether no big page is free or the
+     * current owner would exceed its hard limit
+     */
+    pub fn try_allocate_big_page(&mut self) -> Result<usize, OwnerAllocError> {
+        self.allocate_charged(TreeType::Tree2mb, self.current_owner, Self::claim_big_page)
+    }
+
+    /**
+     * Take a free 2Mb page in the trees
+     */
+    fn claim_big_page(&mut self) -> Option<usize> {
         // First level search
-        let l1_idx_found = self.search_first_bit_set(TreeType::Tree2mb, 0);
-        if l1_idx_found.is_none() {
-            return None;
-        }
-        let l1_idx = l1_idx_found.unwrap();
-        if l1_idx >= NB_GB {
+        let l1_idx = self.select_level1(TreeType::Tree2mb)?;
+        if l1_idx >= self.nb_gb {
             return None;
         }
 
         // Second level search
-        let first_block_l2 = Self::compute_first_block_index(l1_idx, 0, Level::Level2);
-        let l2_idx_found = self.search_first_bit_set(TreeType::Tree2mb, first_block_l2);
+        let l2_idx_found = self.select_block(TreeType::Tree2mb, Level::Level2, l1_idx, 0);
         assert!(l2_idx_found.is_some());
         let l2_idx = l2_idx_found.unwrap();
 
-        // Set bits to 0
-        self.tree_2mb[first_block_l2 + l2_idx / 64] &= !(1u64 << (l2_idx % 64));
-        // if block is full set upper level to 0
-        if self
-            .search_first_bit_set(TreeType::Tree2mb, first_block_l2)
-            .is_none()
-        {
-            self.tree_2mb[l1_idx / 64] &= !(1u64 << (l1_idx % 64));
-        }
-
-        // set bits from TREE_1GB and TREE_4KB to 0
-        self.tree_4kb[first_block_l2 + l2_idx / 64] &= !(1u64 << (l2_idx % 64));
-        if self
-            .search_first_bit_set(TreeType::Tree4kb, first_block_l2)
-            .is_none()
-        {
-            self.tree_4kb[l1_idx / 64] &= !(1u64 << (l1_idx % 64));
-        }
-
-        self.tree_1gb[l1_idx / 64] &= !(1u64 << (l1_idx % 64));
+        self.mark_big_page_allocated(l1_idx, l2_idx);
 
         // Memory index is built as follow: |l1 l1 l1 l1 l1 l1 l1 l1 l1|l2 l2 l2 l2 l2 l2 l2 l2 l2|0 0 0 0 0 0 0 0 0|
         Some((l1_idx << 18) + (l2_idx << 9))
     }
 
     /**
-     * Allocate 1Gb page
-     * return None if allocation fails
+     * Allocate 1Gb page, charged to the current owner (see `set_current_owner`)
+     * return None if allocation fails or if the owner would exceed its hard limit
      */
     pub fn allocate_huge_page(&mut self) -> Option<usize> {
+        self.try_allocate_huge_page().ok()
+    }
+
+    /**
+     * Same as `allocate_huge_page` but return an error telling whether no huge page is free or the
+     * current owner would exceed its hard limit
+     */
+    pub fn try_allocate_huge_page(&mut self) -> Result<usize, OwnerAllocError> {
+        self.allocate_charged(TreeType::Tree1gb, self.current_owner, Self::claim_huge_page)
+    }
+
+    /**
+     * Take a free 1Gb page in the trees
+     */
+    fn claim_huge_page(&mut self) -> Option<usize> {
         // First level search
-        let l1_idx_found = self.search_first_bit_set(TreeType::Tree1gb, 0);
-        if l1_idx_found.is_none() {
-            return None;
-        }
-        let l1_idx = l1_idx_found.unwrap();
-        if l1_idx >= NB_GB {
+        let l1_idx = self.select_level1(TreeType::Tree1gb)?;
+        if l1_idx >= self.nb_gb {
             return None;
         }
 
-        // set bits from TREE_1GB, TREE_2MB and TREE_4KB to 0
-        self.tree_1gb[l1_idx / 64] &= !(1u64 << (l1_idx % 64));
-        self.tree_2mb[l1_idx / 64] &= !(1u64 << (l1_idx % 64));
-        self.tree_4kb[l1_idx / 64] &= !(1u64 << (l1_idx % 64));
+        self.mark_huge_page_allocated(l1_idx);
 
         // Memory index is built as follow: |l1 l1 l1 l1 l1 l1 l1 l1 l1|0 0 0 0 0 0 0 0 0|0 0 0 0 0 0 0 0 0|
         Some(l1_idx << 18)
@@ -208,30 +659,27 @@ impl BuddyAllocator {
 
     /**
      * Deallocate frame
-     * nothing is done if frame was not previously allocated
+     * return an error and do nothing if frame was not previously allocated as a 4Kb frame or if
+     * it has other references (see `put_frame`)
      */
-    pub fn deallocate_frame(&mut self, frame_id: usize) {
+    pub fn deallocate_frame(&mut self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_allocated(frame_id, TreeType::Tree4kb)?;
+        self.check_unshared(frame_id)?;
         let mut id = frame_id;
-        // return if frame was not allocated
-        if self.get_bit_level_index(TreeType::Tree4kb, Level::Level3, id) {
-            return;
-        }
 
         let l3_block_idx = id & 0x1FF;
         id >>= 9;
         let l2_block_idx = id & 0x1FF;
         id >>= 9;
-        let l1_block_idx = id & 0x1FF;
+        let l1_block_idx = id;
 
         // Set the 3 levels to free
-        let l1_tree_idx = l1_block_idx / 64;
         let l2_tree_idx =
-            Self::compute_first_block_index(l1_block_idx, 0, Level::Level2) + l2_block_idx / 64;
-        let l3_tree_idx =
-            Self::compute_first_block_index(l1_block_idx, l2_block_idx, Level::Level3)
-                + l3_block_idx / 64;
+            self.compute_first_block_index(l1_block_idx, 0, Level::Level2) + l2_block_idx / 64;
+        let l3_tree_idx = self.compute_first_block_index(l1_block_idx, l2_block_idx, Level::Level3)
+            + l3_block_idx / 64;
 
-        self.tree_4kb[l1_tree_idx] |= 1u64 << (l1_block_idx % 64);
+        self.set_level1_bit(TreeType::Tree4kb, l1_block_idx, true);
         self.tree_4kb[l2_tree_idx] |= 1u64 << (l2_block_idx % 64);
         self.tree_4kb[l3_tree_idx] |= 1u64 << (l3_block_idx % 64);
 
@@ -239,106 +687,294 @@ impl BuddyAllocator {
         let first_block_l3 = l3_tree_idx - l3_block_idx / 64;
         if self.all_free(TreeType::Tree4kb, first_block_l3) {
             self.tree_2mb[l2_tree_idx] |= 1u64 << (l2_block_idx % 64);
-            self.tree_2mb[l1_tree_idx] |= 1u64 << (l1_block_idx % 64);
+            self.set_level1_bit(TreeType::Tree2mb, l1_block_idx, true);
         }
 
         // if all 2Mb are free, free 1Gb block
         let first_block_l2 = l2_tree_idx - l2_block_idx / 64;
         if self.all_free(TreeType::Tree2mb, first_block_l2) {
-            self.tree_1gb[l1_tree_idx] |= 1u64 << (l1_block_idx % 64);
+            self.set_level1_bit(TreeType::Tree1gb, l1_block_idx, true);
         }
+
+        self.clear_owner(frame_id, 1);
+        self.account_frames(frame_id, 1, true);
+
+        Ok(())
     }
 
     /**
      * Deallocate big page
-     * nothing is done if page was not previously allocated
+     * return an error and do nothing if page was not previously allocated as a 2Mb page or if
+     * it has other references (see `put_frame`)
      */
-    pub fn deallocate_big_page(&mut self, frame_id: usize) {
+    pub fn deallocate_big_page(&mut self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_allocated(frame_id, TreeType::Tree2mb)?;
+        self.check_unshared(frame_id)?;
         let mut id = frame_id;
-        // return if big page was not allocated
-        if id % 512 != 0
-            || self.get_bit_level_index(TreeType::Tree2mb, Level::Level2, id)
-            || self.get_bit_level_index(TreeType::Tree4kb, Level::Level2, id)
-        {
-            return;
-        }
 
         let l3_block_idx = id & 0x1FF;
         id >>= 9;
         let l2_block_idx = id & 0x1FF;
         id >>= 9;
-        let l1_block_idx = id & 0x1FF;
+        let l1_block_idx = id;
         assert!(l3_block_idx == 0); // l3_block_idx must be 0 for 2mb pages
 
-        let l1_tree_idx = l1_block_idx / 64;
         let l2_tree_idx =
-            Self::compute_first_block_index(l1_block_idx, 0, Level::Level2) + l2_block_idx / 64;
+            self.compute_first_block_index(l1_block_idx, 0, Level::Level2) + l2_block_idx / 64;
 
-        self.tree_2mb[l1_tree_idx] |= 1u64 << (l1_block_idx % 64);
+        self.set_level1_bit(TreeType::Tree2mb, l1_block_idx, true);
         self.tree_2mb[l2_tree_idx] |= 1u64 << (l2_block_idx % 64);
 
         self.tree_4kb[l2_tree_idx] |= 1u64 << (l2_block_idx % 64);
-        self.tree_4kb[l1_tree_idx] |= 1u64 << (l1_block_idx % 64);
+        self.set_level1_bit(TreeType::Tree4kb, l1_block_idx, true);
 
         let first_block_l2 = l2_tree_idx - l2_block_idx / 64;
         if self.all_free(TreeType::Tree2mb, first_block_l2) {
-            self.tree_1gb[l1_tree_idx] |= 1u64 << (l1_block_idx % 64);
+            self.set_level1_bit(TreeType::Tree1gb, l1_block_idx, true);
         }
+
+        self.clear_owner(frame_id, 512);
+        self.account_frames(frame_id, 512, true);
+
+        Ok(())
     }
 
     /**
      * Deallocate huge page
-     * nothing is done if frame was not previously allocated
+     * return an error and do nothing if page was not previously allocated as a 1Gb page or if
+     * it has other references (see `put_frame`)
      */
-    pub fn deallocate_huge_page(&mut self, frame_id: usize) {
+    pub fn deallocate_huge_page(&mut self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_allocated(frame_id, TreeType::Tree1gb)?;
+        self.check_unshared(frame_id)?;
         let mut id = frame_id;
-        // return if huge page was not allocated
-        if id % (512 * 512) != 0
-            || self.get_bit_level_index(TreeType::Tree1gb, Level::Level1, id)
-            || self.get_bit_level_index(TreeType::Tree2mb, Level::Level1, id)
-            || self.get_bit_level_index(TreeType::Tree4kb, Level::Level1, id)
-        {
-            return;
-        }
 
         let l3_block_idx = id & 0x1FF;
         id >>= 9;
         let l2_block_idx = id & 0x1FF;
         id >>= 9;
-        let l1_block_idx = id & 0x1FF;
+        let l1_block_idx = id;
         assert!(l3_block_idx == 0); // l3_block_idx must be 0 for 1gb pages
         assert!(l2_block_idx == 0); // l2_block_idx must be 0 for 1gb pages
 
-        let l1_tree_idx = l1_block_idx / 64;
-        self.tree_1gb[l1_tree_idx] |= 1u64 << (l1_block_idx % 64);
-        self.tree_2mb[l1_tree_idx] |= 1u64 << (l1_block_idx % 64);
-        self.tree_4kb[l1_tree_idx] |= 1u64 << (l1_block_idx % 64);
+        self.set_level1_bit(TreeType::Tree1gb, l1_block_idx, true);
+        self.set_level1_bit(TreeType::Tree2mb, l1_block_idx, true);
+        self.set_level1_bit(TreeType::Tree4kb, l1_block_idx, true);
+
+        self.clear_owner(frame_id, 512 * 512);
+        self.account_frames(frame_id, 512 * 512, true);
+
+        Ok(())
     }
 
     /**
-     * Check integrity of allocated pages
-     * crash if integrity is not ensured
+     * Set bits of a free 4Kb frame to 0 in the three trees
      */
-    pub fn check_integrity(&self) {
-        for i in 0..NB_PAGES {
-            // 4Kb tree level 3 not free
-            assert!(
-                !(!self.get_bit_level_index(TreeType::Tree4kb, Level::Level3, i)
-                    && (self.get_bit_level_index(TreeType::Tree2mb, Level::Level2, i)
-                        || self.get_bit_level_index(TreeType::Tree1gb, Level::Level1, i)))
-            );
+    fn mark_frame_allocated(&mut self, l1_idx: usize, l2_idx: usize, l3_idx: usize) {
+        let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+        let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
 
-            // 4Kb tree level 3 free and 2Mb level 2 not free
-            assert!(
-                !(self.get_bit_level_index(TreeType::Tree4kb, Level::Level3, i)
-                    && !self.get_bit_level_index(TreeType::Tree2mb, Level::Level2, i)
-                    && self.get_bit_level_index(TreeType::Tree1gb, Level::Level1, i))
-            );
+        // 4Kb tree: set bits to 0
+        self.tree_4kb[first_block_l3 + l3_idx / 64] &= !(1u64 << (l3_idx % 64));
+        // if block is full set upper level to 0
+        if self
+            .search_first_bit_set(TreeType::Tree4kb, first_block_l3)
+            .is_none()
+        {
+            self.tree_4kb[first_block_l2 + l2_idx / 64] &= !(1u64 << (l2_idx % 64));
+        }
+        if self
+            .search_first_bit_set(TreeType::Tree4kb, first_block_l2)
+            .is_none()
+        {
+            self.set_level1_bit(TreeType::Tree4kb, l1_idx, false);
+        }
+
+        // 2Mb tree: set bits to 0
+        self.tree_2mb[first_block_l2 + l2_idx / 64] &= !(1u64 << (l2_idx % 64));
+        if self
+            .search_first_bit_set(TreeType::Tree2mb, first_block_l2)
+            .is_none()
+        {
+            self.set_level1_bit(TreeType::Tree2mb, l1_idx, false);
+        }
+
+        // 1Gb tree: set bit to 0
+        self.set_level1_bit(TreeType::Tree1gb, l1_idx, false);
+
+        self.account_frames((l1_idx << 18) + (l2_idx << 9) + l3_idx, 1, false);
+    }
+
+    /**
+     * Set bits of a free 2Mb page to 0 in the three trees
+     */
+    fn mark_big_page_allocated(&mut self, l1_idx: usize, l2_idx: usize) {
+        let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+
+        // Set bits to 0
+        self.tree_2mb[first_block_l2 + l2_idx / 64] &= !(1u64 << (l2_idx % 64));
+        // if block is full set upper level to 0
+        if self
+            .search_first_bit_set(TreeType::Tree2mb, first_block_l2)
+            .is_none()
+        {
+            self.set_level1_bit(TreeType::Tree2mb, l1_idx, false);
+        }
+
+        // set bits from TREE_1GB and TREE_4KB to 0
+        self.tree_4kb[first_block_l2 + l2_idx / 64] &= !(1u64 << (l2_idx % 64));
+        if self
+            .search_first_bit_set(TreeType::Tree4kb, first_block_l2)
+            .is_none()
+        {
+            self.set_level1_bit(TreeType::Tree4kb, l1_idx, false);
+        }
+
+        self.set_level1_bit(TreeType::Tree1gb, l1_idx, false);
+
+        self.account_frames((l1_idx << 18) + (l2_idx << 9), 512, false);
+    }
+
+    /**
+     * Set bits of a free 1Gb page to 0 in the three trees
+     */
+    fn mark_huge_page_allocated(&mut self, l1_idx: usize) {
+        // set bits from TREE_1GB, TREE_2MB and TREE_4KB to 0
+        self.set_level1_bit(TreeType::Tree1gb, l1_idx, false);
+        self.set_level1_bit(TreeType::Tree2mb, l1_idx, false);
+        self.set_level1_bit(TreeType::Tree4kb, l1_idx, false);
+
+        self.account_frames(l1_idx << 18, 512 * 512, false);
+    }
+
+    /**
+     * Allocate a page of size `S` (`Size4K`, `Size2M` or `Size1G`)
+     * return None if allocation fails
+     */
+    pub fn allocate<S: PageSize>(&mut self) -> Option<Frame<S>> {
+        let frame_id = match S::TREE_TYPE {
+            TreeType::Tree4kb => self.allocate_frame(),
+            TreeType::Tree2mb => self.allocate_big_page(),
+            TreeType::Tree1gb => self.allocate_huge_page(),
+        }?;
+        Some(Frame::new(frame_id, self.frame_to_addr(frame_id)))
+    }
+
+    /**
+     * Deallocate a page previously returned by `allocate`
+     */
+    pub fn deallocate<S: PageSize>(&mut self, frame: Frame<S>) -> Result<(), DeallocError> {
+        match S::TREE_TYPE {
+            TreeType::Tree4kb => self.deallocate_frame(frame.index()),
+            TreeType::Tree2mb => self.deallocate_big_page(frame.index()),
+            TreeType::Tree1gb => self.deallocate_huge_page(frame.index()),
+        }
+    }
+
+    /**
+     * Return the handle of the page of size `S` starting at frame `frame_id`
+     * return an error if no page of this size is allocated there, see `deallocate_frame`
+     */
+    pub fn to_frame<S: PageSize>(&self, frame_id: usize) -> Result<Frame<S>, DeallocError> {
+        self.check_allocated(frame_id, S::TREE_TYPE)?;
+        Ok(Frame::new(frame_id, self.frame_to_addr(frame_id)))
+    }
+
+    /**
+     * Allocate 4kb page and return its physical address
+     * return None if allocation fails
+     */
+    pub fn allocate_frame_addr(&mut self) -> Option<PhysAddr> {
+        let frame_id = self.allocate_frame()?;
+        Some(self.frame_to_addr(frame_id))
+    }
+
+    /**
+     * Allocate 2Mb page and return its physical address
+     * return None if allocation fails
+     */
+    pub fn allocate_big_page_addr(&mut self) -> Option<PhysAddr> {
+        let frame_id = self.allocate_big_page()?;
+        Some(self.frame_to_addr(frame_id))
+    }
+
+    /**
+     * Allocate 1Gb page and return its physical address
+     * return None if allocation fails
+     */
+    pub fn allocate_huge_page_addr(&mut self) -> Option<PhysAddr> {
+        let frame_id = self.allocate_huge_page()?;
+        Some(self.frame_to_addr(frame_id))
+    }
+
+    /**
+     * Deallocate frame at a given physical address
+     * return an error and do nothing if frame was not previously allocated at this address
+     */
+    pub fn deallocate_frame_addr(&mut self, addr: PhysAddr) -> Result<(), DeallocError> {
+        let frame_id = self.checked_addr_to_frame(addr, FRAME_SIZE)?;
+        self.deallocate_frame(frame_id)
+    }
+
+    /**
+     * Deallocate big page at a given physical address
+     * return an error and do nothing if big page was not previously allocated at this address
+     */
+    pub fn deallocate_big_page_addr(&mut self, addr: PhysAddr) -> Result<(), DeallocError> {
+        let frame_id = self.checked_addr_to_frame(addr, BIG_PAGE_SIZE)?;
+        self.deallocate_big_page(frame_id)
+    }
+
+    /**
+     * Deallocate huge page at a given physical address
+     * return an error and do nothing if huge page was not previously allocated at this address
+     */
+    pub fn deallocate_huge_page_addr(&mut self, addr: PhysAddr) -> Result<(), DeallocError> {
+        let frame_id = self.checked_addr_to_frame(addr, HUGE_PAGE_SIZE)?;
+        self.deallocate_huge_page(frame_id)
+    }
+
+    /**
+     * Return the physical address of a frame index
+     */
+    pub fn frame_to_addr(&self, frame_id: usize) -> PhysAddr {
+        assert!(frame_id < self.nb_pages);
+        PhysAddr::new(self.base.as_u64() + frame_id as u64 * FRAME_SIZE)
+    }
+
+    /**
+     * Return the frame index of a physical address aligned to `page_size`
+     * return None if address is misaligned or outside of the managed range
+     */
+    pub fn addr_to_frame(&self, addr: PhysAddr, page_size: u64) -> Option<usize> {
+        self.checked_addr_to_frame(addr, page_size).ok()
+    }
+
+    fn checked_addr_to_frame(&self, addr: PhysAddr, page_size: u64) -> Result<usize, DeallocError> {
+        if !addr.is_aligned(page_size) {
+            return Err(DeallocError::Misaligned);
         }
+        if addr < self.base {
+            return Err(DeallocError::OutOfRange);
+        }
+        let frame_id = ((addr.as_u64() - self.base.as_u64()) / FRAME_SIZE) as usize;
+        if frame_id >= self.nb_pages {
+            return Err(DeallocError::OutOfRange);
+        }
+        Ok(frame_id)
+    }
+
+    /**
+     * Check integrity of allocated pages
+     * crash on the first violation, see `integrity_report` to list all of them
+     */
+    pub fn check_integrity(&self) {
+        self.for_each_violation(|violation| panic!("integrity is not ensured: {}", violation));
     }
 
     /**
      * Return the number of free block in the following order (1gb, 2mb, 4kb)
+     * scan the whole trees, `free_memory` returns the same in constant time
      */
     pub fn stat_free_memory(&self) -> (u64, u64, u64) {
         let mut nb_4kb = 0u64;
@@ -347,7 +983,7 @@ impl BuddyAllocator {
 
         let mut num_free = 0;
         let mut i = 0;
-        while i < NB_PAGES {
+        while i < self.nb_pages {
             // 4Kb tree level 3 not free
             if !self.get_bit_level_index(TreeType::Tree4kb, Level::Level3, i) {
                 i += 1;
@@ -425,6 +1061,7 @@ impl BuddyAllocator {
 
     /**
      * Return the spatial occupation of blocks with a granularity of 512 blocks
+     * only the last state seen in a 2Mb block is kept, see `occupancy_map` for frame counts
      *
      * Returned array contains the following states for each blocks
      * 0 for FREE
@@ -432,11 +1069,12 @@ impl BuddyAllocator {
      * 2 for 2Mb pages
      * 3 for 1Gb pages
      */
-    pub fn spatial_stat_memory(&self) -> [u8; NB_GB * 512] {
-        let mut state = [0x00; NB_GB * 512];
+    #[cfg(feature = "alloc")]
+    pub fn spatial_stat_memory(&self) -> Vec<u8> {
+        let mut state = vec![0x00; self.nb_gb * 512];
         let mut i = 0;
 
-        while i < NB_PAGES {
+        while i < self.nb_pages {
             // 4Kb tree level 3 not free
             if !self.get_bit_level_index(TreeType::Tree4kb, Level::Level3, i) {
                 state[i / 512] = 1;
@@ -483,12 +1121,87 @@ impl BuddyAllocator {
         state
     }
 
+    /**
+     * Return true if frame `frame_id` is not usable according to the firmware memory map
+     */
+    #[inline(always)]
+    pub(crate) fn is_hole(&self, frame_id: usize) -> bool {
+        self.holes[frame_id / 64] & (1u64 << (frame_id % 64)) != 0
+    }
+
+    /**
+     * Return true if the 2Mb block starting at frame `frame_id` holds a memory map hole
+     */
+    pub(crate) fn has_holes_in_2mb(&self, frame_id: usize) -> bool {
+        let first_word = frame_id / 64;
+        let last_word = (first_word + 8).min(self.holes.len());
+        self.holes[first_word..last_word]
+            .iter()
+            .any(|&word| word != 0)
+    }
+
+    /**
+     * Return the size with which a frame is allocated, None if the frame is free
+     * big and huge pages only clear their bit at level 2 and level 1 of the 4Kb tree
+     */
+    fn allocation_size(&self, frame_id: usize) -> Option<TreeType> {
+        if !self.get_bit_level_index(TreeType::Tree4kb, Level::Level3, frame_id) {
+            Some(TreeType::Tree4kb)
+        } else if !self.get_bit_level_index(TreeType::Tree4kb, Level::Level2, frame_id) {
+            Some(TreeType::Tree2mb)
+        } else if !self.get_bit_level_index(TreeType::Tree4kb, Level::Level1, frame_id) {
+            Some(TreeType::Tree1gb)
+        } else {
+            None
+        }
+    }
+
+    /**
+     * Check that `frame_id` is the first frame of a page currently allocated with `size`
+     * return the error a deallocation of this page would report
+     */
+    pub(crate) fn check_allocated(
+        &self,
+        frame_id: usize,
+        size: TreeType,
+    ) -> Result<(), DeallocError> {
+        if frame_id >= self.nb_pages || self.is_offline(frame_id) || self.is_hole(frame_id) {
+            return Err(DeallocError::OutOfRange);
+        }
+        if !frame_id.is_multiple_of(size.nb_frames()) {
+            return Err(DeallocError::Misaligned);
+        }
+        if frame_id + size.nb_frames() > self.nb_pages {
+            return Err(DeallocError::OutOfRange);
+        }
+        match self.allocation_size(frame_id) {
+            Some(allocated) if allocated == size => Ok(()),
+            Some(_) => Err(DeallocError::WrongSize),
+            None => match size {
+                TreeType::Tree4kb => Err(DeallocError::NotAllocated),
+                // a free first frame is part of a smaller allocation unless the whole block is free
+                TreeType::Tree2mb
+                    if !self.get_bit_level_index(TreeType::Tree2mb, Level::Level2, frame_id) =>
+                {
+                    Err(DeallocError::WrongSize)
+                }
+                TreeType::Tree1gb
+                    if !self.get_bit_level_index(TreeType::Tree1gb, Level::Level1, frame_id) =>
+                {
+                    Err(DeallocError::WrongSize)
+                }
+                _ => Err(DeallocError::NotAllocated),
+            },
+        }
+    }
+
     /**
      * Check if a bit is set of a given tree at a given level
      * return true if bit equals 1, raise an error if given level does not exist
      */
+    #[inline(always)]
     fn get_bit_level_index(&self, tree_type: TreeType, level: Level, index: usize) -> bool {
-        assert!(index < NB_PAGES);
+        assert!(index < self.nb_pages);
 
         let mut id = index;
 
@@ -496,7 +1209,7 @@ impl BuddyAllocator {
         id >>= 9;
         let l2_block_idx = id & 0x1FF;
         id >>= 9;
-        let l1_block_idx = id & 0x1FF;
+        let l1_block_idx = id;
 
         self.get_bit_level_block_levels_index(
             tree_type,
@@ -511,6 +1224,7 @@ impl BuddyAllocator {
      * Check if a bit is set of a given tree at a given level (l1_block_idx, l2_block_idx and l3_block_idx are given)
      * return true if bit equals 1, raise an error if given level does not exist
      */
+    #[inline(always)]
     fn get_bit_level_block_levels_index(
         &self,
         tree_type: TreeType,
@@ -519,44 +1233,37 @@ impl BuddyAllocator {
         l2_block_idx: usize,
         l3_block_idx: usize,
     ) -> bool {
-        let l1_tree_idx = Self::compute_first_block_index(l1_block_idx, 0, Level::Level1);
+        let l0_block_idx = l1_block_idx >> 9;
+        let l0_tree_idx = l0_block_idx / 64;
+        let l1_tree_idx = self.compute_first_block_index(l1_block_idx, 0, Level::Level1)
+            + (l1_block_idx % 512) / 64;
         let l2_tree_idx =
-            Self::compute_first_block_index(l1_block_idx, 0, Level::Level2) + l2_block_idx / 64;
-        let l3_tree_idx =
-            Self::compute_first_block_index(l1_block_idx, l2_block_idx, Level::Level3)
-                + l3_block_idx / 64;
+            self.compute_first_block_index(l1_block_idx, 0, Level::Level2) + l2_block_idx / 64;
+        let l3_tree_idx = self.compute_first_block_index(l1_block_idx, l2_block_idx, Level::Level3)
+            + l3_block_idx / 64;
 
         match tree_type {
             TreeType::Tree4kb => match level {
-                Level::Level1 => {
-                    return (self.tree_4kb[l1_tree_idx] & 1 << (l1_block_idx % 64)) != 0
-                }
-                Level::Level2 => {
-                    return (self.tree_4kb[l2_tree_idx] & 1 << (l2_block_idx % 64)) != 0
-                }
-                Level::Level3 => {
-                    return (self.tree_4kb[l3_tree_idx] & 1 << (l3_block_idx % 64)) != 0
-                }
+                Level::Level0 => (self.tree_4kb[l0_tree_idx] & 1 << (l0_block_idx % 64)) != 0,
+                Level::Level1 => (self.tree_4kb[l1_tree_idx] & 1 << (l1_block_idx % 64)) != 0,
+                Level::Level2 => (self.tree_4kb[l2_tree_idx] & 1 << (l2_block_idx % 64)) != 0,
+                Level::Level3 => (self.tree_4kb[l3_tree_idx] & 1 << (l3_block_idx % 64)) != 0,
             },
             TreeType::Tree2mb => {
                 assert!(level != Level::Level3);
                 match level {
-                    Level::Level1 => {
-                        return (self.tree_2mb[l1_tree_idx] & 1 << (l1_block_idx % 64)) != 0
-                    }
+                    Level::Level0 => (self.tree_2mb[l0_tree_idx] & 1 << (l0_block_idx % 64)) != 0,
+                    Level::Level1 => (self.tree_2mb[l1_tree_idx] & 1 << (l1_block_idx % 64)) != 0,
 
-                    Level::Level2 => {
-                        return (self.tree_2mb[l2_tree_idx] & 1 << (l2_block_idx % 64)) != 0
-                    }
+                    Level::Level2 => (self.tree_2mb[l2_tree_idx] & 1 << (l2_block_idx % 64)) != 0,
                     Level::Level3 => false,
                 }
             }
             TreeType::Tree1gb => {
-                assert!(level == Level::Level1);
+                assert!(level == Level::Level0 || level == Level::Level1);
                 match level {
-                    Level::Level1 => {
-                        return (self.tree_1gb[l1_tree_idx] & 1 << (l1_block_idx % 64)) != 0
-                    }
+                    Level::Level0 => (self.tree_1gb[l0_tree_idx] & 1 << (l0_block_idx % 64)) != 0,
+                    Level::Level1 => (self.tree_1gb[l1_tree_idx] & 1 << (l1_block_idx % 64)) != 0,
                     Level::Level2 => false,
                     Level::Level3 => false,
                 }
@@ -569,31 +1276,20 @@ impl BuddyAllocator {
      * search from LSB to MSB except for 1Gb tree
      * return Some(idx) if a bit is set otherwise None
      */
+    #[inline(always)]
     fn search_first_bit_set(&self, tree_type: TreeType, start_idx: usize) -> Option<usize> {
+        let block = &self.tree(tree_type)[start_idx..start_idx + 8];
         let mut found_index = None;
         for i in 0..8 {
-            // code duplication because of `match` arms have incompatible types
-            match tree_type {
-                TreeType::Tree4kb => {
-                    if self.tree_4kb[start_idx + i] != 0 {
-                        found_index = Some(Self::bsf(self.tree_4kb[start_idx + i]) + 64 * i);
-                        break;
-                    }
-                }
-                TreeType::Tree2mb => {
-                    if self.tree_2mb[start_idx + i] != 0 {
-                        found_index = Some(Self::bsf(self.tree_2mb[start_idx + i]) + 64 * i);
-                        break;
-                    }
-                }
-                TreeType::Tree1gb => {
-                    let rev_i = 7 - i;
-                    if self.tree_1gb[start_idx + rev_i] != 0 {
-                        found_index =
-                            Some(Self::bsr(self.tree_1gb[start_idx + rev_i]) + 64 * rev_i);
-                        break;
-                    }
+            if tree_type == TreeType::Tree1gb {
+                let rev_i = 7 - i;
+                if block[rev_i] != 0 {
+                    found_index = Some(Self::bsr(block[rev_i]) + 64 * rev_i);
+                    break;
                 }
+            } else if block[i] != 0 {
+                found_index = Some(Self::bsf(block[i]) + 64 * i);
+                break;
             }
         }
 
@@ -603,31 +1299,82 @@ impl BuddyAllocator {
     /**
      * Return false if at least one block of the 512 one is not free
      */
+    #[inline(always)]
     fn all_free(&self, tree_type: TreeType, start_idx: usize) -> bool {
-        let mut all_free = true;
-        for i in 0..8 {
-            if match tree_type {
-                TreeType::Tree4kb => self.tree_4kb[start_idx + i],
-                TreeType::Tree2mb => self.tree_2mb[start_idx + i],
-                TreeType::Tree1gb => self.tree_1gb[start_idx + i],
-            } != !0u64
+        let block = &self.tree(tree_type)[start_idx..start_idx + 8];
+        block.iter().all(|&word| word == !0u64)
+    }
+
+    /**
+     * Return the bitmap of a given tree
+     */
+    #[inline(always)]
+    fn tree(&self, tree_type: TreeType) -> &[u64] {
+        match tree_type {
+            TreeType::Tree4kb => &self.tree_4kb,
+            TreeType::Tree2mb => &self.tree_2mb,
+            TreeType::Tree1gb => &self.tree_1gb,
+        }
+    }
+
+    /**
+     * Return the bitmap of a given tree
+     */
+    #[inline(always)]
+    fn tree_mut(&mut self, tree_type: TreeType) -> &mut [u64] {
+        match tree_type {
+            TreeType::Tree4kb => &mut self.tree_4kb,
+            TreeType::Tree2mb => &mut self.tree_2mb,
+            TreeType::Tree1gb => &mut self.tree_1gb,
+        }
+    }
+
+    /**
+     * Set (free == true) or clear the level 1 bit of a 1Gb block
+     * the level 0 bit of its 512Gb group is set if at least one level 1 bit of the group is set
+     */
+    fn set_level1_bit(&mut self, tree_type: TreeType, l1_idx: usize, free: bool) {
+        let first_block_l1 = self.compute_first_block_index(l1_idx, 0, Level::Level1);
+        let l0_idx = l1_idx >> 9;
+        let tree = self.tree_mut(tree_type);
+        if free {
+            tree[first_block_l1 + (l1_idx % 512) / 64] |= 1u64 << (l1_idx % 64);
+            tree[l0_idx / 64] |= 1u64 << (l0_idx % 64);
+        } else {
+            tree[first_block_l1 + (l1_idx % 512) / 64] &= !(1u64 << (l1_idx % 64));
+            if tree[first_block_l1..first_block_l1 + 8]
+                .iter()
+                .all(|&word| word == 0)
             {
-                all_free = false;
-                break;
+                tree[l0_idx / 64] &= !(1u64 << (l0_idx % 64));
             }
         }
-        all_free
     }
 
     /**
      * Compute index of the first block at a given level given his parents indexes
-     * for level 1 and level 2, l2_idx is ignored
+     * for level 0, level 1 and level 2, l2_idx is ignored
      */
-    fn compute_first_block_index(l1_idx: usize, l2_idx: usize, level: Level) -> usize {
+    #[inline(always)]
+    fn compute_first_block_index(&self, l1_idx: usize, l2_idx: usize, level: Level) -> usize {
+        Self::block_index(self.nb_gb, l1_idx, l2_idx, level)
+    }
+
+    /**
+     * Layout of the trees managing `nb_gb` 1Gb blocks, each level is stored after its parent:
+     * level 0: 512 bits, one per group of 512 1Gb blocks (512Gb)
+     * level 1: 512 bits per group, one per 1Gb block
+     * level 2: 512 bits per 1Gb block, one per 2Mb block (4Kb and 2Mb trees)
+     * level 3: 512 bits per 2Mb block, one per 4Kb frame (4Kb tree)
+     */
+    #[inline(always)]
+    pub(crate) fn block_index(nb_gb: usize, l1_idx: usize, l2_idx: usize, level: Level) -> usize {
+        let level1_size = 8 * nb_gb.div_ceil(512);
         match level {
-            Level::Level1 => l1_idx / 64,
-            Level::Level2 => TREE_1GB_SIZE + 8 * l1_idx,
-            Level::Level3 => TREE_1GB_SIZE + 8 * NB_GB + 512 * 8 * l1_idx + 8 * l2_idx,
+            Level::Level0 => 0,
+            Level::Level1 => LEVEL0_SIZE + 8 * (l1_idx >> 9),
+            Level::Level2 => LEVEL0_SIZE + level1_size + 8 * l1_idx,
+            Level::Level3 => LEVEL0_SIZE + level1_size + 8 * nb_gb + 512 * 8 * l1_idx + 8 * l2_idx,
         }
     }
 }
@@ -672,7 +1419,7 @@ mod tests {
             }
             let new_frame = frame_alloc.allocate_frame();
             assert!(new_frame.is_some());
-            frame_alloc.deallocate_frame(new_frame.unwrap());
+            frame_alloc.deallocate_frame(new_frame.unwrap()).unwrap();
         }
         frame_alloc.check_integrity();
     }
@@ -700,7 +1447,7 @@ mod tests {
             }
             let new_frame = frame_alloc.allocate_big_page();
             assert!(new_frame.is_some());
-            frame_alloc.deallocate_big_page(new_frame.unwrap());
+            frame_alloc.deallocate_big_page(new_frame.unwrap()).unwrap();
         }
     }
 
@@ -713,7 +1460,9 @@ mod tests {
             }
             let new_frame = frame_alloc.allocate_huge_page();
             assert!(new_frame.is_some());
-            frame_alloc.deallocate_huge_page(new_frame.unwrap());
+            frame_alloc
+                .deallocate_huge_page(new_frame.unwrap())
+                .unwrap();
         }
     }
 
@@ -782,7 +1531,9 @@ mod tests {
         frame_alloc.check_integrity();
         let huge_page1 = frame_alloc.allocate_huge_page();
         assert!(huge_page1.is_none());
-        frame_alloc.deallocate_huge_page(huge_page.unwrap());
+        frame_alloc
+            .deallocate_huge_page(huge_page.unwrap())
+            .unwrap();
         let huge_page2 = frame_alloc.allocate_huge_page();
         assert!(huge_page2.is_some());
         let big_page1 = frame_alloc.allocate_big_page();
@@ -812,7 +1563,7 @@ mod tests {
             assert!(huge_page.is_none());
             // deallocates all frames
             for i in 0..NB_PAGES {
-                frame_alloc.deallocate_frame(i);
+                frame_alloc.deallocate_frame(i).unwrap();
             }
             frame_alloc.check_integrity();
 
@@ -830,7 +1581,7 @@ mod tests {
             assert!(huge_page.is_none());
             // deallocates all big pages
             for i in 0..NB_PAGES / 512 {
-                frame_alloc.deallocate_big_page(i * 512);
+                frame_alloc.deallocate_big_page(i * 512).unwrap();
             }
             frame_alloc.check_integrity();
 
@@ -848,7 +1599,7 @@ mod tests {
             assert!(huge_page.is_none());
             // deallocates all big pages
             for i in 0..NB_GB {
-                frame_alloc.deallocate_huge_page(i * 512 * 512);
+                frame_alloc.deallocate_huge_page(i * 512 * 512).unwrap();
             }
             frame_alloc.check_integrity();
 
@@ -866,7 +1617,7 @@ mod tests {
             assert!(huge_page.is_none());
             // deallocates all frames
             for i in 0..NB_PAGES {
-                frame_alloc.deallocate_frame(i);
+                frame_alloc.deallocate_frame(i).unwrap();
             }
             frame_alloc.check_integrity();
 
@@ -884,7 +1635,7 @@ mod tests {
             assert!(huge_page.is_none());
             // deallocates all big pages
             for i in 0..NB_GB {
-                frame_alloc.deallocate_huge_page(i * 512 * 512);
+                frame_alloc.deallocate_huge_page(i * 512 * 512).unwrap();
             }
             frame_alloc.check_integrity();
 
@@ -902,9 +1653,419 @@ mod tests {
             assert!(huge_page.is_none());
             // deallocates all big pages
             for i in 0..NB_PAGES / 512 {
-                frame_alloc.deallocate_big_page(i * 512);
+                frame_alloc.deallocate_big_page(i * 512).unwrap();
             }
             frame_alloc.check_integrity();
         }
     }
+
+    #[test]
+    fn test_with_capacity_partial_gb() {
+        let num_frames = 2 * 512 * 512 + 3 * 512 + 100;
+        let mut frame_alloc = Box::new(BuddyAllocator::with_capacity(num_frames));
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.capacity(), num_frames);
+        assert_eq!(frame_alloc.stat_free_memory(), (2, 3, 100));
+        assert_eq!(frame_alloc.spatial_stat_memory().len(), 3 * 512);
+
+        // allocates all possible frames
+        for _ in 0..num_frames {
+            let frame = frame_alloc.allocate_frame();
+            assert!(frame.is_some());
+            assert!(frame.unwrap() < num_frames);
+        }
+        frame_alloc.check_integrity();
+        assert!(frame_alloc.allocate_frame().is_none());
+        assert_eq!(frame_alloc.stat_free_memory(), (0, 0, 0));
+        for i in 0..num_frames {
+            frame_alloc.deallocate_frame(i).unwrap();
+        }
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.stat_free_memory(), (2, 3, 100));
+
+        // only the complete 1Gb and 2Mb blocks can be allocated as huge and big pages
+        for _ in 0..2 {
+            assert!(frame_alloc.allocate_huge_page().is_some());
+        }
+        assert!(frame_alloc.allocate_huge_page().is_none());
+        for _ in 0..3 {
+            assert!(frame_alloc.allocate_big_page().is_some());
+        }
+        assert!(frame_alloc.allocate_big_page().is_none());
+        for _ in 0..100 {
+            assert!(frame_alloc.allocate_frame().is_some());
+        }
+        assert!(frame_alloc.allocate_frame().is_none());
+        frame_alloc.check_integrity();
+    }
+
+    #[test]
+    fn test_dealloc_out_of_range_fails() {
+        let num_frames = 512 * 512 + 512 + 10;
+        let mut frame_alloc = Box::new(BuddyAllocator::with_capacity(num_frames));
+        for _ in 0..num_frames {
+            assert!(frame_alloc.allocate_frame().is_some());
+        }
+
+        // the last partial 2Mb and 1Gb blocks are full of 4Kb frames, not big or huge pages
+        assert_eq!(
+            frame_alloc.deallocate_frame(num_frames),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(512 * 512 + 512),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(512 * 512),
+            Err(DeallocError::OutOfRange)
+        );
+        frame_alloc.check_integrity();
+        assert!(frame_alloc.allocate_frame().is_none());
+        assert!(frame_alloc.allocate_big_page().is_none());
+    }
+
+    #[test]
+    fn test_from_memory_map_with_holes() {
+        let regions = [
+            MemoryRegion {
+                start: 0,
+                length: 0x9F000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x9F000,
+                length: 0x61000,
+                kind: MemoryKind::Reserved,
+            },
+            MemoryRegion {
+                start: 0x100000,
+                length: 0x7FD00000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x7FE00000,
+                length: 0x200000,
+                kind: MemoryKind::Acpi,
+            },
+            MemoryRegion {
+                start: 0xFEC00000,
+                length: 0x1000,
+                kind: MemoryKind::Mmio,
+            },
+            MemoryRegion {
+                start: 0x100000000,
+                length: 0x40000000,
+                kind: MemoryKind::Usable,
+            },
+        ];
+        let mut frame_alloc = Box::new(BuddyAllocator::from_memory_map(&regions));
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.capacity(), 0x140000);
+
+        // only the 1Gb block at 4Gb is entirely usable
+        let huge_page = frame_alloc.allocate_huge_page();
+        assert_eq!(huge_page, Some(0x100000));
+        assert!(frame_alloc.allocate_huge_page().is_none());
+
+        // the first and last 2Mb blocks below 2Gb are broken by holes
+        for _ in 0..2 * 511 {
+            let big_page = frame_alloc.allocate_big_page().unwrap();
+            assert!(big_page != 0 && big_page != 0x7FE00);
+        }
+        assert!(frame_alloc.allocate_big_page().is_none());
+
+        // remaining frames are the usable ones of the first 2Mb block
+        for _ in 0..0x9F + 0x100 {
+            let frame = frame_alloc.allocate_frame().unwrap();
+            assert!(frame < 0x9F || (0x100..0x200).contains(&frame));
+        }
+        assert!(frame_alloc.allocate_frame().is_none());
+        frame_alloc.check_integrity();
+
+        // frames of the holes are never freed
+        for hole in [0x9F, 0xFF, 0x7FE00, 0xFEC00] {
+            assert_eq!(
+                frame_alloc.deallocate_frame(hole),
+                Err(DeallocError::OutOfRange)
+            );
+        }
+        frame_alloc.deallocate_frame(0x9E).unwrap();
+        assert_eq!(frame_alloc.allocate_frame(), Some(0x9E));
+    }
+
+    #[test]
+    fn test_from_memory_map_unaligned_and_overlapping() {
+        let regions = [
+            MemoryRegion {
+                start: 0x800,
+                length: 0x400000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x3000,
+                length: 0x10,
+                kind: MemoryKind::Reserved,
+            },
+        ];
+        let mut frame_alloc = Box::new(BuddyAllocator::from_memory_map(&regions));
+        frame_alloc.check_integrity();
+        // partial frames at both ends of the usable region are not free
+        assert_eq!(frame_alloc.capacity(), 0x400);
+
+        // frames 0 and 3 are not usable, so the first 2Mb block cannot be a big page
+        assert_eq!(frame_alloc.allocate_big_page(), Some(0x200));
+        assert!(frame_alloc.allocate_big_page().is_none());
+        for _ in 0..0x200 - 2 {
+            let frame = frame_alloc.allocate_frame().unwrap();
+            assert!(frame != 0 && frame != 3);
+        }
+        assert!(frame_alloc.allocate_frame().is_none());
+        frame_alloc.check_integrity();
+    }
+
+    #[test]
+    fn test_trees_in_caller_storage() {
+        let num_frames = 512 * 512 + 512;
+        let storage =
+            Box::leak(vec![!0u64; BuddyAllocator::storage_size(num_frames)].into_boxed_slice());
+        let mut frame_alloc = BuddyAllocator::with_capacity_in(num_frames, storage);
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.stat_free_memory(), (1, 1, 0));
+        assert!(frame_alloc.allocate_huge_page().is_some());
+        assert!(frame_alloc.allocate_big_page().is_some());
+        assert!(frame_alloc.allocate_frame().is_none());
+        frame_alloc.check_integrity();
+
+        let regions = [MemoryRegion {
+            start: 0x1000,
+            length: 0x3FF000,
+            kind: MemoryKind::Usable,
+        }];
+        let num_frames = BuddyAllocator::memory_map_capacity(&regions);
+        let storage =
+            Box::leak(vec![!0u64; BuddyAllocator::storage_size(num_frames)].into_boxed_slice());
+        let mut frame_alloc = BuddyAllocator::from_memory_map_in(&regions, storage);
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.allocate_big_page(), Some(512));
+        assert_eq!(frame_alloc.allocate_frame(), Some(1));
+    }
+
+    #[test]
+    fn test_physical_addresses() {
+        let base = PhysAddr::new(4 * HUGE_PAGE_SIZE);
+        let mut frame_alloc =
+            Box::new(BuddyAllocator::with_capacity(2 * 512 * 512).with_base_address(base));
+        assert_eq!(frame_alloc.base_address(), base);
+
+        let frame = frame_alloc.allocate_frame_addr().unwrap();
+        assert_eq!(frame, base);
+        let big_page = frame_alloc.allocate_big_page_addr().unwrap();
+        assert_eq!(big_page, PhysAddr::new(base.as_u64() + BIG_PAGE_SIZE));
+        let huge_page = frame_alloc.allocate_huge_page_addr().unwrap();
+        assert_eq!(huge_page, PhysAddr::new(base.as_u64() + HUGE_PAGE_SIZE));
+        assert_eq!(
+            frame_alloc.addr_to_frame(huge_page, HUGE_PAGE_SIZE),
+            Some(512 * 512)
+        );
+        assert_eq!(frame_alloc.frame_to_addr(512), big_page);
+
+        // misaligned or out of range addresses are ignored
+        assert_eq!(
+            frame_alloc.addr_to_frame(PhysAddr::new(0), FRAME_SIZE),
+            None
+        );
+        assert_eq!(
+            frame_alloc.addr_to_frame(PhysAddr::new(base.as_u64() + 8), FRAME_SIZE),
+            None
+        );
+        assert_eq!(frame_alloc.addr_to_frame(frame, BIG_PAGE_SIZE), Some(0));
+        assert_eq!(
+            frame_alloc.addr_to_frame(PhysAddr::new(base.as_u64() + FRAME_SIZE), BIG_PAGE_SIZE),
+            None
+        );
+        assert_eq!(
+            frame_alloc.addr_to_frame(PhysAddr::new(6 * HUGE_PAGE_SIZE), FRAME_SIZE),
+            None
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page_addr(PhysAddr::new(big_page.as_u64() + FRAME_SIZE)),
+            Err(DeallocError::Misaligned)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_frame_addr(PhysAddr::new(6 * HUGE_PAGE_SIZE)),
+            Err(DeallocError::OutOfRange)
+        );
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.stat_free_memory(), (0, 510, 511));
+
+        frame_alloc.deallocate_frame_addr(frame).unwrap();
+        frame_alloc.deallocate_big_page_addr(big_page).unwrap();
+        frame_alloc.deallocate_huge_page_addr(huge_page).unwrap();
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.stat_free_memory(), (2, 0, 0));
+    }
+
+    #[test]
+    fn test_typed_frames() {
+        let base = PhysAddr::new(HUGE_PAGE_SIZE);
+        let mut frame_alloc =
+            Box::new(BuddyAllocator::with_capacity(2 * 512 * 512).with_base_address(base));
+
+        let frame = frame_alloc.allocate::<Size4K>().unwrap();
+        let big_page: Frame<Size2M> = frame_alloc.allocate().unwrap();
+        let huge_page = frame_alloc.allocate::<Size1G>().unwrap();
+        frame_alloc.check_integrity();
+        assert_eq!(frame.index(), 0);
+        assert_eq!(frame.start_address(), base);
+        assert_eq!(frame.size(), FRAME_SIZE);
+        assert_eq!(big_page.index(), 512);
+        assert_eq!(big_page.size(), BIG_PAGE_SIZE);
+        assert_eq!(huge_page.index(), 512 * 512);
+        assert_eq!(
+            huge_page.start_address(),
+            PhysAddr::new(base.as_u64() + HUGE_PAGE_SIZE)
+        );
+        assert!(frame_alloc.allocate::<Size1G>().is_none());
+
+        // a raw index only becomes a handle again with its own size
+        let index = frame.into_index();
+        assert_eq!(
+            frame_alloc.to_frame::<Size2M>(index),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.to_frame::<Size4K>(index + 1),
+            Err(DeallocError::NotAllocated)
+        );
+        let frame = frame_alloc.to_frame::<Size4K>(index).unwrap();
+        assert_eq!(frame.start_address(), base);
+
+        frame_alloc.deallocate(frame).unwrap();
+        frame_alloc.deallocate(big_page).unwrap();
+        frame_alloc.deallocate(huge_page).unwrap();
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.stat_free_memory(), (2, 0, 0));
+    }
+
+    #[test]
+    fn test_dealloc_errors() {
+        let mut frame_alloc = Box::new(BuddyAllocator::with_capacity(2 * 512 * 512));
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        let frame = frame_alloc.allocate_frame().unwrap();
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        assert_eq!((frame, big_page), (0, 512));
+
+        // double free
+        assert_eq!(
+            frame_alloc.deallocate_frame(1),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(1024),
+            Err(DeallocError::NotAllocated)
+        );
+
+        // misaligned
+        assert_eq!(
+            frame_alloc.deallocate_big_page(513),
+            Err(DeallocError::Misaligned)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(512),
+            Err(DeallocError::Misaligned)
+        );
+
+        // size mismatch
+        assert_eq!(
+            frame_alloc.deallocate_frame(513),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_frame(huge_page + 7),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(0),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(huge_page),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(0),
+            Err(DeallocError::WrongSize)
+        );
+
+        // out of range
+        assert_eq!(
+            frame_alloc.deallocate_frame(2 * 512 * 512),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(2 * 512 * 512),
+            Err(DeallocError::OutOfRange)
+        );
+        frame_alloc.check_integrity();
+
+        assert_eq!(frame_alloc.deallocate_huge_page(huge_page), Ok(()));
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(huge_page),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(frame_alloc.deallocate_big_page(big_page), Ok(()));
+        assert_eq!(frame_alloc.deallocate_frame(frame), Ok(()));
+        assert_eq!(
+            frame_alloc.deallocate_frame(frame),
+            Err(DeallocError::NotAllocated)
+        );
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.stat_free_memory(), (2, 0, 0));
+    }
+
+    #[test]
+    fn test_more_than_512gb() {
+        const GB: usize = 512 * 512;
+        // 512Gb plus two 2Mb blocks in a second 512Gb group
+        let mut frame_alloc = Box::new(BuddyAllocator::with_capacity(NB_PAGES + 1024));
+
+        for i in (0..NB_GB).rev() {
+            assert_eq!(frame_alloc.allocate_huge_page(), Some(i * GB));
+        }
+        assert!(frame_alloc.allocate_huge_page().is_none());
+        assert!(!frame_alloc.get_bit_level_index(TreeType::Tree1gb, Level::Level0, 0));
+
+        // the remaining memory is in the second group
+        assert_eq!(frame_alloc.allocate_big_page(), Some(NB_PAGES));
+        assert_eq!(frame_alloc.allocate_frame(), Some(NB_PAGES + 512));
+        assert_eq!(
+            frame_alloc.allocate_contiguous(511, FRAME_SIZE, FRAME_SIZE),
+            Some(NB_PAGES + 513)
+        );
+        assert!(frame_alloc.allocate_frame().is_none());
+        assert!(frame_alloc.allocate_big_page().is_none());
+        assert!(frame_alloc.allocate_frame_in(Zone::Normal).is_none());
+
+        assert_eq!(frame_alloc.deallocate_huge_page(300 * GB), Ok(()));
+        assert_eq!(frame_alloc.deallocate_huge_page(301 * GB), Ok(()));
+        assert_eq!(
+            frame_alloc.allocate_contiguous(2, HUGE_PAGE_SIZE, HUGE_PAGE_SIZE),
+            Some(300 * GB)
+        );
+        assert_eq!(frame_alloc.deallocate_big_page(NB_PAGES), Ok(()));
+        assert_eq!(frame_alloc.allocate_huge_page_in(Zone::Normal), None);
+        assert_eq!(
+            frame_alloc.allocate_big_page_in(Zone::Normal),
+            Some(NB_PAGES)
+        );
+        assert_eq!(frame_alloc.deallocate_huge_page(7 * GB), Ok(()));
+        assert_eq!(frame_alloc.allocate_frame(), Some(7 * GB));
+        assert_eq!(frame_alloc.deallocate_frame(NB_PAGES + 512), Ok(()));
+        assert_eq!(
+            frame_alloc.deallocate_frame(NB_PAGES + 512),
+            Err(DeallocError::NotAllocated)
+        );
+        frame_alloc.check_integrity();
+    }
 }
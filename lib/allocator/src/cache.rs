@@ -0,0 +1,502 @@
+//! Per-CPU caches (magazines) of 4Kb frames and 2Mb pages
+//!
+//! Each CPU (or thread) owns a `FrameCache` holding a small stack of free pages. Allocations are
+//! served from the stack without walking the trees or taking the allocator lock, and so are
+//! deallocations: a page freed by mistake is only reported when it is drained to the allocator,
+//! unless the cache checks every page on deallocation (`with_checked_frees`). The stack is
+//! refilled and drained by batches of half its size.
+
+use crate::addr::{BIG_PAGE_SIZE, FRAME_SIZE};
+use crate::error::DeallocError;
+#[cfg(feature = "alloc")]
+use crate::TreeType;
+
+/**
+ * Number of 4Kb frames kept by a cache
+ */
+pub const FRAME_CACHE_SIZE: usize = 64;
+
+/**
+ * Number of 2Mb pages kept by a cache
+ */
+pub const BIG_PAGE_CACHE_SIZE: usize = 8;
+
+/**
+ * Allocator shared between caches, pages are moved by batches to amortize locking
+ * `page_size` is either FRAME_SIZE or BIG_PAGE_SIZE
+ */
+pub trait CacheBackend {
+    /**
+     * Allocate up to `pages.len()` pages, return the number of pages written in `pages`
+     */
+    fn allocate_batch(&self, page_size: u64, pages: &mut [usize]) -> usize;
+
+    /**
+     * Deallocate every page of `pages`, return the first error encountered
+     */
+    fn deallocate_batch(&self, page_size: u64, pages: &[usize]) -> Result<(), DeallocError>;
+
+    /**
+     * Check that `frame_id` is a page of `page_size` bytes that is currently allocated, see
+     * `FrameCache::with_checked_frees`
+     * return the error its deallocation would report
+     */
+    fn check_allocated(&self, page_size: u64, frame_id: usize) -> Result<(), DeallocError>;
+}
+
+/**
+ * Return the tree of pages of `page_size` bytes, only 4Kb frames and 2Mb pages are cached
+ */
+#[cfg(feature = "alloc")]
+fn cached_tree_type(page_size: u64) -> TreeType {
+    match page_size {
+        FRAME_SIZE => TreeType::Tree4kb,
+        BIG_PAGE_SIZE => TreeType::Tree2mb,
+        _ => panic!("unsupported page size {page_size:#x}"),
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: crate::PlacementPolicy> CacheBackend for std::sync::Mutex<crate::BuddyAllocator<P>> {
+    fn allocate_batch(&self, page_size: u64, pages: &mut [usize]) -> usize {
+        let mut allocator = self.lock().unwrap();
+        let mut nb_allocated = 0;
+        for page in pages.iter_mut() {
+            let allocated = match page_size {
+                FRAME_SIZE => allocator.allocate_frame(),
+                BIG_PAGE_SIZE => allocator.allocate_big_page(),
+                _ => panic!("unsupported page size {page_size:#x}"),
+            };
+            match allocated {
+                Some(frame_id) => *page = frame_id,
+                None => break,
+            }
+            nb_allocated += 1;
+        }
+        nb_allocated
+    }
+
+    fn deallocate_batch(&self, page_size: u64, pages: &[usize]) -> Result<(), DeallocError> {
+        let mut allocator = self.lock().unwrap();
+        let mut result = Ok(());
+        for &frame_id in pages {
+            let freed = match page_size {
+                FRAME_SIZE => allocator.deallocate_frame(frame_id),
+                BIG_PAGE_SIZE => allocator.deallocate_big_page(frame_id),
+                _ => panic!("unsupported page size {page_size:#x}"),
+            };
+            result = result.and(freed);
+        }
+        result
+    }
+
+    fn check_allocated(&self, page_size: u64, frame_id: usize) -> Result<(), DeallocError> {
+        self.lock()
+            .unwrap()
+            .check_allocated(frame_id, cached_tree_type(page_size))
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<P: crate::PlacementPolicy> CacheBackend for crate::ConcurrentBuddyAllocator<P> {
+    fn allocate_batch(&self, page_size: u64, pages: &mut [usize]) -> usize {
+        let mut nb_allocated = 0;
+        for page in pages.iter_mut() {
+            let allocated = match page_size {
+                FRAME_SIZE => self.allocate_frame(),
+                BIG_PAGE_SIZE => self.allocate_big_page(),
+                _ => panic!("unsupported page size {page_size:#x}"),
+            };
+            match allocated {
+                Some(frame_id) => *page = frame_id,
+                None => break,
+            }
+            nb_allocated += 1;
+        }
+        nb_allocated
+    }
+
+    fn deallocate_batch(&self, page_size: u64, pages: &[usize]) -> Result<(), DeallocError> {
+        let mut result = Ok(());
+        for &frame_id in pages {
+            let freed = match page_size {
+                FRAME_SIZE => self.deallocate_frame(frame_id),
+                BIG_PAGE_SIZE => self.deallocate_big_page(frame_id),
+                _ => panic!("unsupported page size {page_size:#x}"),
+            };
+            result = result.and(freed);
+        }
+        result
+    }
+
+    fn check_allocated(&self, page_size: u64, frame_id: usize) -> Result<(), DeallocError> {
+        crate::ConcurrentBuddyAllocator::check_allocated(
+            self,
+            frame_id,
+            cached_tree_type(page_size),
+        )
+    }
+}
+
+/**
+ * Hit and miss counters of a cache
+ * a miss is an allocation refilling the cache or a deallocation draining it
+ */
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct CacheStats {
+    pub frame_hits: u64,
+    pub frame_misses: u64,
+    pub big_page_hits: u64,
+    pub big_page_misses: u64,
+}
+
+/**
+ * Stack of free pages of one size
+ */
+struct Magazine<const N: usize> {
+    pages: [usize; N],
+    len: usize,
+}
+
+impl<const N: usize> Magazine<N> {
+    const fn new() -> Self {
+        Self {
+            pages: [0; N],
+            len: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.pages[self.len])
+    }
+
+    fn contains(&self, frame_id: usize) -> bool {
+        self.pages[..self.len].contains(&frame_id)
+    }
+
+    fn push(&mut self, frame_id: usize) {
+        assert!(self.len < N);
+        self.pages[self.len] = frame_id;
+        self.len += 1;
+    }
+
+    /**
+     * Refill the magazine up to half of its size, return false if no page could be allocated
+     */
+    fn refill<B: CacheBackend + ?Sized>(&mut self, backend: &B, page_size: u64) -> bool {
+        let target = (N / 2).max(1);
+        self.len += backend.allocate_batch(page_size, &mut self.pages[self.len..target]);
+        self.len > 0
+    }
+
+    /**
+     * Give back pages above `keep` to the backend
+     */
+    fn drain<B: CacheBackend + ?Sized>(
+        &mut self,
+        backend: &B,
+        page_size: u64,
+        keep: usize,
+    ) -> Result<(), DeallocError> {
+        if self.len <= keep {
+            return Ok(());
+        }
+        let result = backend.deallocate_batch(page_size, &self.pages[keep..self.len]);
+        self.len = keep;
+        result
+    }
+}
+
+/**
+ * Per-CPU cache of 4Kb frames and 2Mb pages in front of a shared allocator
+ * pages kept by the cache are allocated from the point of view of the allocator, they are given
+ * back by `flush` or when the cache is dropped
+ */
+pub struct FrameCache<'a, B: CacheBackend + ?Sized> {
+    backend: &'a B,
+    frames: Magazine<FRAME_CACHE_SIZE>,
+    big_pages: Magazine<BIG_PAGE_CACHE_SIZE>,
+    stats: CacheStats,
+    checked: bool,
+}
+
+impl<'a, B: CacheBackend + ?Sized> FrameCache<'a, B> {
+    /**
+     * Create an empty cache in front of `backend`
+     */
+    pub fn new(backend: &'a B) -> Self {
+        Self {
+            backend,
+            frames: Magazine::new(),
+            big_pages: Magazine::new(),
+            stats: CacheStats::default(),
+            checked: false,
+        }
+    }
+
+    /**
+     * Check every deallocated page against the allocator before caching it, so that invalid and
+     * double frees are reported right away rather than when the page is drained
+     * each deallocation then reaches the allocator (and takes its lock), this is meant for
+     * debugging
+     */
+    pub fn with_checked_frees(mut self) -> Self {
+        self.checked = true;
+        self
+    }
+
+    /**
+     * Allocate 4kb page
+     * return None if allocation fails
+     */
+    pub fn allocate_frame(&mut self) -> Option<usize> {
+        if let Some(frame_id) = self.frames.pop() {
+            self.stats.frame_hits += 1;
+            return Some(frame_id);
+        }
+        self.stats.frame_misses += 1;
+        if !self.frames.refill(self.backend, FRAME_SIZE) {
+            return None;
+        }
+        self.frames.pop()
+    }
+
+    /**
+     * Allocate 2Mb page
+     * return None if allocation fails
+     */
+    pub fn allocate_big_page(&mut self) -> Option<usize> {
+        if let Some(frame_id) = self.big_pages.pop() {
+            self.stats.big_page_hits += 1;
+            return Some(frame_id);
+        }
+        self.stats.big_page_misses += 1;
+        if !self.big_pages.refill(self.backend, BIG_PAGE_SIZE) {
+            return None;
+        }
+        self.big_pages.pop()
+    }
+
+    /**
+     * Deallocate 4kb page
+     * return an error and do nothing if the frame is already cached, or is not allocated when
+     * frees are checked, errors of pages drained to the allocator are returned as well
+     */
+    pub fn deallocate_frame(&mut self, frame_id: usize) -> Result<(), DeallocError> {
+        if self.checked {
+            self.backend.check_allocated(FRAME_SIZE, frame_id)?;
+        }
+        if self.frames.contains(frame_id) {
+            return Err(DeallocError::NotAllocated);
+        }
+        let mut result = Ok(());
+        if self.frames.len == FRAME_CACHE_SIZE {
+            self.stats.frame_misses += 1;
+            result = self
+                .frames
+                .drain(self.backend, FRAME_SIZE, FRAME_CACHE_SIZE / 2);
+        } else {
+            self.stats.frame_hits += 1;
+        }
+        self.frames.push(frame_id);
+        result
+    }
+
+    /**
+     * Deallocate 2Mb page
+     * return an error and do nothing if the page is already cached, or is not allocated when
+     * frees are checked, errors of pages drained to the allocator are returned as well
+     */
+    pub fn deallocate_big_page(&mut self, frame_id: usize) -> Result<(), DeallocError> {
+        if self.checked {
+            self.backend.check_allocated(BIG_PAGE_SIZE, frame_id)?;
+        }
+        if self.big_pages.contains(frame_id) {
+            return Err(DeallocError::NotAllocated);
+        }
+        let mut result = Ok(());
+        if self.big_pages.len == BIG_PAGE_CACHE_SIZE {
+            self.stats.big_page_misses += 1;
+            result = self
+                .big_pages
+                .drain(self.backend, BIG_PAGE_SIZE, BIG_PAGE_CACHE_SIZE / 2);
+        } else {
+            self.stats.big_page_hits += 1;
+        }
+        self.big_pages.push(frame_id);
+        result
+    }
+
+    /**
+     * Give back every cached page to the allocator
+     * return the first error of the pages, the other pages are given back anyway
+     */
+    pub fn flush(&mut self) -> Result<(), DeallocError> {
+        let frames = self.frames.drain(self.backend, FRAME_SIZE, 0);
+        let big_pages = self.big_pages.drain(self.backend, BIG_PAGE_SIZE, 0);
+        frames.and(big_pages)
+    }
+
+    /**
+     * Return the number of cached pages in the following order (4kb, 2mb)
+     */
+    pub fn cached(&self) -> (usize, usize) {
+        (self.frames.len, self.big_pages.len)
+    }
+
+    /**
+     * Return hit and miss counters
+     */
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+impl<B: CacheBackend + ?Sized> Drop for FrameCache<'_, B> {
+    fn drop(&mut self) {
+        // pages freed by mistake can only be reported by an explicit `flush`, the valid ones are
+        // given back anyway
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BuddyAllocator, ConcurrentBuddyAllocator};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::vec::Vec;
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_cache_hits_and_misses() {
+        let shared = Mutex::new(BuddyAllocator::with_capacity(GB));
+        let mut cache = FrameCache::new(&shared).with_checked_frees();
+
+        let first = cache.allocate_frame().unwrap();
+        assert_eq!(cache.cached(), (FRAME_CACHE_SIZE / 2 - 1, 0));
+        let mut frames = vec![first];
+        for _ in 1..FRAME_CACHE_SIZE / 2 {
+            frames.push(cache.allocate_frame().unwrap());
+        }
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                frame_hits: FRAME_CACHE_SIZE as u64 / 2 - 1,
+                frame_misses: 1,
+                ..CacheStats::default()
+            }
+        );
+        // frames are taken from the allocator by batches
+        assert_eq!(
+            shared.lock().unwrap().stat_free_memory().2 as usize,
+            512 - FRAME_CACHE_SIZE / 2
+        );
+
+        let big_page = cache.allocate_big_page().unwrap();
+        assert_eq!(big_page % 512, 0);
+        assert_eq!(
+            cache.deallocate_big_page(big_page + 1),
+            Err(DeallocError::Misaligned)
+        );
+        assert_eq!(cache.deallocate_big_page(big_page), Ok(()));
+        assert_eq!(cache.stats().big_page_misses, 1);
+
+        for frame_id in frames {
+            assert_eq!(cache.deallocate_frame(frame_id), Ok(()));
+        }
+        assert_eq!(
+            cache.cached(),
+            (FRAME_CACHE_SIZE / 2, BIG_PAGE_CACHE_SIZE / 2)
+        );
+        assert_eq!(cache.flush(), Ok(()));
+        assert_eq!(cache.cached(), (0, 0));
+        assert_eq!(shared.lock().unwrap().stat_free_memory(), (1, 0, 0));
+    }
+
+    #[test]
+    fn test_cache_drain_and_exhaustion() {
+        let shared = Mutex::new(BuddyAllocator::with_capacity(1000));
+        {
+            let mut cache = FrameCache::new(&shared).with_checked_frees();
+            let mut frames = Vec::new();
+            while let Some(frame_id) = cache.allocate_frame() {
+                frames.push(frame_id);
+            }
+            assert_eq!(frames.len(), 1000);
+            assert!(cache.allocate_big_page().is_none());
+
+            let frames_cached_last = *frames.last().unwrap();
+            for frame_id in frames {
+                assert_eq!(cache.deallocate_frame(frame_id), Ok(()));
+            }
+            // the cache never holds more than its size
+            assert!(cache.cached().0 <= FRAME_CACHE_SIZE);
+            // double frees are detected whether the frame was cached or drained
+            assert_eq!(
+                cache.deallocate_frame(frames_cached_last),
+                Err(DeallocError::NotAllocated)
+            );
+            assert_eq!(cache.deallocate_frame(0), Err(DeallocError::NotAllocated));
+            assert_eq!(cache.deallocate_frame(1000), Err(DeallocError::OutOfRange));
+            assert_eq!(cache.flush(), Ok(()));
+        }
+        let mut frame_alloc = shared.into_inner().unwrap();
+        frame_alloc.check_integrity();
+        assert!(frame_alloc.allocate_big_page().is_some());
+    }
+
+    #[test]
+    fn test_cache_per_thread() {
+        let shared = ConcurrentBuddyAllocator::with_capacity(GB);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let mut cache = FrameCache::new(&shared);
+                    let mut frames = Vec::new();
+                    for i in 0..10_000 {
+                        if i % 3 == 2 {
+                            let frame_id = frames.pop().unwrap();
+                            assert_eq!(cache.deallocate_frame(frame_id), Ok(()));
+                        } else {
+                            frames.push(cache.allocate_frame().unwrap());
+                        }
+                    }
+                    let stats = cache.stats();
+                    assert!(stats.frame_hits > 10 * stats.frame_misses);
+                    for frame_id in frames {
+                        assert_eq!(cache.deallocate_frame(frame_id), Ok(()));
+                    }
+                });
+            }
+        });
+        assert_eq!(shared.free_frames(), GB);
+    }
+
+    #[test]
+    fn test_unchecked_frees_reported_on_drain() {
+        let shared = Mutex::new(BuddyAllocator::with_capacity(GB));
+        let mut cache = FrameCache::new(&shared);
+        let frame_id = cache.allocate_frame().unwrap();
+        let big_page = cache.allocate_big_page().unwrap();
+        assert_eq!(cache.deallocate_frame(frame_id), Ok(()));
+        assert_eq!(
+            cache.deallocate_frame(frame_id),
+            Err(DeallocError::NotAllocated)
+        );
+
+        // pages that are not allocated are cached until they are drained
+        assert_eq!(cache.deallocate_frame(GB), Ok(()));
+        assert_eq!(cache.deallocate_big_page(big_page + 512), Ok(()));
+        assert_eq!(cache.deallocate_big_page(big_page), Ok(()));
+        assert_eq!(cache.flush(), Err(DeallocError::OutOfRange));
+        assert_eq!(cache.cached(), (0, 0));
+        assert_eq!(shared.lock().unwrap().stat_free_memory(), (1, 0, 0));
+    }
+}
@@ -0,0 +1,1101 @@
+//! Thread-safe variant of the allocator
+//!
+//! Bitmap words are atomics and every method takes `&self`, so the allocator can be shared
+//! between threads. Level 3 of the 4Kb tree is the only source of truth: a frame belongs to the
+//! thread whose atomic update cleared its bit. Big and huge pages clear the level 3 bits of all
+//! their frames, word by word, and give back the words already claimed if another thread took
+//! one of the frames in the meantime. One bit per 2Mb and per 1Gb block records the size of the
+//! pages allocated there, so that freeing a page with another size is reported.
+//!
+//! Level 1 and level 2 bits of the three trees are hints propagated after each level 3 update.
+//! A hint may be left set while its block cannot satisfy an allocation (the allocation then
+//! fails on level 3 and clears the hint) but is never left cleared while its block can: a hint
+//! is always cleared first and its condition checked again afterwards.
+//!
+//! Blocks are chosen by the placement policy of the allocator, as in `BuddyAllocator`, from a
+//! copy of the words read just before. The policy sits behind a spin lock since its `select`
+//! takes `&mut self`. Free counters follow the layout of `counters.rs` and are updated with one
+//! atomic operation per counter along the trees, except that the counters of the 2Mb blocks of a
+//! 1Gb page are updated too.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, Ordering};
+
+use alloc::boxed::Box;
+use alloc::vec;
+
+use crate::addr::{PhysAddr, FRAME_SIZE};
+use crate::error::DeallocError;
+use crate::policy::Counters;
+use crate::{
+    BuddyAllocator, Candidates, FirstFitLow, Frame, Level, PageSize, PlacementPolicy, TreeType,
+};
+
+/**
+ * Buddy allocator callable through `&self` from several threads, `P` chooses where pages are
+ * placed (see `BuddyAllocator::with_policy`)
+ */
+pub struct ConcurrentBuddyAllocator<P = FirstFitLow> {
+    tree_4kb: Box<[AtomicU64]>,
+    tree_2mb: Box<[AtomicU64]>,
+    tree_1gb: Box<[AtomicU64]>,
+    counters: Box<[AtomicU64]>,
+    big_pages: Box<[AtomicU64]>,
+    huge_pages: Box<[AtomicU64]>,
+    offline: Box<[u64]>,
+    holes: Box<[u64]>,
+    free_pages: [AtomicIsize; 3],
+    nb_gb: usize,
+    nb_pages: usize,
+    base: PhysAddr,
+    policy: SharedPolicy<P>,
+}
+
+/**
+ * Placement policy behind a spin lock
+ */
+struct SharedPolicy<P> {
+    locked: AtomicBool,
+    policy: UnsafeCell<P>,
+}
+
+// SAFETY: the policy is only reached by the thread holding the lock
+unsafe impl<P: Send> Sync for SharedPolicy<P> {}
+
+impl<P: PlacementPolicy> SharedPolicy<P> {
+    /**
+     * Call `select` of the policy, see `PlacementPolicy`
+     */
+    fn select(&self, candidates: &Candidates) -> usize {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // SAFETY: the lock is held, no other reference to the policy exists
+        let idx = unsafe { (*self.policy.get()).select(candidates) };
+        self.locked.store(false, Ordering::Release);
+        idx
+    }
+}
+
+impl<P: PlacementPolicy> From<BuddyAllocator<P>> for ConcurrentBuddyAllocator<P> {
+    /**
+     * Take over the state and the policy of a sequential allocator, pages allocated in it stay
+     * allocated, offline 1Gb blocks and memory map holes stay out of reach: their frames are
+     * never handed out nor freed
+     * reference counts, owner ids and quotas are not supported and are dropped, pages keep their
+     * size but lose their references and their owner
+     */
+    fn from(mut allocator: BuddyAllocator<P>) -> Self {
+        let mut big_pages = vec![0u64; allocator.nb_gb * 8];
+        let mut huge_pages = vec![0u64; allocator.nb_gb.div_ceil(64)];
+        for l1_idx in 0..allocator.nb_gb {
+            for l2_idx in 0..512 {
+                let frame_id = (l1_idx << 18) | (l2_idx << 9);
+                if frame_id >= allocator.nb_pages {
+                    break;
+                }
+                match allocator.allocation_size(frame_id) {
+                    Some(TreeType::Tree2mb) => {
+                        big_pages[l1_idx * 8 + l2_idx / 64] |= 1 << (l2_idx % 64)
+                    }
+                    Some(TreeType::Tree1gb)
+                        if l2_idx == 0 && allocator.is_region_online(l1_idx) =>
+                    {
+                        huge_pages[l1_idx / 64] |= 1 << (l1_idx % 64)
+                    }
+                    Some(TreeType::Tree1gb) => {}
+                    _ => continue,
+                }
+                allocator.set_level3_range(frame_id, frame_id + 512, false);
+            }
+        }
+        allocator.build_summary_levels();
+        allocator.init_block_counters();
+
+        let atomics = |words: &[u64]| words.iter().map(|&word| AtomicU64::new(word)).collect();
+        let free_pages = allocator
+            .free_pages
+            .map(|nb_free| AtomicIsize::new(nb_free as isize));
+        Self {
+            tree_4kb: atomics(&allocator.tree_4kb),
+            tree_2mb: atomics(&allocator.tree_2mb),
+            tree_1gb: atomics(&allocator.tree_1gb),
+            counters: atomics(&allocator.counters),
+            big_pages: atomics(&big_pages),
+            huge_pages: atomics(&huge_pages),
+            offline: allocator.offline.to_vec().into_boxed_slice(),
+            holes: allocator.holes.to_vec().into_boxed_slice(),
+            free_pages,
+            nb_gb: allocator.nb_gb,
+            nb_pages: allocator.nb_pages,
+            base: allocator.base,
+            policy: SharedPolicy {
+                locked: AtomicBool::new(false),
+                policy: UnsafeCell::new(allocator.policy),
+            },
+        }
+    }
+}
+
+impl ConcurrentBuddyAllocator {
+    /**
+     * Create an allocator managing `num_frames` 4Kb frames, see `BuddyAllocator::with_capacity`
+     */
+    pub fn with_capacity(num_frames: usize) -> Self {
+        Self::from(BuddyAllocator::with_capacity(num_frames))
+    }
+}
+
+impl<P: PlacementPolicy> ConcurrentBuddyAllocator<P> {
+    /**
+     * Return the physical address of the first managed frame
+     */
+    pub fn base_address(&self) -> PhysAddr {
+        self.base
+    }
+
+    /**
+     * Return the number of 4Kb frames managed by the allocator
+     */
+    pub fn capacity(&self) -> usize {
+        self.nb_pages
+    }
+
+    /**
+     * Return the physical address of a frame
+     */
+    pub fn frame_to_addr(&self, frame_id: usize) -> PhysAddr {
+        PhysAddr::new(self.base.as_u64() + frame_id as u64 * FRAME_SIZE)
+    }
+
+    /**
+     * Return the number of free 4Kb frames, only exact when no other thread is allocating
+     */
+    pub fn free_frames(&self) -> usize {
+        self.free_pages(TreeType::Tree4kb)
+    }
+
+    /**
+     * Return the number of free pages of size `size` in constant time, see
+     * `BuddyAllocator::free_pages`, only exact when no other thread is allocating
+     */
+    pub fn free_pages(&self, size: TreeType) -> usize {
+        // a block may be counted as taken by one thread before another counted it as freed
+        self.free_pages[size as usize].load(Ordering::SeqCst).max(0) as usize
+    }
+
+    /**
+     * Allocate 4kb page
+     * return None if allocation fails
+     */
+    pub fn allocate_frame(&self) -> Option<usize> {
+        loop {
+            let l1_idx = self.select_level1(TreeType::Tree4kb)?;
+            let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+            let Some(l2_idx) = self.select_block(TreeType::Tree4kb, Level::Level2, l1_idx, 0)
+            else {
+                self.clear_hint(TreeType::Tree4kb, Level::Level1, l1_idx, 0, || {
+                    self.search_first_bit_set(TreeType::Tree4kb, first_block_l2)
+                        .is_some()
+                });
+                continue;
+            };
+
+            match self.claim_frame(l1_idx, l2_idx) {
+                Some(l3_idx) => {
+                    let frame_id = (l1_idx << 18) | (l2_idx << 9) | l3_idx;
+                    self.account_frames(frame_id, 1, false);
+                    self.update_block_hints_taken(l1_idx, l2_idx);
+                    self.update_gb_hints_taken(l1_idx);
+                    return Some(frame_id);
+                }
+                None => {
+                    let first_block_l3 =
+                        self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+                    self.clear_hint(TreeType::Tree4kb, Level::Level2, l1_idx, l2_idx, || {
+                        self.search_first_bit_set(TreeType::Tree4kb, first_block_l3)
+                            .is_some()
+                    })
+                }
+            }
+        }
+    }
+
+    /**
+     * Allocate 2Mb page
+     * return None if allocation fails
+     */
+    pub fn allocate_big_page(&self) -> Option<usize> {
+        loop {
+            let l1_idx = self.select_level1(TreeType::Tree2mb)?;
+            let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+            let Some(l2_idx) = self.select_block(TreeType::Tree2mb, Level::Level2, l1_idx, 0)
+            else {
+                self.clear_hint(TreeType::Tree2mb, Level::Level1, l1_idx, 0, || {
+                    self.search_first_bit_set(TreeType::Tree2mb, first_block_l2)
+                        .is_some()
+                });
+                continue;
+            };
+
+            let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+            if self.claim_blocks(l1_idx, l2_idx, 1).is_ok() {
+                let frame_id = (l1_idx << 18) | (l2_idx << 9);
+                Self::mark(&self.big_pages, frame_id >> 9, true);
+                self.account_frames(frame_id, 512, false);
+                self.update_block_hints_taken(l1_idx, l2_idx);
+                self.update_gb_hints_taken(l1_idx);
+                return Some(frame_id);
+            }
+            self.clear_hint(TreeType::Tree2mb, Level::Level2, l1_idx, l2_idx, || {
+                self.all_free(TreeType::Tree4kb, first_block_l3)
+            });
+        }
+    }
+
+    /**
+     * Allocate 1Gb page
+     * return None if allocation fails
+     */
+    pub fn allocate_huge_page(&self) -> Option<usize> {
+        loop {
+            let l1_idx = self.select_level1(TreeType::Tree1gb)?;
+            let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+            let first_block_l3 = self.compute_first_block_index(l1_idx, 0, Level::Level3);
+            match self.claim_blocks(l1_idx, 0, 512) {
+                Ok(()) => {
+                    Self::mark(&self.huge_pages, l1_idx, true);
+                    self.account_frames(l1_idx << 18, 512 * 512, false);
+                    for l2_idx in 0..512 {
+                        self.update_block_hints_taken(l1_idx, l2_idx);
+                    }
+                    self.update_gb_hints_taken(l1_idx);
+                    return Some(l1_idx << 18);
+                }
+                Err(l2_idx) => {
+                    // the 2Mb block holding the frame taken by another thread is not free
+                    self.clear_hint(TreeType::Tree2mb, Level::Level2, l1_idx, l2_idx, || {
+                        self.all_free(TreeType::Tree4kb, first_block_l3 + 8 * l2_idx)
+                    });
+                    self.clear_hint(TreeType::Tree1gb, Level::Level1, l1_idx, 0, || {
+                        self.all_free(TreeType::Tree2mb, first_block_l2)
+                    });
+                }
+            }
+        }
+    }
+
+    /**
+     * Allocate a page of size `S` (`Size4K`, `Size2M` or `Size1G`)
+     * return None if allocation fails
+     */
+    pub fn allocate<S: PageSize>(&self) -> Option<Frame<S>> {
+        let frame_id = match S::TREE_TYPE {
+            TreeType::Tree4kb => self.allocate_frame(),
+            TreeType::Tree2mb => self.allocate_big_page(),
+            TreeType::Tree1gb => self.allocate_huge_page(),
+        }?;
+        Some(Frame::new(frame_id, self.frame_to_addr(frame_id)))
+    }
+
+    /**
+     * Deallocate a page previously returned by `allocate`
+     */
+    pub fn deallocate<S: PageSize>(&self, frame: Frame<S>) -> Result<(), DeallocError> {
+        match S::TREE_TYPE {
+            TreeType::Tree4kb => self.deallocate_frame(frame.index()),
+            TreeType::Tree2mb => self.deallocate_big_page(frame.index()),
+            TreeType::Tree1gb => self.deallocate_huge_page(frame.index()),
+        }
+    }
+
+    /**
+     * Return the handle of the page of size `S` starting at frame `frame_id`
+     * return an error if no page of this size is allocated there
+     */
+    pub fn to_frame<S: PageSize>(&self, frame_id: usize) -> Result<Frame<S>, DeallocError> {
+        self.check_allocated(frame_id, S::TREE_TYPE)?;
+        Ok(Frame::new(frame_id, self.frame_to_addr(frame_id)))
+    }
+
+    /**
+     * Deallocate 4kb page
+     */
+    pub fn deallocate_frame(&self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_allocated(frame_id, TreeType::Tree4kb)?;
+        let (l1_idx, l2_idx, l3_idx) = <BuddyAllocator>::split_index(frame_id);
+        let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+        let bit = 1u64 << (l3_idx % 64);
+        self.account_frames(frame_id, 1, true);
+        if self.tree_4kb[first_block_l3 + l3_idx / 64].fetch_or(bit, Ordering::SeqCst) & bit != 0 {
+            // freed by another thread since it was checked
+            self.account_frames(frame_id, 1, false);
+            return Err(DeallocError::NotAllocated);
+        }
+
+        self.update_block_hints_freed(l1_idx, l2_idx);
+        self.update_gb_hints_freed(l1_idx);
+        Ok(())
+    }
+
+    /**
+     * Deallocate 2Mb page
+     */
+    pub fn deallocate_big_page(&self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_allocated(frame_id, TreeType::Tree2mb)?;
+        // clearing the size bit first makes a single thread release the page
+        if !Self::mark(&self.big_pages, frame_id >> 9, false) {
+            return Err(DeallocError::NotAllocated);
+        }
+        let (l1_idx, l2_idx, _) = <BuddyAllocator>::split_index(frame_id);
+        let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+        self.account_frames(frame_id, 512, true);
+        if let Err(error) = self.release_words(first_block_l3, 8) {
+            self.account_frames(frame_id, 512, false);
+            Self::mark(&self.big_pages, frame_id >> 9, true);
+            return Err(error);
+        }
+
+        self.update_block_hints_freed(l1_idx, l2_idx);
+        self.update_gb_hints_freed(l1_idx);
+        Ok(())
+    }
+
+    /**
+     * Deallocate 1Gb page
+     */
+    pub fn deallocate_huge_page(&self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_allocated(frame_id, TreeType::Tree1gb)?;
+        let (l1_idx, _, _) = <BuddyAllocator>::split_index(frame_id);
+        if !Self::mark(&self.huge_pages, l1_idx, false) {
+            return Err(DeallocError::NotAllocated);
+        }
+        let first_block_l3 = self.compute_first_block_index(l1_idx, 0, Level::Level3);
+        self.account_frames(frame_id, 512 * 512, true);
+        if let Err(error) = self.release_words(first_block_l3, 512 * 8) {
+            self.account_frames(frame_id, 512 * 512, false);
+            Self::mark(&self.huge_pages, l1_idx, true);
+            return Err(error);
+        }
+
+        for l2_idx in 0..512 {
+            self.update_block_hints_freed(l1_idx, l2_idx);
+        }
+        self.update_gb_hints_freed(l1_idx);
+        Ok(())
+    }
+
+    /**
+     * Check that `frame_id` is the first frame of a page allocated with size `size`
+     */
+    pub(crate) fn check_allocated(
+        &self,
+        frame_id: usize,
+        size: TreeType,
+    ) -> Result<(), DeallocError> {
+        let nb_frames = size.nb_frames();
+        if frame_id >= self.nb_pages || self.is_offline(frame_id) || self.is_hole(frame_id) {
+            return Err(DeallocError::OutOfRange);
+        }
+        if !frame_id.is_multiple_of(nb_frames) {
+            return Err(DeallocError::Misaligned);
+        }
+        if frame_id + nb_frames > self.nb_pages {
+            return Err(DeallocError::OutOfRange);
+        }
+        // level 3 blocks are stored contiguously, so the word of frame i is at offset i / 64
+        let first_word = self.compute_first_block_index(0, 0, Level::Level3) + frame_id / 64;
+        let big_page = Self::is_marked(&self.big_pages, frame_id >> 9);
+        let huge_page = Self::is_marked(&self.huge_pages, frame_id >> 18);
+        match size {
+            TreeType::Tree4kb if big_page || huge_page => Err(DeallocError::WrongSize),
+            TreeType::Tree4kb => {
+                let bit = 1u64 << (frame_id % 64);
+                if self.tree_4kb[first_word].load(Ordering::SeqCst) & bit != 0 {
+                    return Err(DeallocError::NotAllocated);
+                }
+                Ok(())
+            }
+            TreeType::Tree2mb if huge_page => Err(DeallocError::WrongSize),
+            TreeType::Tree2mb if big_page => Ok(()),
+            TreeType::Tree1gb if huge_page => Ok(()),
+            _ => {
+                // the frames are free or hold smaller pages
+                let all_free = self.tree_4kb[first_word..first_word + nb_frames / 64]
+                    .iter()
+                    .all(|word| word.load(Ordering::SeqCst) == !0u64);
+                if all_free {
+                    return Err(DeallocError::NotAllocated);
+                }
+                Err(DeallocError::WrongSize)
+            }
+        }
+    }
+
+    /**
+     * Return true if frame `frame_id` belongs to a 1Gb block that was offline in the sequential
+     * allocator, its level 3 bits are cleared as for an allocated 1Gb page
+     */
+    #[inline(always)]
+    fn is_offline(&self, frame_id: usize) -> bool {
+        let gb_index = frame_id >> 18;
+        self.offline[gb_index / 64] & (1u64 << (gb_index % 64)) != 0
+    }
+
+    /**
+     * Return true if frame `frame_id` is in a hole of the memory map of the sequential
+     * allocator, its level 3 bit stays cleared
+     */
+    #[inline(always)]
+    fn is_hole(&self, frame_id: usize) -> bool {
+        self.holes[frame_id / 64] & (1u64 << (frame_id % 64)) != 0
+    }
+
+    /**
+     * Return true if bit `idx` of a page size bitmap is set
+     */
+    #[inline(always)]
+    fn is_marked(bitmap: &[AtomicU64], idx: usize) -> bool {
+        bitmap[idx / 64].load(Ordering::SeqCst) & (1u64 << (idx % 64)) != 0
+    }
+
+    /**
+     * Set or clear bit `idx` of a page size bitmap
+     * return true if it was set before
+     */
+    #[inline(always)]
+    fn mark(bitmap: &[AtomicU64], idx: usize, set: bool) -> bool {
+        let bit = 1u64 << (idx % 64);
+        let old = if set {
+            bitmap[idx / 64].fetch_or(bit, Ordering::SeqCst)
+        } else {
+            bitmap[idx / 64].fetch_and(!bit, Ordering::SeqCst)
+        };
+        old & bit != 0
+    }
+
+    /**
+     * Clear the bit of a free frame of 2Mb block (l1_idx, l2_idx) chosen by the policy
+     * return its index in the block, None if every frame of the block is taken
+     */
+    fn claim_frame(&self, l1_idx: usize, l2_idx: usize) -> Option<usize> {
+        let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+        loop {
+            let l3_idx = self.select_block(TreeType::Tree4kb, Level::Level3, l1_idx, l2_idx)?;
+            let bit = 1u64 << (l3_idx % 64);
+            // the frame is ours if its bit was still set, otherwise choose again
+            if self.tree_4kb[first_block_l3 + l3_idx / 64].fetch_and(!bit, Ordering::SeqCst) & bit
+                != 0
+            {
+                return Some(l3_idx);
+            }
+        }
+    }
+
+    /**
+     * Clear the level 3 words of `nb_blocks` fully free 2Mb blocks starting at `l2_idx`
+     * if a word is not fully free, words already cleared are set back and the index of its block
+     * is returned, hints are then published again since other threads may have seen those words
+     * cleared in the meantime
+     */
+    fn claim_blocks(&self, l1_idx: usize, l2_idx: usize, nb_blocks: usize) -> Result<(), usize> {
+        let first_word = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+        for i in 0..nb_blocks * 8 {
+            if self.tree_4kb[first_word + i]
+                .compare_exchange(!0u64, 0, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                for word in &self.tree_4kb[first_word..first_word + i] {
+                    word.fetch_or(!0u64, Ordering::SeqCst);
+                }
+                if i > 0 {
+                    for block in l2_idx..l2_idx + i.div_ceil(8) {
+                        self.update_block_hints_freed(l1_idx, block);
+                    }
+                    self.update_gb_hints_freed(l1_idx);
+                }
+                return Err(l2_idx + i / 8);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Set `nb_words` level 3 words starting at `first_word`, all their frames must be taken
+     * each word goes from fully taken to fully free with a compare-and-swap, if a frame was
+     * freed by another thread the words already set are taken back, except those in which
+     * another thread allocated since then
+     */
+    fn release_words(&self, first_word: usize, nb_words: usize) -> Result<(), DeallocError> {
+        let words = &self.tree_4kb[first_word..first_word + nb_words];
+        for (i, word) in words.iter().enumerate() {
+            if word
+                .compare_exchange(0, !0u64, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                for word in &words[..i] {
+                    let _ = word.compare_exchange(!0u64, 0, Ordering::SeqCst, Ordering::SeqCst);
+                }
+                return Err(DeallocError::NotAllocated);
+            }
+        }
+        Ok(())
+    }
+
+    /**
+     * Update the free counters when `nb_frames` frames starting at `first_frame` are freed or
+     * allocated, `nb_frames` is 1, 512 or 512 * 512
+     * frames are counted as free before their bits are set and as taken after their bits are
+     * cleared, so that a frame taken again right after being freed never makes a counter negative
+     */
+    fn account_frames(&self, first_frame: usize, nb_frames: usize, freed: bool) {
+        let update = |counter: &AtomicU64, delta: u64| {
+            if freed {
+                counter.fetch_add(delta, Ordering::SeqCst)
+            } else {
+                counter.fetch_sub(delta, Ordering::SeqCst)
+            }
+        };
+        let (l1_idx, _, _) = <BuddyAllocator>::split_index(first_frame);
+        let nb_frames_2mb = nb_frames.min(512);
+        for frame_id in (first_frame..first_frame + nb_frames).step_by(512) {
+            let (_, l2_idx, _) = <BuddyAllocator>::split_index(frame_id);
+            let shift = 16 * (l2_idx % 4);
+            let counter = &self.counters[self.nb_gb + 128 * l1_idx + l2_idx / 4];
+            let free_2mb = (update(counter, (nb_frames_2mb as u64) << shift) >> shift) & 0xFFFF;
+            self.account_free_block(TreeType::Tree2mb, free_2mb, nb_frames_2mb, freed);
+        }
+        let free_1gb = update(&self.counters[l1_idx], nb_frames as u64);
+        self.account_free_block(TreeType::Tree1gb, free_1gb, nb_frames, freed);
+        let nb_frames = nb_frames as isize;
+        self.free_pages[TreeType::Tree4kb as usize]
+            .fetch_add(if freed { nb_frames } else { -nb_frames }, Ordering::SeqCst);
+    }
+
+    /**
+     * Update the number of free pages of size `size` once `nb_frames` frames were freed or
+     * allocated in one of its blocks, whose counter held `old_free` free frames
+     */
+    fn account_free_block(&self, size: TreeType, old_free: u64, nb_frames: usize, freed: bool) {
+        let size_frames = size.nb_frames() as u64;
+        let new_free = if freed {
+            old_free + nb_frames as u64
+        } else {
+            old_free - nb_frames as u64
+        };
+        if (old_free == size_frames) != (new_free == size_frames) {
+            self.free_pages[size as usize].fetch_add(if freed { 1 } else { -1 }, Ordering::SeqCst);
+        }
+    }
+
+    /**
+     * Choose with the placement policy a block among the candidates of one 512 bits block
+     * the words are read once, candidates may be taken by other threads in the meantime
+     */
+    fn select_block(
+        &self,
+        page: TreeType,
+        level: Level,
+        l1_idx: usize,
+        l2_idx: usize,
+    ) -> Option<usize> {
+        let start_idx = self.compute_first_block_index(l1_idx, l2_idx, level);
+        let load = |tree: &[AtomicU64]| -> [u64; 8] {
+            core::array::from_fn(|i| tree[start_idx + i].load(Ordering::SeqCst))
+        };
+        let free = load(self.tree(page));
+        if free == [0; 8] {
+            return None;
+        }
+
+        let intact = match level {
+            Level::Level0 => [0; 8],
+            Level::Level1 => load(&self.tree_1gb),
+            Level::Level2 => load(&self.tree_2mb),
+            Level::Level3 => free,
+        };
+        let frames = match level {
+            Level::Level1 => Some(Counters::Atomics(&self.counters[l1_idx..self.nb_gb])),
+            Level::Level2 => Some(Counters::Atomics(
+                &self.counters[self.nb_gb + 128 * l1_idx..][..128],
+            )),
+            _ => None,
+        };
+        let candidates = Candidates::new(page, level, &free, &intact, frames);
+        let idx = self.policy.select(&candidates);
+        assert!(candidates.is_free(idx));
+        Some(idx)
+    }
+
+    /**
+     * Update level 2 hints of a 2Mb block after frames were taken in it
+     */
+    fn update_block_hints_taken(&self, l1_idx: usize, l2_idx: usize) {
+        let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+        if self
+            .search_first_bit_set(TreeType::Tree4kb, first_block_l3)
+            .is_none()
+        {
+            self.clear_hint(TreeType::Tree4kb, Level::Level2, l1_idx, l2_idx, || {
+                self.search_first_bit_set(TreeType::Tree4kb, first_block_l3)
+                    .is_some()
+            });
+        }
+        self.clear_hint(TreeType::Tree2mb, Level::Level2, l1_idx, l2_idx, || {
+            self.all_free(TreeType::Tree4kb, first_block_l3)
+        });
+    }
+
+    /**
+     * Update level 1 hints of a 1Gb block after frames were taken in it
+     */
+    fn update_gb_hints_taken(&self, l1_idx: usize) {
+        let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+        for tree_type in [TreeType::Tree4kb, TreeType::Tree2mb] {
+            if self
+                .search_first_bit_set(tree_type, first_block_l2)
+                .is_none()
+            {
+                self.clear_hint(tree_type, Level::Level1, l1_idx, 0, || {
+                    self.search_first_bit_set(tree_type, first_block_l2)
+                        .is_some()
+                });
+            }
+        }
+        self.clear_hint(TreeType::Tree1gb, Level::Level1, l1_idx, 0, || {
+            self.all_free(TreeType::Tree2mb, first_block_l2)
+        });
+    }
+
+    /**
+     * Update level 2 hints of a 2Mb block after frames were freed in it
+     */
+    fn update_block_hints_freed(&self, l1_idx: usize, l2_idx: usize) {
+        let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+        self.set_hint(TreeType::Tree4kb, Level::Level2, l1_idx, l2_idx);
+        if self.all_free(TreeType::Tree4kb, first_block_l3) {
+            self.set_hint(TreeType::Tree2mb, Level::Level2, l1_idx, l2_idx);
+        }
+    }
+
+    /**
+     * Update level 1 hints of a 1Gb block after frames were freed in it
+     */
+    fn update_gb_hints_freed(&self, l1_idx: usize) {
+        let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+        self.set_hint(TreeType::Tree4kb, Level::Level1, l1_idx, 0);
+        if self
+            .search_first_bit_set(TreeType::Tree2mb, first_block_l2)
+            .is_some()
+        {
+            self.set_hint(TreeType::Tree2mb, Level::Level1, l1_idx, 0);
+        }
+        if self.all_free(TreeType::Tree2mb, first_block_l2) {
+            self.set_hint(TreeType::Tree1gb, Level::Level1, l1_idx, 0);
+        }
+    }
+
+    /**
+     * Choose a level 1 hint set with the placement policy, going through level 0
+     * level 0 hints of groups without level 1 hint are cleared on the way
+     */
+    fn select_level1(&self, tree_type: TreeType) -> Option<usize> {
+        loop {
+            let l0_idx = self.select_block(tree_type, Level::Level0, 0, 0)?;
+            let first_block_l1 = self.compute_first_block_index(l0_idx << 9, 0, Level::Level1);
+            match self.select_block(tree_type, Level::Level1, l0_idx << 9, 0) {
+                Some(l1_idx) => return Some((l0_idx << 9) + l1_idx),
+                None => self.clear_hint(tree_type, Level::Level0, l0_idx << 9, 0, || {
+                    self.search_first_bit_set(tree_type, first_block_l1)
+                        .is_some()
+                }),
+            }
+        }
+    }
+
+    /**
+     * Set the level 1 or level 2 bit of a block
+     * level 0 bits are set along with level 1 bits and only cleared by `select_level1`
+     */
+    fn set_hint(&self, tree_type: TreeType, level: Level, l1_idx: usize, l2_idx: usize) {
+        let (word, bit) = self.hint_bit(level, l1_idx, l2_idx);
+        self.tree(tree_type)[word].fetch_or(bit, Ordering::SeqCst);
+        if level == Level::Level1 {
+            let (word, bit) = self.hint_bit(Level::Level0, l1_idx, 0);
+            self.tree(tree_type)[word].fetch_or(bit, Ordering::SeqCst);
+        }
+    }
+
+    /**
+     * Clear the level 0, 1 or 2 bit of a block, then set it back if `still_true` holds
+     * checking after clearing ensures a concurrent update of the block is never missed
+     */
+    fn clear_hint(
+        &self,
+        tree_type: TreeType,
+        level: Level,
+        l1_idx: usize,
+        l2_idx: usize,
+        still_true: impl Fn() -> bool,
+    ) {
+        let (word, bit) = self.hint_bit(level, l1_idx, l2_idx);
+        self.tree(tree_type)[word].fetch_and(!bit, Ordering::SeqCst);
+        if still_true() {
+            self.set_hint(tree_type, level, l1_idx, l2_idx);
+        }
+    }
+
+    /**
+     * Return the word index and the mask of the level 0, 1 or 2 bit of a block
+     */
+    #[inline(always)]
+    fn hint_bit(&self, level: Level, l1_idx: usize, l2_idx: usize) -> (usize, u64) {
+        match level {
+            Level::Level0 => ((l1_idx >> 9) / 64, 1u64 << ((l1_idx >> 9) % 64)),
+            Level::Level1 => (
+                self.compute_first_block_index(l1_idx, 0, Level::Level1) + (l1_idx % 512) / 64,
+                1u64 << (l1_idx % 64),
+            ),
+            Level::Level2 => (
+                self.compute_first_block_index(l1_idx, 0, Level::Level2) + l2_idx / 64,
+                1u64 << (l2_idx % 64),
+            ),
+            Level::Level3 => unreachable!("level 3 bits are not hints"),
+        }
+    }
+
+    /**
+     * Search for the first bit set in the next 512 bits at a given start index
+     * search from LSB to MSB except for 1Gb tree
+     * return Some(idx) if a bit is set otherwise None
+     */
+    #[inline(always)]
+    fn search_first_bit_set(&self, tree_type: TreeType, start_idx: usize) -> Option<usize> {
+        let block = &self.tree(tree_type)[start_idx..start_idx + 8];
+        for i in 0..8 {
+            if tree_type == TreeType::Tree1gb {
+                let rev_i = 7 - i;
+                let word = block[rev_i].load(Ordering::SeqCst);
+                if word != 0 {
+                    return Some(<BuddyAllocator>::bsr(word) + 64 * rev_i);
+                }
+            } else {
+                let word = block[i].load(Ordering::SeqCst);
+                if word != 0 {
+                    return Some(<BuddyAllocator>::bsf(word) + 64 * i);
+                }
+            }
+        }
+        None
+    }
+
+    /**
+     * Return false if at least one block of the 512 one is not free
+     */
+    #[inline(always)]
+    fn all_free(&self, tree_type: TreeType, start_idx: usize) -> bool {
+        let block = &self.tree(tree_type)[start_idx..start_idx + 8];
+        block
+            .iter()
+            .all(|word| word.load(Ordering::SeqCst) == !0u64)
+    }
+
+    /**
+     * Return the bitmap of a given tree
+     */
+    #[inline(always)]
+    fn tree(&self, tree_type: TreeType) -> &[AtomicU64] {
+        match tree_type {
+            TreeType::Tree4kb => &self.tree_4kb,
+            TreeType::Tree2mb => &self.tree_2mb,
+            TreeType::Tree1gb => &self.tree_1gb,
+        }
+    }
+
+    /**
+     * Compute index of the first block at a given level given his parents indexes
+     * same layout as `BuddyAllocator`
+     */
+    #[inline(always)]
+    fn compute_first_block_index(&self, l1_idx: usize, l2_idx: usize, level: Level) -> usize {
+        <BuddyAllocator>::block_index(self.nb_gb, l1_idx, l2_idx, level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BestFit, FirstFitHigh, MemoryKind, MemoryRegion, Size2M, Size4K};
+    use std::thread;
+    use std::vec::Vec;
+
+    const GB: usize = 512 * 512;
+    const NB_THREADS: usize = 8;
+
+    #[test]
+    fn test_concurrent_alloc_and_dealloc() {
+        let frame_alloc = ConcurrentBuddyAllocator::with_capacity(2 * GB + 1000);
+        assert_eq!(frame_alloc.free_frames(), 2 * GB + 1000);
+
+        let huge = frame_alloc.allocate_huge_page().unwrap();
+        assert_eq!(huge, GB);
+        let big = frame_alloc.allocate_big_page().unwrap();
+        assert_eq!(big, 0);
+        let frame = frame_alloc.allocate_frame().unwrap();
+        assert_eq!(frame, 512);
+        assert!(frame_alloc.allocate_huge_page().is_none());
+        assert_eq!(frame_alloc.free_frames(), 2 * GB + 1000 - GB - 513);
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree2mb), 510 + 1);
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree1gb), 0);
+
+        // pages are freed with the size they were allocated with
+        assert_eq!(
+            frame_alloc.deallocate_frame(big + 1),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(huge + 512),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(0),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(frame),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(1024),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(frame_alloc.free_frames(), 2 * GB + 1000 - GB - 513);
+
+        assert_eq!(frame_alloc.deallocate_frame(frame), Ok(()));
+        assert_eq!(
+            frame_alloc.deallocate_frame(frame),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(big + 1),
+            Err(DeallocError::Misaligned)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(2 * GB + 512),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(frame_alloc.deallocate_big_page(big), Ok(()));
+        assert_eq!(
+            frame_alloc.deallocate_big_page(big),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(frame_alloc.deallocate_huge_page(huge), Ok(()));
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(huge),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree2mb), 1024 + 1);
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree1gb), 2);
+
+        // typed handles
+        let big_page = frame_alloc.allocate::<Size2M>().unwrap();
+        assert_eq!(
+            frame_alloc.to_frame::<Size4K>(big_page.index()),
+            Err(DeallocError::WrongSize)
+        );
+        let index = big_page.into_index();
+        let big_page = frame_alloc.to_frame::<Size2M>(index).unwrap();
+        assert_eq!(frame_alloc.deallocate(big_page), Ok(()));
+
+        // hints of freed blocks are set back, the partial 1Gb block has no huge page
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(GB));
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(0));
+        assert!(frame_alloc.allocate_huge_page().is_none());
+    }
+
+    #[test]
+    fn test_concurrent_more_than_512gb() {
+        let frame_alloc = ConcurrentBuddyAllocator::with_capacity(513 * GB);
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(512 * GB));
+        for i in (0..512).rev() {
+            assert_eq!(frame_alloc.allocate_huge_page(), Some(i * GB));
+        }
+        assert!(frame_alloc.allocate_frame().is_none());
+        assert_eq!(frame_alloc.deallocate_huge_page(512 * GB), Ok(()));
+        assert_eq!(frame_alloc.allocate_frame(), Some(512 * GB));
+        assert_eq!(frame_alloc.free_frames(), GB - 1);
+    }
+
+    #[test]
+    fn test_from_sequential_allocator() {
+        let mut sequential = BuddyAllocator::with_capacity(2 * GB);
+        let huge = sequential.allocate_huge_page().unwrap();
+        let big = sequential.allocate_big_page().unwrap();
+        let frame = sequential.allocate_frame().unwrap();
+
+        let frame_alloc = ConcurrentBuddyAllocator::from(sequential);
+        assert_eq!(frame_alloc.free_frames(), GB - 513);
+        assert_eq!(
+            frame_alloc.deallocate_frame(big),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(huge),
+            Err(DeallocError::WrongSize)
+        );
+        assert!(frame_alloc.allocate_huge_page().is_none());
+        assert_eq!(frame_alloc.allocate_frame(), Some(frame + 1));
+        assert_eq!(frame_alloc.deallocate_huge_page(huge), Ok(()));
+        assert_eq!(frame_alloc.deallocate_big_page(big), Ok(()));
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(huge));
+
+        // an offline block is not an allocated 1Gb page
+        let mut sequential = BuddyAllocator::with_capacity(2 * GB);
+        sequential.offline_region(1).unwrap();
+        let frame_alloc = ConcurrentBuddyAllocator::from(sequential);
+        assert_eq!(frame_alloc.free_frames(), GB);
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(GB),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_frame(GB + 1),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(0));
+        assert!(frame_alloc.allocate_huge_page().is_none());
+
+        // frames of the memory map holes are never freed
+        let regions = [
+            MemoryRegion {
+                start: 0,
+                length: 0x9F000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x9F000,
+                length: 0x61000,
+                kind: MemoryKind::Reserved,
+            },
+            MemoryRegion {
+                start: 0x100000,
+                length: 0x100000,
+                kind: MemoryKind::Usable,
+            },
+        ];
+        let frame_alloc = ConcurrentBuddyAllocator::from(BuddyAllocator::from_memory_map(&regions));
+        assert_eq!(frame_alloc.free_frames(), 0x9F + 0x100);
+        for hole in [0x9F, 0xFF] {
+            assert_eq!(
+                frame_alloc.deallocate_frame(hole),
+                Err(DeallocError::OutOfRange)
+            );
+        }
+        assert_eq!(frame_alloc.free_frames(), 0x9F + 0x100);
+    }
+
+    #[test]
+    fn test_placement_policy() {
+        let sequential = BuddyAllocator::with_capacity(2 * GB).with_policy(FirstFitHigh);
+        let frame_alloc = ConcurrentBuddyAllocator::from(sequential);
+        assert_eq!(frame_alloc.allocate_frame(), Some(2 * GB - 1));
+        assert_eq!(frame_alloc.allocate_big_page(), Some(2 * GB - 1024));
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(0));
+        assert_eq!(frame_alloc.allocate_huge_page(), None);
+
+        // the free counters lead best fit to the most occupied 2Mb block
+        let mut sequential = BuddyAllocator::with_capacity(2 * GB).with_policy(BestFit);
+        sequential.reserve_range(0, 1024).unwrap();
+        for frame_id in [3, 512, 600] {
+            sequential.deallocate_frame(frame_id).unwrap();
+        }
+        let frame_alloc = ConcurrentBuddyAllocator::from(sequential);
+        assert_eq!(frame_alloc.allocate_frame(), Some(3));
+        assert_eq!(frame_alloc.allocate_frame(), Some(512));
+        assert_eq!(frame_alloc.allocate_frame(), Some(600));
+        assert_eq!(frame_alloc.allocate_frame(), Some(1024));
+    }
+
+    #[test]
+    fn test_concurrent_stress() {
+        let frame_alloc = ConcurrentBuddyAllocator::with_capacity(3 * GB);
+
+        let held: Vec<Vec<(usize, usize)>> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..NB_THREADS)
+                .map(|t| {
+                    let frame_alloc = &frame_alloc;
+                    scope.spawn(move || {
+                        // xorshift, each thread has its own sequence
+                        let mut state = 0x9E37_79B9_7F4A_7C15u64 ^ (t as u64 + 1);
+                        let mut next = move || {
+                            state ^= state << 13;
+                            state ^= state >> 7;
+                            state ^= state << 17;
+                            state
+                        };
+                        let mut held = Vec::new();
+                        for _ in 0..20_000 {
+                            let choice = next() % 100;
+                            if choice < 40 && !held.is_empty() {
+                                let (frame_id, len) =
+                                    held.swap_remove(next() as usize % held.len());
+                                let result = match len {
+                                    1 => frame_alloc.deallocate_frame(frame_id),
+                                    512 => frame_alloc.deallocate_big_page(frame_id),
+                                    _ => frame_alloc.deallocate_huge_page(frame_id),
+                                };
+                                assert_eq!(result, Ok(()));
+                            } else if choice < 90 {
+                                if let Some(frame_id) = frame_alloc.allocate_frame() {
+                                    held.push((frame_id, 1));
+                                }
+                            } else if choice < 99 {
+                                if let Some(frame_id) = frame_alloc.allocate_big_page() {
+                                    held.push((frame_id, 512));
+                                }
+                            } else if let Some(frame_id) = frame_alloc.allocate_huge_page() {
+                                held.push((frame_id, GB));
+                            }
+                        }
+                        held
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        // no page was handed out twice
+        let mut all: Vec<(usize, usize)> = held.iter().flatten().copied().collect();
+        all.sort_unstable();
+        for pair in all.windows(2) {
+            assert!(pair[0].0 + pair[0].1 <= pair[1].0);
+        }
+        let used: usize = all.iter().map(|&(_, len)| len).sum();
+        assert_eq!(frame_alloc.free_frames(), 3 * GB - used);
+
+        for (frame_id, len) in all {
+            let result = match len {
+                1 => frame_alloc.deallocate_frame(frame_id),
+                512 => frame_alloc.deallocate_big_page(frame_id),
+                _ => frame_alloc.deallocate_huge_page(frame_id),
+            };
+            assert_eq!(result, Ok(()));
+        }
+        assert_eq!(frame_alloc.free_frames(), 3 * GB);
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree2mb), 3 * 512);
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree1gb), 3);
+
+        // no hint was lost: every 1Gb block is available again
+        for _ in 0..3 {
+            assert!(frame_alloc.allocate_huge_page().is_some());
+        }
+        assert!(frame_alloc.allocate_huge_page().is_none());
+    }
+}
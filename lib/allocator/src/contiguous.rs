@@ -0,0 +1,336 @@
+//! Allocation of physically contiguous runs of pages (e.g. DMA buffers)
+//!
+//! A run never crosses its parent block: runs of 4Kb frames are searched inside a 2Mb block,
+//! runs of 2Mb pages inside a 1Gb block and runs of 1Gb pages inside a 512Gb group.
+
+use crate::addr::{BIG_PAGE_SIZE, FRAME_SIZE, HUGE_PAGE_SIZE};
+use crate::error::DeallocError;
+use crate::{BuddyAllocator, Level, PlacementPolicy, TreeType};
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Allocate `count` contiguous pages of `page_size` bytes (FRAME_SIZE, BIG_PAGE_SIZE or
+     * HUGE_PAGE_SIZE) whose first page is physically aligned to `align` bytes, a power of two
+     * blocks and runs are chosen by the placement policy among those that can hold the run
+     * the pages have no owner and are not charged to the current owner
+     * return the index of the first frame, None if allocation fails or if `count` is not in
+     * [1, 512] since a run never crosses its parent block
+     */
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        page_size: u64,
+        align: u64,
+    ) -> Option<usize> {
+        let page = Self::page_tree_type(page_size)?;
+        if count == 0 || count > 512 || !align.is_power_of_two() {
+            return None;
+        }
+        let align_pages = (align / page_size).max(1) as usize;
+        let base_pages = (self.base.as_u64() / page_size) as usize;
+        // aligned runs of a block whose first page is page `offset` of the physical memory
+        let runs =
+            |offset: usize| move |block: &[u64]| Self::free_runs(block, count, align_pages, offset);
+
+        // parent blocks with at least one free page, then a run inside one of them
+        self.try_blocks(page, Level::Level0, 0, 0, |allocator, l0_idx| {
+            if page == TreeType::Tree1gb {
+                let offset = base_pages + (l0_idx << 9);
+                let l1_idx =
+                    allocator.select_run(page, Level::Level1, l0_idx << 9, 0, runs(offset))?;
+                let l1_idx = (l0_idx << 9) + l1_idx;
+                for i in l1_idx..l1_idx + count {
+                    allocator.mark_huge_page_allocated(i);
+                }
+                return Some(l1_idx << 18);
+            }
+            allocator.try_blocks(page, Level::Level1, l0_idx << 9, 0, |allocator, l1_idx| {
+                let l1_idx = (l0_idx << 9) + l1_idx;
+                if page == TreeType::Tree2mb {
+                    let offset = base_pages + (l1_idx << 9);
+                    let l2_idx =
+                        allocator.select_run(page, Level::Level2, l1_idx, 0, runs(offset))?;
+                    for i in l2_idx..l2_idx + count {
+                        allocator.mark_big_page_allocated(l1_idx, i);
+                    }
+                    return Some((l1_idx << 18) + (l2_idx << 9));
+                }
+                allocator.try_blocks(page, Level::Level2, l1_idx, 0, |allocator, l2_idx| {
+                    let first_page = (l1_idx << 18) + (l2_idx << 9);
+                    let offset = base_pages + first_page;
+                    let l3_idx =
+                        allocator.select_run(page, Level::Level3, l1_idx, l2_idx, runs(offset))?;
+                    for i in l3_idx..l3_idx + count {
+                        allocator.mark_frame_allocated(l1_idx, l2_idx, i);
+                    }
+                    Some(first_page + l3_idx)
+                })
+            })
+        })
+    }
+
+    /**
+     * Deallocate `count` contiguous pages of `page_size` bytes starting at `frame_id`
+     * return an error and do nothing if one of the pages was not allocated with this size
+     */
+    pub fn deallocate_contiguous(
+        &mut self,
+        frame_id: usize,
+        count: usize,
+        page_size: u64,
+    ) -> Result<(), DeallocError> {
+        // no page of another size is ever allocated
+        let tree_type = Self::page_tree_type(page_size).ok_or(DeallocError::WrongSize)?;
+        let nb_frames = tree_type.nb_frames();
+        let end = count
+            .checked_mul(nb_frames)
+            .and_then(|len| frame_id.checked_add(len));
+        if end.is_none_or(|end| end > self.nb_pages) {
+            return Err(DeallocError::OutOfRange);
+        }
+        if !frame_id.is_multiple_of(nb_frames) {
+            return Err(DeallocError::Misaligned);
+        }
+
+        // check every page before freeing any of them
+        for i in 0..count {
+            self.check_allocated(frame_id + i * nb_frames, tree_type)?;
+            self.check_unshared(frame_id + i * nb_frames)?;
+        }
+        for i in 0..count {
+            let page_id = frame_id + i * nb_frames;
+            match tree_type {
+                TreeType::Tree4kb => self.deallocate_frame(page_id)?,
+                TreeType::Tree2mb => self.deallocate_big_page(page_id)?,
+                TreeType::Tree1gb => self.deallocate_huge_page(page_id)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Return the tree of pages of `page_size` bytes, None if no page has this size
+     */
+    fn page_tree_type(page_size: u64) -> Option<TreeType> {
+        match page_size {
+            FRAME_SIZE => Some(TreeType::Tree4kb),
+            BIG_PAGE_SIZE => Some(TreeType::Tree2mb),
+            HUGE_PAGE_SIZE => Some(TreeType::Tree1gb),
+            _ => None,
+        }
+    }
+
+    /**
+     * Try the blocks set in a 512 bits block of the tree of `page`, in the order chosen by the
+     * placement policy, until `found` succeeds in one of them
+     */
+    fn try_blocks<T>(
+        &mut self,
+        page: TreeType,
+        level: Level,
+        l1_idx: usize,
+        l2_idx: usize,
+        found: impl FnMut(&mut Self, usize) -> Option<T>,
+    ) -> Option<T> {
+        self.try_blocks_in(page, level, l1_idx, l2_idx, 0..512, found)
+    }
+
+    /**
+     * Ask the placement policy for the start of a run in a 512 bits block of the tree of
+     * `page`, `runs` returns the positions where a run can start from the block
+     * return None if the block holds no run
+     */
+    fn select_run(
+        &mut self,
+        page: TreeType,
+        level: Level,
+        l1_idx: usize,
+        l2_idx: usize,
+        runs: impl FnOnce(&[u64]) -> [u64; 8],
+    ) -> Option<usize> {
+        let start_idx = self.compute_first_block_index(l1_idx, l2_idx, level);
+        let runs = runs(&self.tree(page)[start_idx..start_idx + 8]);
+        if runs == [0; 8] {
+            return None;
+        }
+        Some(self.select_among(page, level, l1_idx, l2_idx, &runs))
+    }
+
+    /**
+     * Return the positions `pos` of the runs of `count` bits set in a 512 bits block that
+     * satisfy (offset + pos) % align == 0, runs may cross 64 bits words
+     */
+    fn free_runs(block: &[u64], count: usize, align: usize, offset: usize) -> [u64; 8] {
+        let mut runs = [0u64; 8];
+        let mut pos = offset.next_multiple_of(align) - offset;
+        while pos + count <= 512 {
+            match Self::search_first_bit_clear(block, pos, pos + count) {
+                None => {
+                    runs[pos / 64] |= 1u64 << (pos % 64);
+                    pos += align;
+                }
+                Some(clear) => pos = (offset + clear + 1).next_multiple_of(align) - offset,
+            }
+        }
+        runs
+    }
+
+    /**
+     * Search for the first bit equal to 0 in [start, end) of a 512 bits block
+     */
+    fn search_first_bit_clear(block: &[u64], start: usize, end: usize) -> Option<usize> {
+        let mut i = start;
+        while i < end {
+            let nb_bits = (64 - i % 64).min(end - i);
+            let mask = (!0u64 >> (64 - nb_bits)) << (i % 64);
+            let clear = !block[i / 64] & mask;
+            if clear != 0 {
+                return Some(i - i % 64 + Self::bsf(clear));
+            }
+            i += nb_bits;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FirstFitHigh, PhysAddr};
+
+    #[test]
+    fn test_contiguous_frames() {
+        let mut frame_alloc = Box::new(BuddyAllocator::with_capacity(2 * 512 * 512));
+        let frame = frame_alloc.allocate_frame().unwrap();
+        assert_eq!(frame, 0);
+
+        // 16 frames aligned on 64Kb skip the allocated frame
+        let run = frame_alloc
+            .allocate_contiguous(16, FRAME_SIZE, 16 * FRAME_SIZE)
+            .unwrap();
+        assert_eq!(run, 16);
+        // 64 frames crossing a 64 bits word boundary
+        assert_eq!(
+            frame_alloc.allocate_contiguous(64, FRAME_SIZE, FRAME_SIZE),
+            Some(32)
+        );
+        frame_alloc.check_integrity();
+        for i in 0..512 {
+            let allocated = i == 0 || (16..96).contains(&i);
+            assert_eq!(frame_alloc.allocation_size(i).is_some(), allocated);
+        }
+
+        // a run never crosses a 2Mb block, the second one is used
+        assert_eq!(
+            frame_alloc.allocate_contiguous(512, FRAME_SIZE, FRAME_SIZE),
+            Some(512)
+        );
+        frame_alloc.check_integrity();
+
+        frame_alloc
+            .deallocate_contiguous(16, 16, FRAME_SIZE)
+            .unwrap();
+        frame_alloc
+            .deallocate_contiguous(32, 64, FRAME_SIZE)
+            .unwrap();
+        frame_alloc
+            .deallocate_contiguous(512, 512, FRAME_SIZE)
+            .unwrap();
+        frame_alloc.deallocate_frame(frame).unwrap();
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.stat_free_memory(), (2, 0, 0));
+    }
+
+    #[test]
+    fn test_contiguous_big_and_huge_pages() {
+        let base = PhysAddr::new(HUGE_PAGE_SIZE);
+        let mut frame_alloc =
+            Box::new(BuddyAllocator::with_capacity(4 * 512 * 512).with_base_address(base));
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        assert_eq!(big_page, 0);
+
+        // 8 big pages aligned on 16Mb
+        let run = frame_alloc
+            .allocate_contiguous(8, BIG_PAGE_SIZE, 8 * BIG_PAGE_SIZE)
+            .unwrap();
+        assert_eq!(run, 8 * 512);
+        frame_alloc.check_integrity();
+
+        // 2 huge pages physically aligned on 2Gb: the managed range starts at 1Gb
+        let run = frame_alloc
+            .allocate_contiguous(2, HUGE_PAGE_SIZE, 2 * HUGE_PAGE_SIZE)
+            .unwrap();
+        assert_eq!(run, 512 * 512);
+        assert!(frame_alloc
+            .frame_to_addr(run)
+            .is_aligned(2 * HUGE_PAGE_SIZE));
+        assert!(frame_alloc
+            .allocate_contiguous(2, HUGE_PAGE_SIZE, HUGE_PAGE_SIZE)
+            .is_none());
+        frame_alloc.check_integrity();
+
+        // pages of the run are checked before any of them is freed
+        assert_eq!(
+            frame_alloc.deallocate_contiguous(7 * 512, 2, BIG_PAGE_SIZE),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_contiguous(run, 2, BIG_PAGE_SIZE),
+            Err(DeallocError::WrongSize)
+        );
+        frame_alloc
+            .deallocate_contiguous(8 * 512, 8, BIG_PAGE_SIZE)
+            .unwrap();
+        frame_alloc
+            .deallocate_contiguous(run, 2, HUGE_PAGE_SIZE)
+            .unwrap();
+        frame_alloc.deallocate_big_page(big_page).unwrap();
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.stat_free_memory(), (4, 0, 0));
+    }
+
+    #[test]
+    fn test_contiguous_arguments_and_policy() {
+        let mut frame_alloc = Box::new(BuddyAllocator::with_capacity(2 * 512 * 512));
+        assert_eq!(
+            frame_alloc.allocate_contiguous(0, FRAME_SIZE, FRAME_SIZE),
+            None
+        );
+        assert_eq!(
+            frame_alloc.allocate_contiguous(513, FRAME_SIZE, FRAME_SIZE),
+            None
+        );
+        assert_eq!(frame_alloc.allocate_contiguous(1, 8192, FRAME_SIZE), None);
+        assert_eq!(
+            frame_alloc.allocate_contiguous(1, FRAME_SIZE, 3 * FRAME_SIZE),
+            None
+        );
+        assert_eq!(
+            frame_alloc.deallocate_contiguous(0, 1, 8192),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_contiguous(0, usize::MAX, BIG_PAGE_SIZE),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(frame_alloc.stat_free_memory(), (2, 0, 0));
+
+        // runs are placed by the policy
+        let mut frame_alloc =
+            Box::new(BuddyAllocator::with_capacity(2 * 512 * 512).with_policy(FirstFitHigh));
+        assert_eq!(
+            frame_alloc.allocate_contiguous(16, FRAME_SIZE, 16 * FRAME_SIZE),
+            Some(2 * 512 * 512 - 16)
+        );
+        assert_eq!(
+            frame_alloc.allocate_contiguous(2, BIG_PAGE_SIZE, BIG_PAGE_SIZE),
+            Some(2 * 512 * 512 - 3 * 512)
+        );
+        assert_eq!(
+            frame_alloc.allocate_contiguous(1, HUGE_PAGE_SIZE, HUGE_PAGE_SIZE),
+            Some(0)
+        );
+        frame_alloc.check_integrity();
+    }
+}
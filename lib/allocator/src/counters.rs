@@ -0,0 +1,335 @@
+//! Free frame counters per 2Mb and 1Gb block
+//!
+//! Level 1 and level 2 bits of the 4Kb tree only tell whether a block still has a free frame.
+//! Counters keep the number of free 4Kb frames of every 2Mb and 1Gb block, they are updated on
+//! each allocation and deallocation so that placement policies can prefer the fullest blocks.
+//!
+//! Layout: one word per 1Gb block, followed by 128 words per 1Gb block holding four 16 bits
+//! counters each, one per 2Mb block. Huge pages only update the counter of their 1Gb block, the
+//! counters of its 2Mb blocks keep their free value and are ignored while it is 0.
+//!
+//! The number of free 4Kb frames, of fully free 2Mb blocks and of fully free 1Gb blocks of the
+//! whole allocator is updated along, when a counter reaches or leaves the size of its block.
+
+use crate::{BuddyAllocator, PlacementPolicy, TreeType};
+
+impl BuddyAllocator {
+    /**
+     * Return the number of 64 bits words of the free counters of `num_frames` frames
+     */
+    pub(crate) fn counters_size(num_frames: usize) -> usize {
+        let nb_gb = num_frames.div_ceil(512 * 512);
+        nb_gb + nb_gb * 512 / 4
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Return the number of free 4Kb frames of the 2Mb block containing `frame_id`
+     */
+    pub fn free_frames_in_2mb(&self, frame_id: usize) -> usize {
+        assert!(frame_id < self.nb_pages);
+        let (l1_idx, l2_idx, _) = Self::split_index(frame_id);
+        if self.counters[l1_idx] == 0 {
+            return 0;
+        }
+        let word = self.counters[self.nb_gb + 128 * l1_idx + l2_idx / 4];
+        ((word >> (16 * (l2_idx % 4))) & 0xFFFF) as usize
+    }
+
+    /**
+     * Return the number of free 4Kb frames of the 1Gb block containing `frame_id`
+     */
+    pub fn free_frames_in_1gb(&self, frame_id: usize) -> usize {
+        assert!(frame_id < self.nb_pages);
+        self.counters[frame_id >> 18] as usize
+    }
+
+    /**
+     * Return the number of free pages of size `size` in constant time, a free 1Gb block also
+     * counts as 512 free 2Mb blocks
+     */
+    pub fn free_pages(&self, size: TreeType) -> usize {
+        self.free_pages[size as usize]
+    }
+
+    /**
+     * Return the number of free block in the following order (1gb, 2mb, 4kb) in constant time
+     * unlike `stat_free_memory`, free frames are only counted as a 1Gb or 2Mb block when they
+     * fill an aligned block
+     */
+    pub fn free_memory(&self) -> (u64, u64, u64) {
+        let nb_1gb = self.free_pages(TreeType::Tree1gb);
+        let nb_2mb = self.free_pages(TreeType::Tree2mb);
+        let nb_4kb = self.free_pages(TreeType::Tree4kb);
+        (
+            nb_1gb as u64,
+            (nb_2mb - 512 * nb_1gb) as u64,
+            (nb_4kb - 512 * nb_2mb) as u64,
+        )
+    }
+
+    /**
+     * Update the free counters of the blocks and of the zones when `nb_frames` frames starting
+     * at `first_frame` are freed or allocated
+     */
+    pub(crate) fn account_frames(&mut self, first_frame: usize, nb_frames: usize, freed: bool) {
+        self.account_block_frames(first_frame, nb_frames, freed);
+        self.account_zone_frames(first_frame, nb_frames, freed);
+    }
+
+    /**
+     * Compute the free counters of the blocks and of the zones from the trees
+     */
+    pub(crate) fn init_free_counters(&mut self) {
+        self.init_block_counters();
+        self.init_zone_counters();
+    }
+
+    /**
+     * Update the counters of the blocks covering `nb_frames` frames starting at `first_frame`
+     */
+    pub(crate) fn account_block_frames(
+        &mut self,
+        first_frame: usize,
+        nb_frames: usize,
+        freed: bool,
+    ) {
+        if nb_frames == 512 * 512 {
+            let l1_idx = first_frame >> 18;
+            let free_1gb = self.counters[l1_idx];
+            if freed {
+                self.counters[l1_idx] += nb_frames as u64;
+                self.free_pages[TreeType::Tree4kb as usize] += nb_frames;
+                self.free_pages[TreeType::Tree2mb as usize] += 512;
+            } else {
+                self.counters[l1_idx] -= nb_frames as u64;
+                self.free_pages[TreeType::Tree4kb as usize] -= nb_frames;
+                self.free_pages[TreeType::Tree2mb as usize] -= 512;
+            }
+            self.account_free_block(TreeType::Tree1gb, free_1gb, self.counters[l1_idx]);
+            return;
+        }
+
+        let end = first_frame + nb_frames;
+        let mut frame_id = first_frame;
+        while frame_id < end {
+            let nb = (end - frame_id).min(512 - frame_id % 512);
+            let (l1_idx, l2_idx, _) = Self::split_index(frame_id);
+            let counter_2mb = self.nb_gb + 128 * l1_idx + l2_idx / 4;
+            let shift = 16 * (l2_idx % 4);
+            let free_1gb = self.counters[l1_idx];
+            let free_2mb = (self.counters[counter_2mb] >> shift) & 0xFFFF;
+            if freed {
+                self.counters[l1_idx] += nb as u64;
+                self.counters[counter_2mb] += (nb as u64) << shift;
+                self.free_pages[TreeType::Tree4kb as usize] += nb;
+            } else {
+                self.counters[l1_idx] -= nb as u64;
+                self.counters[counter_2mb] -= (nb as u64) << shift;
+                self.free_pages[TreeType::Tree4kb as usize] -= nb;
+            }
+            let new_free_2mb = (self.counters[counter_2mb] >> shift) & 0xFFFF;
+            self.account_free_block(TreeType::Tree2mb, free_2mb, new_free_2mb);
+            self.account_free_block(TreeType::Tree1gb, free_1gb, self.counters[l1_idx]);
+            frame_id += nb;
+        }
+    }
+
+    /**
+     * Update the number of free pages of size `size` once the counter of one of its blocks goes
+     * from `old_free` to `new_free` free frames
+     */
+    fn account_free_block(&mut self, size: TreeType, old_free: u64, new_free: u64) {
+        let nb_frames = size.nb_frames() as u64;
+        if old_free != nb_frames && new_free == nb_frames {
+            self.free_pages[size as usize] += 1;
+        } else if old_free == nb_frames && new_free != nb_frames {
+            self.free_pages[size as usize] -= 1;
+        }
+    }
+
+    /**
+     * Set the counters of the 512 2Mb blocks of 1Gb block `l1_idx` to `nb_free`
+     */
+    pub(crate) fn set_2mb_counters(&mut self, l1_idx: usize, nb_free: u64) {
+        let first_counter = self.nb_gb + 128 * l1_idx;
+        let word = nb_free * 0x0001_0001_0001_0001;
+        self.counters[first_counter..first_counter + 128].fill(word);
+    }
+
+    /**
+     * Compute the counters of every block from the trees
+     */
+    pub(crate) fn init_block_counters(&mut self) {
+        self.free_pages = [0; 3];
+        for l1_idx in 0..self.nb_gb {
+            let huge_page = (l1_idx << 18) + 512 * 512 <= self.nb_pages
+                && self.allocation_size(l1_idx << 18) == Some(TreeType::Tree1gb);
+            let mut nb_free_1gb = 0;
+            for l2_idx in 0..512 {
+                let start = (l1_idx << 18) + (l2_idx << 9);
+                let end = (start + 512).min(self.nb_pages);
+                let nb_free = if huge_page {
+                    512
+                } else if start < end {
+                    self.count_free_frames(start..end)
+                } else {
+                    0
+                };
+                let counter_2mb = self.nb_gb + 128 * l1_idx + l2_idx / 4;
+                let shift = 16 * (l2_idx % 4);
+                self.counters[counter_2mb] &= !(0xFFFF << shift);
+                self.counters[counter_2mb] |= (nb_free as u64) << shift;
+                nb_free_1gb += nb_free;
+                if !huge_page && nb_free == 512 {
+                    self.free_pages[TreeType::Tree2mb as usize] += 1;
+                }
+            }
+            if huge_page {
+                self.counters[l1_idx] = 0;
+                continue;
+            }
+            self.counters[l1_idx] = nb_free_1gb as u64;
+            self.free_pages[TreeType::Tree4kb as usize] += nb_free_1gb;
+            if nb_free_1gb == 512 * 512 {
+                self.free_pages[TreeType::Tree1gb as usize] += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BestFit, MemoryKind, MemoryRegion};
+
+    const GB: usize = 512 * 512;
+
+    fn assert_counters<P: PlacementPolicy>(frame_alloc: &BuddyAllocator<P>) {
+        for l1_idx in 0..frame_alloc.nb_gb {
+            let start = l1_idx * GB;
+            let end = (start + GB).min(frame_alloc.nb_pages);
+            assert_eq!(
+                frame_alloc.free_frames_in_1gb(start),
+                frame_alloc.count_free_frames(start..end)
+            );
+            for block in (start..end).step_by(512) {
+                assert_eq!(
+                    frame_alloc.free_frames_in_2mb(block),
+                    frame_alloc.count_free_frames(block..(block + 512).min(end))
+                );
+            }
+        }
+
+        let nb_pages = frame_alloc.nb_pages;
+        let free_blocks = |nb_frames: usize| {
+            (0..nb_pages)
+                .step_by(nb_frames)
+                .filter(|&block| {
+                    frame_alloc.count_free_frames(block..(block + nb_frames).min(nb_pages))
+                        == nb_frames
+                })
+                .count()
+        };
+        assert_eq!(
+            frame_alloc.free_pages(TreeType::Tree4kb),
+            frame_alloc.count_free_frames(0..nb_pages)
+        );
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree2mb), free_blocks(512));
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree1gb), free_blocks(GB));
+    }
+
+    #[test]
+    fn test_counters_follow_allocations() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB + 1000);
+        assert_eq!(frame_alloc.free_frames_in_1gb(0), GB);
+        assert_eq!(frame_alloc.free_frames_in_2mb(2 * GB + 512), 488);
+        assert_eq!(frame_alloc.free_frames_in_1gb(2 * GB), 1000);
+
+        let frame = frame_alloc.allocate_frame().unwrap();
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        frame_alloc.reserve_range(1100, 400).unwrap();
+        assert_eq!(frame_alloc.free_frames_in_2mb(frame), 511);
+        assert_eq!(frame_alloc.free_frames_in_2mb(big_page), 0);
+        assert_eq!(frame_alloc.free_frames_in_2mb(1024), 112);
+        assert_eq!(frame_alloc.free_frames_in_1gb(huge_page), 0);
+        assert_eq!(frame_alloc.free_frames_in_1gb(0), GB - 1 - 512 - 400);
+        assert_counters(&frame_alloc);
+
+        assert_eq!(frame_alloc.free_frames_in_2mb(huge_page + 512), 0);
+        frame_alloc.init_free_counters();
+        assert_counters(&frame_alloc);
+
+        frame_alloc.deallocate_big_page(big_page).unwrap();
+        frame_alloc.deallocate_huge_page(huge_page).unwrap();
+        assert_eq!(frame_alloc.free_frames_in_2mb(huge_page + 512), 512);
+        frame_alloc.deallocate_frame(frame).unwrap();
+        assert_eq!(frame_alloc.free_frames_in_1gb(0), GB - 400);
+        assert_counters(&frame_alloc);
+    }
+
+    #[test]
+    fn test_free_memory_follows_allocations() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(3 * GB + 1000);
+        assert_eq!(frame_alloc.free_memory(), (3, 1, 488));
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree2mb), 3 * 512 + 1);
+
+        let frame = frame_alloc.allocate_frame().unwrap();
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        frame_alloc.reserve_range(1100, 400).unwrap();
+        frame_alloc.offline_region(1).unwrap();
+        assert_eq!(frame_alloc.free_memory(), frame_alloc.stat_free_memory());
+        assert_counters(&frame_alloc);
+
+        frame_alloc.split_huge_page(huge_page).unwrap();
+        frame_alloc.deallocate_big_page(huge_page + 512).unwrap();
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree2mb), 509 + 1 + 1);
+        assert_eq!(frame_alloc.free_memory(), frame_alloc.stat_free_memory());
+        assert_counters(&frame_alloc);
+
+        frame_alloc.allocate_big_page_at(huge_page + 512).unwrap();
+        frame_alloc.merge_to_huge_page(huge_page).unwrap();
+        frame_alloc.deallocate_huge_page(huge_page).unwrap();
+        frame_alloc.deallocate_big_page(big_page).unwrap();
+        frame_alloc.deallocate_frame(frame).unwrap();
+        frame_alloc.online_region(1).unwrap();
+        assert_eq!(frame_alloc.free_memory(), (2, 512, 112 + 488));
+        assert_eq!(frame_alloc.free_memory(), frame_alloc.stat_free_memory());
+        frame_alloc.init_free_counters();
+        assert_eq!(frame_alloc.free_memory(), (2, 512, 112 + 488));
+    }
+
+    #[test]
+    fn test_counters_from_memory_map() {
+        let frame_alloc = BuddyAllocator::from_memory_map(&[
+            MemoryRegion {
+                start: 0,
+                length: 0x9F000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x100000,
+                length: 0x4000_0000,
+                kind: MemoryKind::Usable,
+            },
+        ]);
+        assert_eq!(frame_alloc.free_frames_in_2mb(0), 512 - 97);
+        assert_counters(&frame_alloc);
+    }
+
+    #[test]
+    fn test_best_fit_prefers_fullest_gb() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(3 * GB).with_policy(BestFit);
+        frame_alloc.allocate_frame_at(0).unwrap();
+        frame_alloc.reserve_range(GB, 1024).unwrap();
+
+        // 1Gb block 1 has less free frames than 1Gb block 0
+        assert_eq!(frame_alloc.allocate_frame(), Some(GB + 1024));
+        assert_eq!(frame_alloc.allocate_big_page(), Some(GB + 1536));
+        frame_alloc.deallocate_frame(GB + 1024).unwrap();
+        assert_eq!(frame_alloc.allocate_frame(), Some(GB + 1024));
+    }
+}
@@ -0,0 +1,194 @@
+//! Errors returned by the allocator
+
+use core::fmt;
+
+/**
+ * Reason why a page could not be deallocated
+ * NotAllocated: page is free (double free)
+ * Misaligned: index or address is not aligned to the page size
+ * WrongSize: page is allocated with another size (e.g. 4Kb frame inside an allocated 2Mb page)
+ * OutOfRange: page is outside of the managed range
+ * Shared: page has more than one reference and cannot be freed, split or merged (see
+ * `get_frame`)
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DeallocError {
+    NotAllocated,
+    Misaligned,
+    WrongSize,
+    OutOfRange,
+    Shared,
+}
+
+impl fmt::Display for DeallocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            DeallocError::NotAllocated => "page is not allocated",
+            DeallocError::Misaligned => "page is not aligned to its size",
+            DeallocError::WrongSize => "page is allocated with another size",
+            DeallocError::OutOfRange => "page is outside of the managed range",
+            DeallocError::Shared => "page has more than one reference",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DeallocError {}
+
+/**
+ * Reason why a reference could not be taken on a page
+ * Page: no allocated page starts at this index, see `DeallocError`
+ * Disabled: reference counts are not enabled
+ * Overflow: page already has the maximum number of references
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RefCountError {
+    Page(DeallocError),
+    Disabled,
+    Overflow,
+}
+
+impl From<DeallocError> for RefCountError {
+    fn from(error: DeallocError) -> Self {
+        RefCountError::Page(error)
+    }
+}
+
+impl fmt::Display for RefCountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RefCountError::Page(error) => error.fmt(f),
+            RefCountError::Disabled => f.write_str("reference counts are not enabled"),
+            RefCountError::Overflow => f.write_str("page has too many references"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RefCountError {}
+
+/**
+ * Reason why a page could not be allocated at a given index
+ * Overlap: target overlaps an allocated page
+ * Misaligned: index is not aligned to the page size
+ * OutOfRange: target is outside of the managed range
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AllocError {
+    Overlap,
+    Misaligned,
+    OutOfRange,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            AllocError::Overlap => "target overlaps an allocated page",
+            AllocError::Misaligned => "target is not aligned to the page size",
+            AllocError::OutOfRange => "target is outside of the managed range",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AllocError {}
+
+/**
+ * Reason why a snapshot could not be restored
+ * BadMagic: data is not an allocator snapshot
+ * UnsupportedVersion: snapshot was written by an incompatible version of the format
+ * BadGeometry: managed range, base address or size of the trees do not match each other
+ * Truncated: data is shorter or longer than announced by the header
+ * BadChecksum: data was modified after the snapshot was taken
+ * Corrupted: trees do not pass the integrity check
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion,
+    BadGeometry,
+    Truncated,
+    BadChecksum,
+    Corrupted,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            SnapshotError::BadMagic => "data is not an allocator snapshot",
+            SnapshotError::UnsupportedVersion => "snapshot version is not supported",
+            SnapshotError::BadGeometry => "snapshot geometry is inconsistent",
+            SnapshotError::Truncated => "snapshot length does not match its header",
+            SnapshotError::BadChecksum => "snapshot checksum does not match",
+            SnapshotError::Corrupted => "snapshot trees fail the integrity check",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SnapshotError {}
+
+/**
+ * Reason why a page could not be allocated for an owner or given to it
+ * OutOfMemory: no free page of the requested size is left
+ * QuotaExceeded: the page would take the owner above its hard limit
+ * Disabled: owner ids are not enabled, or quotas for the quota functions
+ * Page: no allocated page starts at this index, see `DeallocError`
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum OwnerAllocError {
+    OutOfMemory,
+    QuotaExceeded,
+    Disabled,
+    Page(DeallocError),
+}
+
+impl From<DeallocError> for OwnerAllocError {
+    fn from(error: DeallocError) -> Self {
+        OwnerAllocError::Page(error)
+    }
+}
+
+impl fmt::Display for OwnerAllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OwnerAllocError::OutOfMemory => f.write_str("no free page of this size is left"),
+            OwnerAllocError::QuotaExceeded => f.write_str("owner would exceed its hard limit"),
+            OwnerAllocError::Disabled => f.write_str("owner ids or quotas are not enabled"),
+            OwnerAllocError::Page(error) => error.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for OwnerAllocError {}
+
+/**
+ * Reason why a NUMA allocator could not be built or its fallback order set
+ * UnknownNode: a node index is not below the number of nodes
+ * InvalidFallback: a node falls back to itself or to the same node twice
+ * InvalidRange: no node range is given, a range holds no whole 4Kb frame or overlaps another one
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum NumaError {
+    UnknownNode,
+    InvalidFallback,
+    InvalidRange,
+}
+
+impl fmt::Display for NumaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            NumaError::UnknownNode => "node does not exist",
+            NumaError::InvalidFallback => "fallback order repeats a node",
+            NumaError::InvalidRange => "node range is empty, too small or overlapping",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for NumaError {}
@@ -0,0 +1,120 @@
+//! Typed handles of allocated pages, the page size is part of the type so that a page can only
+//! be deallocated with its own size
+//!
+//! `allocate::<S>()` and `deallocate` are the primary API of `BuddyAllocator` and
+//! `ConcurrentBuddyAllocator`. Functions taking raw frame indexes stay for pages whose index is
+//! only kept outside of Rust values (page table entries, snapshots, caches, NUMA nodes): each raw
+//! deallocation is checked against the size recorded by the allocator and returns `WrongSize`
+//! on a mismatch. `into_index` and `to_frame` move a page between both worlds.
+
+use core::marker::PhantomData;
+
+use crate::addr::{PhysAddr, BIG_PAGE_SIZE, FRAME_SIZE, HUGE_PAGE_SIZE};
+use crate::TreeType;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/**
+ * Size of a page handed out by the allocator, implemented by `Size4K`, `Size2M` and `Size1G`
+ */
+pub trait PageSize: sealed::Sealed {
+    /**
+     * Size of the page in bytes
+     */
+    const SIZE: u64;
+    /**
+     * Tree in which pages of this size are allocated
+     */
+    const TREE_TYPE: TreeType;
+}
+
+/**
+ * 4Kb frame
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Size4K {}
+
+/**
+ * 2Mb big page
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Size2M {}
+
+/**
+ * 1Gb huge page
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Size1G {}
+
+impl sealed::Sealed for Size4K {}
+impl sealed::Sealed for Size2M {}
+impl sealed::Sealed for Size1G {}
+
+impl PageSize for Size4K {
+    const SIZE: u64 = FRAME_SIZE;
+    const TREE_TYPE: TreeType = TreeType::Tree4kb;
+}
+
+impl PageSize for Size2M {
+    const SIZE: u64 = BIG_PAGE_SIZE;
+    const TREE_TYPE: TreeType = TreeType::Tree2mb;
+}
+
+impl PageSize for Size1G {
+    const SIZE: u64 = HUGE_PAGE_SIZE;
+    const TREE_TYPE: TreeType = TreeType::Tree1gb;
+}
+
+/**
+ * Allocated page of size `S`
+ * the handle cannot be copied, it is consumed when the page is deallocated
+ */
+#[must_use]
+#[derive(PartialEq, Eq, Debug)]
+pub struct Frame<S: PageSize> {
+    frame_id: usize,
+    addr: PhysAddr,
+    size: PhantomData<S>,
+}
+
+impl<S: PageSize> Frame<S> {
+    pub(crate) fn new(frame_id: usize, addr: PhysAddr) -> Self {
+        assert!(addr.is_aligned(S::SIZE));
+        Frame {
+            frame_id,
+            addr,
+            size: PhantomData,
+        }
+    }
+
+    /**
+     * Return the index of the first 4Kb frame of the page
+     */
+    pub fn index(&self) -> usize {
+        self.frame_id
+    }
+
+    /**
+     * Return the physical address of the page
+     */
+    pub fn start_address(&self) -> PhysAddr {
+        self.addr
+    }
+
+    /**
+     * Return the size of the page in bytes
+     */
+    pub fn size(&self) -> u64 {
+        S::SIZE
+    }
+
+    /**
+     * Give up the handle and return the index of the first 4Kb frame of the page, the page
+     * stays allocated until it is freed with the raw API or turned back into a handle
+     */
+    pub fn into_index(self) -> usize {
+        self.frame_id
+    }
+}
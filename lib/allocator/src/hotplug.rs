@@ -0,0 +1,254 @@
+//! Memory hotplug of 1Gb blocks
+//!
+//! An offline 1Gb block has its level 1 bit cleared in the three trees, as an allocated 1Gb page,
+//! so that no page is ever taken from it. A bitmap with one bit per 1Gb block tells it apart from
+//! an allocated 1Gb page, pages inside an offline block are reported out of range. Level 2 and
+//! level 3 bits are left as they were so that `online_region` can restore the block: they still
+//! show its frames free, but the free counters of the block and of its 2Mb blocks are 0 and
+//! `integrity_report` only checks the level 1 bits of offline blocks. Memory map holes inside a
+//! block are neither free nor allocated, they do not prevent it from going offline.
+
+use crate::error::AllocError;
+use crate::{BuddyAllocator, Level, PlacementPolicy, TreeType};
+
+impl BuddyAllocator {
+    /**
+     * Return the number of 64 bits words of the offline bitmap of `num_frames` frames
+     */
+    pub(crate) fn offline_size(num_frames: usize) -> usize {
+        num_frames.div_ceil(512 * 512).div_ceil(64)
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Remove 1Gb block `gb_index` from the allocator, its frames are never handed out until it is
+     * brought back with `online_region`
+     * return an error and do nothing if one of its frames is allocated, holes of the memory map
+     * do not count as allocated
+     */
+    pub fn offline_region(&mut self, gb_index: usize) -> Result<(), AllocError> {
+        if gb_index >= self.nb_gb {
+            return Err(AllocError::OutOfRange);
+        }
+        if !self.is_region_online(gb_index) {
+            return Ok(());
+        }
+        let (start, end) = self.region_frames(gb_index);
+        let nb_holes = (start..end)
+            .filter(|&frame_id| self.is_hole(frame_id))
+            .count();
+        if self.count_free_frames(start..end) + nb_holes != end - start {
+            return Err(AllocError::Overlap);
+        }
+
+        self.set_level1_bit(TreeType::Tree1gb, gb_index, false);
+        self.set_level1_bit(TreeType::Tree2mb, gb_index, false);
+        self.set_level1_bit(TreeType::Tree4kb, gb_index, false);
+        self.offline[gb_index / 64] |= 1u64 << (gb_index % 64);
+
+        self.account_region(start, end, false);
+        Ok(())
+    }
+
+    /**
+     * Bring 1Gb block `gb_index` back after `offline_region`, its frames are free again
+     * nothing is done if the block is already online
+     */
+    pub fn online_region(&mut self, gb_index: usize) -> Result<(), AllocError> {
+        if gb_index >= self.nb_gb {
+            return Err(AllocError::OutOfRange);
+        }
+        if self.is_region_online(gb_index) {
+            return Ok(());
+        }
+
+        // level 2 and level 3 bits were left untouched while the block was offline
+        let first_block_l2 = self.compute_first_block_index(gb_index, 0, Level::Level2);
+        self.offline[gb_index / 64] &= !(1u64 << (gb_index % 64));
+        if self
+            .search_first_bit_set(TreeType::Tree4kb, first_block_l2)
+            .is_some()
+        {
+            self.set_level1_bit(TreeType::Tree4kb, gb_index, true);
+        }
+        if self
+            .search_first_bit_set(TreeType::Tree2mb, first_block_l2)
+            .is_some()
+        {
+            self.set_level1_bit(TreeType::Tree2mb, gb_index, true);
+        }
+        if self.all_free(TreeType::Tree2mb, first_block_l2) {
+            self.set_level1_bit(TreeType::Tree1gb, gb_index, true);
+        }
+
+        let (start, end) = self.region_frames(gb_index);
+        self.account_region(start, end, true);
+        Ok(())
+    }
+
+    /**
+     * Update the free counters when the frames of [start, end) outside of the memory map holes
+     * are freed or allocated
+     */
+    fn account_region(&mut self, start: usize, end: usize, freed: bool) {
+        let mut frame_id = start;
+        while frame_id < end {
+            if self.is_hole(frame_id) {
+                frame_id += 1;
+                continue;
+            }
+            let run_end = (frame_id..end).find(|&i| self.is_hole(i)).unwrap_or(end);
+            self.account_frames(frame_id, run_end - frame_id, freed);
+            frame_id = run_end;
+        }
+    }
+
+    /**
+     * Return false if 1Gb block `gb_index` was removed with `offline_region`
+     */
+    pub fn is_region_online(&self, gb_index: usize) -> bool {
+        assert!(gb_index < self.nb_gb);
+        self.offline[gb_index / 64] & (1u64 << (gb_index % 64)) == 0
+    }
+
+    /**
+     * Return true if frame `frame_id` belongs to an offline 1Gb block
+     */
+    #[inline(always)]
+    pub(crate) fn is_offline(&self, frame_id: usize) -> bool {
+        !self.is_region_online(frame_id >> 18)
+    }
+
+    /**
+     * Return the range [start, end) of frames of 1Gb block `gb_index` inside the managed range
+     */
+    fn region_frames(&self, gb_index: usize) -> (usize, usize) {
+        let start = gb_index << 18;
+        (start, (start + 512 * 512).min(self.nb_pages))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeallocError, MemoryKind, MemoryRegion, HUGE_PAGE_SIZE};
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_offline_and_online_region() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(3 * GB);
+        let frame = frame_alloc.allocate_frame().unwrap();
+        assert_eq!(frame_alloc.offline_region(0), Err(AllocError::Overlap));
+        assert_eq!(frame_alloc.offline_region(3), Err(AllocError::OutOfRange));
+
+        frame_alloc.offline_region(2).unwrap();
+        frame_alloc.offline_region(1).unwrap();
+        assert!(!frame_alloc.is_region_online(1));
+        assert_eq!(frame_alloc.stat_free_memory(), (0, 511, 511));
+        assert_eq!(frame_alloc.free_frames_in_1gb(GB), 0);
+        assert_eq!(frame_alloc.allocate_huge_page(), None);
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(GB),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(
+            frame_alloc.allocate_frame_at(GB + 1),
+            Err(AllocError::OutOfRange)
+        );
+        frame_alloc.check_integrity();
+
+        // the offline blocks are never used, even once the online one is full
+        for _ in 0..511 {
+            assert!(frame_alloc.allocate_big_page().unwrap() < GB);
+        }
+        assert_eq!(frame_alloc.allocate_frame(), Some(1));
+        assert_eq!(frame_alloc.allocate_big_page(), None);
+
+        frame_alloc.online_region(1).unwrap();
+        frame_alloc.online_region(1).unwrap();
+        assert_eq!(frame_alloc.free_frames_in_1gb(GB), GB);
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(GB));
+        assert_eq!(frame_alloc.offline_region(1), Err(AllocError::Overlap));
+        frame_alloc.deallocate_huge_page(GB).unwrap();
+        frame_alloc.deallocate_frame(frame).unwrap();
+        assert_eq!(frame_alloc.allocate_frame(), Some(0));
+    }
+
+    #[test]
+    fn test_offline_partial_region() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB + 1000);
+        let free = frame_alloc.stat_free_memory();
+        frame_alloc.offline_region(1).unwrap();
+        assert_eq!(frame_alloc.free_frames_in_1gb(GB), 0);
+        assert_eq!(frame_alloc.free_frames_in_2mb(GB), 0);
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(0));
+        assert_eq!(frame_alloc.allocate_frame(), None);
+
+        frame_alloc.online_region(1).unwrap();
+        assert_eq!(frame_alloc.free_frames_in_1gb(GB), 1000);
+        assert_eq!(frame_alloc.free_frames_in_2mb(GB + 512), 488);
+        frame_alloc.deallocate_huge_page(0).unwrap();
+        assert_eq!(frame_alloc.stat_free_memory(), free);
+        frame_alloc.check_integrity();
+    }
+
+    #[test]
+    fn test_offline_region_in_ranges() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB);
+        frame_alloc.allocate_huge_page_at(0).unwrap();
+        frame_alloc.offline_region(1).unwrap();
+        assert_eq!(
+            frame_alloc.reserve_range(GB - 10, 20),
+            Err(AllocError::OutOfRange)
+        );
+        // the run is checked up to the offline block before the first page is freed
+        assert_eq!(
+            frame_alloc.deallocate_contiguous(0, 2, HUGE_PAGE_SIZE),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(frame_alloc.allocation_size(0), Some(TreeType::Tree1gb));
+        frame_alloc.check_integrity();
+    }
+
+    #[test]
+    fn test_offline_region_with_holes() {
+        let regions = [
+            MemoryRegion {
+                start: 0,
+                length: 0x9F000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x100000,
+                length: HUGE_PAGE_SIZE as usize,
+                kind: MemoryKind::Usable,
+            },
+        ];
+        let mut frame_alloc = BuddyAllocator::from_memory_map(&regions);
+        let free = frame_alloc.free_pages(TreeType::Tree4kb);
+        frame_alloc.allocate_frame_at(0x9E).unwrap();
+        assert_eq!(frame_alloc.offline_region(0), Err(AllocError::Overlap));
+        frame_alloc.deallocate_frame(0x9E).unwrap();
+
+        // the hole at 0x9F does not prevent the block from going offline
+        frame_alloc.offline_region(0).unwrap();
+        assert_eq!(frame_alloc.free_frames_in_1gb(0), 0);
+        assert_eq!(frame_alloc.free_frames_in_2mb(0), 0);
+        assert_eq!(
+            frame_alloc.free_pages(TreeType::Tree4kb),
+            free - (GB - 0x61)
+        );
+        frame_alloc.check_integrity();
+
+        frame_alloc.online_region(0).unwrap();
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree4kb), free);
+        assert_eq!(frame_alloc.free_frames_in_2mb(0), 512 - 0x61);
+        assert_eq!(
+            frame_alloc.deallocate_frame(0x9F),
+            Err(DeallocError::OutOfRange)
+        );
+        frame_alloc.check_integrity();
+    }
+}
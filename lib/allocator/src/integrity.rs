@@ -0,0 +1,355 @@
+//! Integrity checker of the trees
+//!
+//! Every summary bit is checked against its children: a level 0, 1 or 2 bit is set when one of
+//! its children is free, except for an allocated 2Mb or 1Gb page whose bit is cleared over free
+//! children in the 4Kb tree. The 2Mb tree marks a 2Mb block free only when all its frames are free
+//! in the 4Kb tree and the 1Gb tree marks a 1Gb block free only when all its 2Mb blocks are free
+//! in the 2Mb tree. Level 1 bits of offline 1Gb blocks are cleared, their lower levels keep the
+//! state to restore when the block is back online and are not checked. No bit is set beyond the
+//! managed range. Free counters are not checked, they are computed from the trees.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{BuddyAllocator, Level, PlacementPolicy, TreeType};
+
+const TREES: [TreeType; 3] = [TreeType::Tree4kb, TreeType::Tree2mb, TreeType::Tree1gb];
+
+/**
+ * Invariant broken by a bit of the trees
+ * SummaryMismatch: a level 0, 1 or 2 bit does not match the bits of its children
+ * TreeMismatch: a bit of the 2Mb tree does not match the 4Kb tree, or a bit of the 1Gb tree does
+ * not match the 2Mb tree
+ * BeyondRange: a bit of a frame or a block outside of the managed range is set
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ViolationKind {
+    SummaryMismatch,
+    TreeMismatch,
+    BeyondRange,
+}
+
+/**
+ * Violation found by the integrity checker, at bit `index` of level `level` of tree `tree`
+ * `index` counts from the first bit of the level: 512Gb group at level 0, 1Gb block at level 1,
+ * 2Mb block at level 2 and 4Kb frame at level 3
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub tree: TreeType,
+    pub level: Level,
+    pub index: usize,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tree = match self.tree {
+            TreeType::Tree4kb => "4Kb",
+            TreeType::Tree2mb => "2Mb",
+            TreeType::Tree1gb => "1Gb",
+        };
+        let msg = match self.kind {
+            ViolationKind::SummaryMismatch => "does not match its children",
+            ViolationKind::TreeMismatch => "does not match the tree below",
+            ViolationKind::BeyondRange => "is set beyond the managed range",
+        };
+        write!(
+            f,
+            "bit {} of level {} of the {} tree {}",
+            self.index, self.level as usize, tree, msg
+        )
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Return every violation of the invariants of the trees, empty if integrity is ensured
+     */
+    #[cfg(feature = "alloc")]
+    pub fn integrity_report(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        self.for_each_violation(|violation| violations.push(violation));
+        violations
+    }
+
+    /**
+     * Call `on_violation` for every violation of the invariants of the trees, level 0 and level 1
+     * bits beyond the managed range first, then 1Gb block by 1Gb block
+     */
+    pub fn for_each_violation(&self, mut on_violation: impl FnMut(Violation)) {
+        let mut report = |kind, tree, level, index| {
+            on_violation(Violation {
+                kind,
+                tree,
+                level,
+                index,
+            })
+        };
+
+        let nb_groups = self.nb_gb.div_ceil(512);
+        for tree_type in TREES {
+            let tree = self.tree(tree_type);
+            for l0_idx in 0..512 {
+                let free = bit(tree, 0, l0_idx);
+                if l0_idx >= nb_groups {
+                    if free {
+                        report(ViolationKind::BeyondRange, tree_type, Level::Level0, l0_idx);
+                    }
+                    continue;
+                }
+                let first_block_l1 = self.compute_first_block_index(l0_idx << 9, 0, Level::Level1);
+                let any_free = tree[first_block_l1..first_block_l1 + 8]
+                    .iter()
+                    .any(|&word| word != 0);
+                if free != any_free {
+                    report(
+                        ViolationKind::SummaryMismatch,
+                        tree_type,
+                        Level::Level0,
+                        l0_idx,
+                    );
+                }
+            }
+            for l1_idx in self.nb_gb..nb_groups * 512 {
+                if self.level1_bit(tree_type, l1_idx) {
+                    report(ViolationKind::BeyondRange, tree_type, Level::Level1, l1_idx);
+                }
+            }
+        }
+
+        for l1_idx in 0..self.nb_gb {
+            self.check_level1(l1_idx, &mut report);
+            for l2_idx in 0..512 {
+                self.check_level2(l1_idx, l2_idx, &mut report);
+            }
+        }
+    }
+
+    /**
+     * Return true if integrity is ensured, see `for_each_violation`
+     */
+    pub(crate) fn is_integrity_ensured(&self) -> bool {
+        let mut ensured = true;
+        self.for_each_violation(|_| ensured = false);
+        ensured
+    }
+
+    /**
+     * Check the level 1 bits of 1Gb block `l1_idx` against its level 2 bits
+     */
+    fn check_level1(
+        &self,
+        l1_idx: usize,
+        report: &mut impl FnMut(ViolationKind, TreeType, Level, usize),
+    ) {
+        let mut report = |kind, tree_type| report(kind, tree_type, Level::Level1, l1_idx);
+        let [free_4kb, free_2mb, free_1gb] =
+            TREES.map(|tree_type| self.level1_bit(tree_type, l1_idx));
+        let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+        let any_free_4kb = self
+            .search_first_bit_set(TreeType::Tree4kb, first_block_l2)
+            .is_some();
+        let any_free_2mb = self
+            .search_first_bit_set(TreeType::Tree2mb, first_block_l2)
+            .is_some();
+        let all_free_2mb = self.all_free(TreeType::Tree2mb, first_block_l2);
+
+        if !self.is_region_online(l1_idx) {
+            for (tree_type, free) in TREES.into_iter().zip([free_4kb, free_2mb, free_1gb]) {
+                if free {
+                    report(ViolationKind::SummaryMismatch, tree_type);
+                }
+            }
+        } else if !free_4kb && any_free_4kb {
+            // allocated huge page, its 2Mb blocks are all free below the level 1 bits
+            if !all_free_2mb {
+                report(ViolationKind::SummaryMismatch, TreeType::Tree4kb);
+            }
+            if free_2mb {
+                report(ViolationKind::TreeMismatch, TreeType::Tree2mb);
+            }
+            if free_1gb {
+                report(ViolationKind::TreeMismatch, TreeType::Tree1gb);
+            }
+        } else {
+            if free_4kb != any_free_4kb {
+                report(ViolationKind::SummaryMismatch, TreeType::Tree4kb);
+            }
+            if free_2mb != any_free_2mb {
+                report(ViolationKind::SummaryMismatch, TreeType::Tree2mb);
+            }
+            if free_1gb != all_free_2mb {
+                report(ViolationKind::TreeMismatch, TreeType::Tree1gb);
+            }
+        }
+    }
+
+    /**
+     * Check the level 2 bits of 2Mb block `l2_idx` of 1Gb block `l1_idx` against its level 3 bits
+     */
+    fn check_level2(
+        &self,
+        l1_idx: usize,
+        l2_idx: usize,
+        report: &mut impl FnMut(ViolationKind, TreeType, Level, usize),
+    ) {
+        let first_frame = (l1_idx << 18) | (l2_idx << 9);
+        let nb_frames = self.nb_pages.saturating_sub(first_frame).min(512);
+        let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+        let words = &self.tree_4kb[first_block_l3..first_block_l3 + 8];
+        for (i, &word) in words.iter().enumerate() {
+            let nb_bits = nb_frames.saturating_sub(64 * i).min(64);
+            let valid = if nb_bits == 64 {
+                u64::MAX
+            } else {
+                (1u64 << nb_bits) - 1
+            };
+            let beyond = word & !valid;
+            if beyond != 0 {
+                let frame_id = first_frame + 64 * i + beyond.trailing_zeros() as usize;
+                report(
+                    ViolationKind::BeyondRange,
+                    TreeType::Tree4kb,
+                    Level::Level3,
+                    frame_id,
+                );
+            }
+        }
+
+        let mut report = |kind, tree_type| report(kind, tree_type, Level::Level2, first_frame >> 9);
+        let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+        let free_4kb = bit(&self.tree_4kb, first_block_l2, l2_idx);
+        let free_2mb = bit(&self.tree_2mb, first_block_l2, l2_idx);
+        if nb_frames == 0 {
+            if free_4kb {
+                report(ViolationKind::BeyondRange, TreeType::Tree4kb);
+            }
+            if free_2mb {
+                report(ViolationKind::BeyondRange, TreeType::Tree2mb);
+            }
+            return;
+        }
+
+        let any_free = words.iter().any(|&word| word != 0);
+        let all_free = words.iter().all(|&word| word == u64::MAX);
+        // allocated big page, its frames are all free below the level 2 bit
+        let big_page = !free_4kb && all_free;
+        if free_4kb != any_free && !big_page {
+            report(ViolationKind::SummaryMismatch, TreeType::Tree4kb);
+        }
+        if free_2mb != (free_4kb && all_free) {
+            report(ViolationKind::TreeMismatch, TreeType::Tree2mb);
+        }
+    }
+
+    /**
+     * Return the level 1 bit of 1Gb block `l1_idx` of a tree, even beyond the managed range
+     */
+    fn level1_bit(&self, tree_type: TreeType, l1_idx: usize) -> bool {
+        let first_block_l1 = self.compute_first_block_index(l1_idx, 0, Level::Level1);
+        bit(self.tree(tree_type), first_block_l1, l1_idx % 512)
+    }
+}
+
+/**
+ * Return bit `idx` of the bits stored from word `start` of `tree`
+ */
+fn bit(tree: &[u64], start: usize, idx: usize) -> bool {
+    tree[start + idx / 64] & (1u64 << (idx % 64)) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_integrity_of_valid_trees() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(3 * GB + 1000);
+        assert_eq!(frame_alloc.integrity_report(), []);
+
+        frame_alloc.allocate_frame().unwrap();
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        frame_alloc.reserve_range(GB + 1000, GB / 2).unwrap();
+        frame_alloc.offline_region(3).unwrap();
+        assert_eq!(frame_alloc.integrity_report(), []);
+
+        frame_alloc.split_big_page(big_page).unwrap();
+        frame_alloc.deallocate_frame(big_page + 1).unwrap();
+        frame_alloc.split_huge_page(huge_page).unwrap();
+        frame_alloc.deallocate_big_page(huge_page).unwrap();
+        assert_eq!(frame_alloc.integrity_report(), []);
+        frame_alloc.check_integrity();
+    }
+
+    #[test]
+    fn test_integrity_violations() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB + 1000);
+        frame_alloc.allocate_frame().unwrap();
+        frame_alloc.offline_region(1).unwrap();
+
+        // frame 0 is allocated in the 4Kb tree but its 2Mb block is free in the 2Mb tree
+        let first_block_l2 = frame_alloc.compute_first_block_index(0, 0, Level::Level2);
+        frame_alloc.tree_2mb[first_block_l2] |= 1;
+        // frame beyond the managed range
+        let first_block_l3 = frame_alloc.compute_first_block_index(1, 1, Level::Level3);
+        frame_alloc.tree_4kb[first_block_l3 + 7] |= 1 << 63;
+        // offline 1Gb block marked free
+        let first_block_l1 = frame_alloc.compute_first_block_index(1, 0, Level::Level1);
+        frame_alloc.tree_4kb[first_block_l1] |= 1 << 1;
+        // 512Gb group beyond the managed range
+        frame_alloc.tree_1gb[0] |= 1 << 2;
+
+        let violation = |kind, tree, level, index| Violation {
+            kind,
+            tree,
+            level,
+            index,
+        };
+        let report = frame_alloc.integrity_report();
+        assert_eq!(
+            report,
+            [
+                violation(
+                    ViolationKind::BeyondRange,
+                    TreeType::Tree1gb,
+                    Level::Level0,
+                    2
+                ),
+                violation(
+                    ViolationKind::TreeMismatch,
+                    TreeType::Tree1gb,
+                    Level::Level1,
+                    0
+                ),
+                violation(
+                    ViolationKind::TreeMismatch,
+                    TreeType::Tree2mb,
+                    Level::Level2,
+                    0
+                ),
+                violation(
+                    ViolationKind::SummaryMismatch,
+                    TreeType::Tree4kb,
+                    Level::Level1,
+                    1
+                ),
+                violation(
+                    ViolationKind::BeyondRange,
+                    TreeType::Tree4kb,
+                    Level::Level3,
+                    GB + 1023
+                ),
+            ]
+        );
+        assert_eq!(
+            report[2].to_string(),
+            "bit 0 of level 2 of the 2Mb tree does not match the tree below"
+        );
+        assert!(!frame_alloc.is_integrity_ensured());
+    }
+}
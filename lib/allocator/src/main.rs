@@ -1,6 +1,5 @@
-pub mod allocator;
+use allocator::BuddyAllocator;
 
-use crate::allocator::BuddyAllocator;
 fn main() {
     let mut frame_alloc = Box::new(BuddyAllocator::new());
     println!("Allocator instanciated!");
@@ -20,7 +19,7 @@ fn main() {
 
         // deallocates all frames
         for i in 0..NB_PAGES {
-            frame_alloc.deallocate_frame(i);
+            frame_alloc.deallocate_frame(i).unwrap();
         }
 
         // allocates all possible big pages
@@ -32,7 +31,7 @@ fn main() {
 
             // deallocates all big pages
             for i in 0..NB_PAGES / 512 {
-                frame_alloc.deallocate_big_page(i * 512);
+                frame_alloc.deallocate_big_page(i * 512).unwrap();
             }
         }
 
@@ -45,7 +44,7 @@ fn main() {
 
             // deallocates all big pages
             for i in 0..NB_GB {
-                frame_alloc.deallocate_huge_page(i * 512 * 512);
+                frame_alloc.deallocate_huge_page(i * 512 * 512).unwrap();
             }
         }
     }
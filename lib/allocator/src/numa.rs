@@ -0,0 +1,424 @@
+//! NUMA aware allocation
+//!
+//! Each node owns a `BuddyAllocator` covering its own physical range. Pages are allocated from a
+//! preferred node, then from the other nodes in the fallback order of the preferred node.
+//! Pages are identified by their physical address since frame indexes are local to a node.
+
+use core::ops::Range;
+
+use alloc::vec::Vec;
+
+use crate::addr::{PhysAddr, BIG_PAGE_SIZE, FRAME_SIZE, HUGE_PAGE_SIZE};
+use crate::error::{DeallocError, NumaError};
+use crate::{BuddyAllocator, FirstFitLow, PlacementPolicy};
+
+/**
+ * Allocator owning one set of trees per NUMA node, `P` is the placement policy of every node
+ */
+pub struct NumaAllocator<P = FirstFitLow> {
+    nodes: Vec<BuddyAllocator<P>>,
+    ranges: Vec<Range<PhysAddr>>,
+    fallback: Vec<Vec<usize>>,
+}
+
+impl NumaAllocator {
+    /**
+     * Create an allocator with one node per physical range, all frames being free
+     * the trees of a node whose range does not start on a 1Gb boundary start at the boundary
+     * below, the frames before its range are reserved and never handed out
+     * return an error if no range is given, if a range does not hold a whole 4Kb frame or if two
+     * ranges overlap
+     */
+    pub fn with_node_ranges(ranges: &[Range<PhysAddr>]) -> Result<Self, NumaError> {
+        let frames = |range: &Range<PhysAddr>| {
+            let first = range.start.as_u64().div_ceil(FRAME_SIZE);
+            first..(range.end.as_u64() / FRAME_SIZE).max(first)
+        };
+        if ranges.is_empty() || ranges.iter().any(|range| frames(range).is_empty()) {
+            return Err(NumaError::InvalidRange);
+        }
+        for (i, range) in ranges.iter().enumerate() {
+            let overlap = ranges[i + 1..]
+                .iter()
+                .any(|other| range.start < other.end && other.start < range.end);
+            if overlap {
+                return Err(NumaError::InvalidRange);
+            }
+        }
+
+        let nodes = ranges
+            .iter()
+            .map(|range| {
+                let base = PhysAddr::new(range.start.as_u64() & !(HUGE_PAGE_SIZE - 1));
+                let base_frame = base.as_u64() / FRAME_SIZE;
+                let frames = frames(range);
+                let num_frames = (frames.end - base_frame) as usize;
+                let mut node = BuddyAllocator::with_capacity(num_frames).with_base_address(base);
+                if frames.start > base_frame {
+                    let nb_reserved = (frames.start - base_frame) as usize;
+                    node.reserve_range(0, nb_reserved).unwrap();
+                }
+                node
+            })
+            .collect();
+        Ok(Self::with_ranges(nodes, ranges.to_vec()))
+    }
+}
+
+impl<P: PlacementPolicy> NumaAllocator<P> {
+    /**
+     * Create an allocator from one allocator per node, node i is `nodes[i]`
+     * each allocator covers the physical range of its node (see `with_base_address`), ranges
+     * must not overlap
+     * by default a node falls back to the other nodes in increasing index order
+     */
+    pub fn new(nodes: Vec<BuddyAllocator<P>>) -> Self {
+        let ranges = nodes.iter().map(Self::range_of).collect();
+        Self::with_ranges(nodes, ranges)
+    }
+
+    /**
+     * Replace the placement policy of every node, pages already allocated stay in place
+     */
+    pub fn with_policy<Q: PlacementPolicy + Clone>(self, policy: Q) -> NumaAllocator<Q> {
+        NumaAllocator {
+            nodes: self
+                .nodes
+                .into_iter()
+                .map(|node| node.with_policy(policy.clone()))
+                .collect(),
+            ranges: self.ranges,
+            fallback: self.fallback,
+        }
+    }
+
+    /**
+     * Set the nodes tried after `node` when it is out of memory, in order
+     * return an error if a node does not exist, if `node` itself or a node appears twice in
+     * `order`
+     */
+    pub fn set_fallback_order(&mut self, node: usize, order: &[usize]) -> Result<(), NumaError> {
+        let nb_nodes = self.nodes.len();
+        if node >= nb_nodes || order.iter().any(|&other| other >= nb_nodes) {
+            return Err(NumaError::UnknownNode);
+        }
+        let mut seen = alloc::vec![false; nb_nodes];
+        seen[node] = true;
+        for &other in order {
+            if seen[other] {
+                return Err(NumaError::InvalidFallback);
+            }
+            seen[other] = true;
+        }
+        self.fallback[node] = order.to_vec();
+        Ok(())
+    }
+
+    /**
+     * Return the nodes tried after `node` when it is out of memory
+     */
+    pub fn fallback_order(&self, node: usize) -> &[usize] {
+        &self.fallback[node]
+    }
+
+    /**
+     * Return the number of nodes
+     */
+    pub fn nb_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /**
+     * Return the allocator of a node
+     */
+    pub fn node(&self, node: usize) -> &BuddyAllocator<P> {
+        &self.nodes[node]
+    }
+
+    /**
+     * Return the physical range of a node
+     */
+    pub fn node_range(&self, node: usize) -> Range<PhysAddr> {
+        self.ranges[node].clone()
+    }
+
+    /**
+     * Return the node whose physical range contains `addr`
+     */
+    pub fn node_of(&self, addr: PhysAddr) -> Option<usize> {
+        self.ranges.iter().position(|range| range.contains(&addr))
+    }
+
+    /**
+     * Return free memory of a node, see `BuddyAllocator::stat_free_memory`
+     */
+    pub fn stat_free_memory(&self, node: usize) -> (u64, u64, u64) {
+        self.nodes[node].stat_free_memory()
+    }
+
+    /**
+     * Return free memory of a node in constant time, see `BuddyAllocator::free_memory`
+     */
+    pub fn free_memory(&self, node: usize) -> (u64, u64, u64) {
+        self.nodes[node].free_memory()
+    }
+
+    /**
+     * Allocate 4kb page, preferably on node `preferred`
+     * return the physical address of the page and its node, None if every node is full or if
+     * `preferred` does not exist
+     */
+    pub fn allocate_frame(&mut self, preferred: usize) -> Option<(PhysAddr, usize)> {
+        self.allocate_with(preferred, BuddyAllocator::allocate_frame_addr)
+    }
+
+    /**
+     * Allocate 2Mb page, preferably on node `preferred`
+     * return the physical address of the page and its node, None if every node is full or if
+     * `preferred` does not exist
+     */
+    pub fn allocate_big_page(&mut self, preferred: usize) -> Option<(PhysAddr, usize)> {
+        self.allocate_with(preferred, BuddyAllocator::allocate_big_page_addr)
+    }
+
+    /**
+     * Allocate 1Gb page, preferably on node `preferred`
+     * return the physical address of the page and its node, None if every node is full or if
+     * `preferred` does not exist
+     */
+    pub fn allocate_huge_page(&mut self, preferred: usize) -> Option<(PhysAddr, usize)> {
+        self.allocate_with(preferred, BuddyAllocator::allocate_huge_page_addr)
+    }
+
+    /**
+     * Deallocate 4kb page on the node owning `addr`
+     */
+    pub fn deallocate_frame(&mut self, addr: PhysAddr) -> Result<(), DeallocError> {
+        self.node_mut_of(addr, FRAME_SIZE)?
+            .deallocate_frame_addr(addr)
+    }
+
+    /**
+     * Deallocate 2Mb page on the node owning `addr`
+     */
+    pub fn deallocate_big_page(&mut self, addr: PhysAddr) -> Result<(), DeallocError> {
+        self.node_mut_of(addr, BIG_PAGE_SIZE)?
+            .deallocate_big_page_addr(addr)
+    }
+
+    /**
+     * Deallocate 1Gb page on the node owning `addr`
+     */
+    pub fn deallocate_huge_page(&mut self, addr: PhysAddr) -> Result<(), DeallocError> {
+        self.node_mut_of(addr, HUGE_PAGE_SIZE)?
+            .deallocate_huge_page_addr(addr)
+    }
+
+    /**
+     * Create an allocator from one allocator per node and the physical range of each node
+     */
+    fn with_ranges(nodes: Vec<BuddyAllocator<P>>, ranges: Vec<Range<PhysAddr>>) -> Self {
+        assert!(!nodes.is_empty());
+        for (i, range) in ranges.iter().enumerate() {
+            for other in &ranges[i + 1..] {
+                assert!(range.end <= other.start || other.end <= range.start);
+            }
+        }
+
+        let nb_nodes = nodes.len();
+        let fallback = (0..nb_nodes)
+            .map(|node| (0..nb_nodes).filter(|&other| other != node).collect())
+            .collect();
+        Self {
+            nodes,
+            ranges,
+            fallback,
+        }
+    }
+
+    /**
+     * Try `allocate` on the preferred node then on its fallback nodes
+     */
+    fn allocate_with(
+        &mut self,
+        preferred: usize,
+        allocate: fn(&mut BuddyAllocator<P>) -> Option<PhysAddr>,
+    ) -> Option<(PhysAddr, usize)> {
+        let fallback = self.fallback.get(preferred)?;
+        let order = core::iter::once(preferred).chain(fallback.iter().copied());
+        for node in order {
+            if let Some(addr) = allocate(&mut self.nodes[node]) {
+                return Some((addr, node));
+            }
+        }
+        None
+    }
+
+    fn node_mut_of(
+        &mut self,
+        addr: PhysAddr,
+        page_size: u64,
+    ) -> Result<&mut BuddyAllocator<P>, DeallocError> {
+        if !addr.is_aligned(page_size) {
+            return Err(DeallocError::Misaligned);
+        }
+        let node = self.node_of(addr).ok_or(DeallocError::OutOfRange)?;
+        Ok(&mut self.nodes[node])
+    }
+
+    /**
+     * Return the physical range covered by the trees of a node
+     */
+    fn range_of(node: &BuddyAllocator<P>) -> Range<PhysAddr> {
+        let start = node.base_address();
+        let end = PhysAddr::new(start.as_u64() + node.capacity() as u64 * FRAME_SIZE);
+        start..end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FirstFitHigh;
+
+    const GB: u64 = HUGE_PAGE_SIZE;
+
+    fn two_nodes() -> NumaAllocator {
+        NumaAllocator::with_node_ranges(&[
+            PhysAddr::new(0)..PhysAddr::new(GB),
+            PhysAddr::new(4 * GB)..PhysAddr::new(5 * GB + 4 * BIG_PAGE_SIZE),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_preferred_node_and_fallback() {
+        let mut numa = two_nodes();
+        assert_eq!(numa.nb_nodes(), 2);
+        assert_eq!(numa.fallback_order(0), &[1]);
+
+        let (addr, node) = numa.allocate_frame(1).unwrap();
+        assert_eq!((addr, node), (PhysAddr::new(4 * GB), 1));
+        assert_eq!(numa.node_of(addr), Some(1));
+        assert_eq!(numa.stat_free_memory(1), (1, 3, 511));
+
+        // node 0 only has one huge page, then falls back to node 1
+        assert_eq!(numa.allocate_huge_page(0), Some((PhysAddr::new(0), 0)));
+        assert_eq!(numa.allocate_big_page(0).unwrap().1, 1);
+        assert_eq!(numa.stat_free_memory(0), (0, 0, 0));
+
+        assert!(numa.allocate_huge_page(0).is_none());
+        assert_eq!(numa.set_fallback_order(0, &[]), Ok(()));
+        assert!(numa.allocate_frame(0).is_none());
+
+        assert_eq!(numa.deallocate_huge_page(PhysAddr::new(0)), Ok(()));
+        assert_eq!(numa.deallocate_frame(addr), Ok(()));
+        assert_eq!(numa.deallocate_frame(addr), Err(DeallocError::NotAllocated));
+        assert_eq!(
+            numa.deallocate_frame(PhysAddr::new(2 * GB)),
+            Err(DeallocError::OutOfRange)
+        );
+        assert_eq!(
+            numa.deallocate_big_page(PhysAddr::new(4 * GB + FRAME_SIZE)),
+            Err(DeallocError::Misaligned)
+        );
+        assert_eq!(numa.stat_free_memory(0), (1, 0, 0));
+    }
+
+    #[test]
+    fn test_fallback_order() {
+        let mut numa = NumaAllocator::with_node_ranges(&[
+            PhysAddr::new(0)..PhysAddr::new(BIG_PAGE_SIZE),
+            PhysAddr::new(GB)..PhysAddr::new(GB + BIG_PAGE_SIZE),
+            PhysAddr::new(2 * GB)..PhysAddr::new(2 * GB + BIG_PAGE_SIZE),
+        ])
+        .unwrap();
+        assert_eq!(numa.set_fallback_order(0, &[2, 1]), Ok(()));
+        assert_eq!(numa.allocate_big_page(0).unwrap().1, 0);
+        assert_eq!(numa.allocate_big_page(0).unwrap().1, 2);
+        assert_eq!(numa.allocate_big_page(0).unwrap().1, 1);
+        assert!(numa.allocate_big_page(0).is_none());
+    }
+
+    #[test]
+    fn test_invalid_nodes() {
+        let mut numa = NumaAllocator::with_node_ranges(&[
+            PhysAddr::new(0)..PhysAddr::new(BIG_PAGE_SIZE),
+            PhysAddr::new(GB)..PhysAddr::new(GB + BIG_PAGE_SIZE),
+            PhysAddr::new(2 * GB)..PhysAddr::new(2 * GB + BIG_PAGE_SIZE),
+        ])
+        .unwrap();
+        assert_eq!(
+            numa.set_fallback_order(0, &[1, 2, 1]),
+            Err(NumaError::InvalidFallback)
+        );
+        assert_eq!(
+            numa.set_fallback_order(0, &[1, 0]),
+            Err(NumaError::InvalidFallback)
+        );
+        assert_eq!(
+            numa.set_fallback_order(0, &[3]),
+            Err(NumaError::UnknownNode)
+        );
+        assert_eq!(numa.set_fallback_order(3, &[]), Err(NumaError::UnknownNode));
+        assert_eq!(numa.fallback_order(0), &[1, 2]);
+
+        assert!(numa.allocate_frame(3).is_none());
+        assert!(numa.allocate_big_page(usize::MAX).is_none());
+
+        // empty, reversed, overlapping or too small ranges
+        let invalid = [
+            &[][..],
+            &[PhysAddr::new(GB)..PhysAddr::new(GB)],
+            &[PhysAddr::new(2 * GB)..PhysAddr::new(GB)],
+            &[PhysAddr::new(GB + 1)..PhysAddr::new(GB + FRAME_SIZE)],
+            &[
+                PhysAddr::new(0)..PhysAddr::new(2 * GB),
+                PhysAddr::new(GB)..PhysAddr::new(3 * GB),
+            ],
+        ];
+        for ranges in invalid {
+            assert!(matches!(
+                NumaAllocator::with_node_ranges(ranges),
+                Err(NumaError::InvalidRange)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_unaligned_nodes() {
+        let mut numa = NumaAllocator::with_node_ranges(&[
+            PhysAddr::new(0)..PhysAddr::new(GB + GB / 2),
+            PhysAddr::new(GB + GB / 2)..PhysAddr::new(3 * GB),
+        ])
+        .unwrap();
+        assert_eq!(numa.node_of(PhysAddr::new(GB + GB / 4)), Some(0));
+        assert_eq!(numa.node_of(PhysAddr::new(GB + GB / 2)), Some(1));
+        assert_eq!(
+            numa.node_range(1),
+            PhysAddr::new(GB + GB / 2)..PhysAddr::new(3 * GB)
+        );
+        assert_eq!(numa.stat_free_memory(1), (1, 256, 0));
+
+        assert_eq!(
+            numa.allocate_frame(1),
+            Some((PhysAddr::new(GB + GB / 2), 1))
+        );
+        assert_eq!(numa.allocate_huge_page(1), Some((PhysAddr::new(2 * GB), 1)));
+        // the partial gigabytes of both nodes have no huge page
+        assert_eq!(numa.allocate_huge_page(1), Some((PhysAddr::new(0), 0)));
+        assert!(numa.allocate_huge_page(1).is_none());
+        assert_eq!(
+            numa.deallocate_frame(PhysAddr::new(GB + GB / 4)),
+            Err(DeallocError::NotAllocated)
+        );
+    }
+
+    #[test]
+    fn test_numa_policy() {
+        let mut numa = two_nodes().with_policy(FirstFitHigh);
+        let (addr, node) = numa.allocate_frame(1).unwrap();
+        assert_eq!(node, 1);
+        assert_eq!(addr.as_u64(), 5 * GB + 4 * BIG_PAGE_SIZE - FRAME_SIZE);
+    }
+}
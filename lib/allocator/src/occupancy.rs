@@ -0,0 +1,219 @@
+//! Spatial occupancy map
+//!
+//! Managed frames are cut in buckets of a chosen number of 4Kb frames (1 for 4Kb, 512 for 2Mb,
+//! 512 * 512 for 1Gb, or any other granularity) and each bucket counts its frames by state: free
+//! or allocated as part of a 4Kb, 2Mb or 1Gb page, or in a hole of the memory map. Frames of
+//! offline 1Gb blocks are not counted. Allocated 1Gb and 2Mb pages and 2Mb blocks fully free or
+//! fully used (from their free counter) are counted at once, only partially used 2Mb blocks and
+//! blocks holding a hole are scanned frame by frame.
+
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+
+use crate::{BuddyAllocator, Level, PlacementPolicy, TreeType};
+
+/**
+ * Number of 4Kb frames of a bucket of the occupancy map in each state
+ */
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Occupancy {
+    pub free: usize,
+    pub allocated_4kb: usize,
+    pub allocated_2mb: usize,
+    pub allocated_1gb: usize,
+    pub holes: usize,
+}
+
+impl Occupancy {
+    /**
+     * Return the counter of frames allocated with `size`, of free frames if `size` is None
+     */
+    fn frames_mut(&mut self, size: Option<TreeType>) -> &mut usize {
+        match size {
+            None => &mut self.free,
+            Some(TreeType::Tree4kb) => &mut self.allocated_4kb,
+            Some(TreeType::Tree2mb) => &mut self.allocated_2mb,
+            Some(TreeType::Tree1gb) => &mut self.allocated_1gb,
+        }
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Return the occupancy of every bucket of `granularity` 4Kb frames, from frame 0
+     * the last bucket is smaller if `granularity` does not divide the number of managed frames
+     */
+    pub fn occupancy_map(&self, granularity: usize) -> Vec<Occupancy> {
+        assert!(granularity > 0);
+        let mut map = vec![Occupancy::default(); self.nb_pages.div_ceil(granularity)];
+
+        let mut frame_id = 0;
+        while frame_id < self.nb_pages {
+            let end_1gb = (frame_id + 512 * 512).min(self.nb_pages);
+            if frame_id % (512 * 512) == 0 && self.is_offline(frame_id) {
+                frame_id = end_1gb;
+                continue;
+            }
+
+            let size = self.allocation_size(frame_id);
+            if size == Some(TreeType::Tree1gb) {
+                add_frames(&mut map, granularity, frame_id..end_1gb, size);
+                frame_id = end_1gb;
+                continue;
+            }
+
+            let end = (frame_id + 512).min(self.nb_pages);
+            let nb_free = self.free_frames_in_2mb(frame_id);
+            let holes = size != Some(TreeType::Tree2mb) && self.has_holes_in_2mb(frame_id);
+            if size == Some(TreeType::Tree2mb) || (nb_free == 0 && !holes) {
+                // frames of a fully used block that is not a big page are 4Kb frames
+                let size = size.or(Some(TreeType::Tree4kb));
+                add_frames(&mut map, granularity, frame_id..end, size);
+            } else if nb_free == end - frame_id {
+                add_frames(&mut map, granularity, frame_id..end, None);
+            } else {
+                for i in frame_id..end {
+                    if self.is_hole(i) {
+                        map[i / granularity].holes += 1;
+                        continue;
+                    }
+                    let free = self.get_bit_level_index(TreeType::Tree4kb, Level::Level3, i);
+                    let size = if free { None } else { Some(TreeType::Tree4kb) };
+                    add_frames(&mut map, granularity, i..i + 1, size);
+                }
+            }
+            frame_id = end;
+        }
+        map
+    }
+}
+
+/**
+ * Count the frames of `range` in state `size` in the buckets of `granularity` frames covering it
+ */
+fn add_frames(
+    map: &mut [Occupancy],
+    granularity: usize,
+    range: Range<usize>,
+    size: Option<TreeType>,
+) {
+    let mut start = range.start;
+    while start < range.end {
+        let bucket = start / granularity;
+        let end = ((bucket + 1) * granularity).min(range.end);
+        *map[bucket].frames_mut(size) += end - start;
+        start = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryKind, MemoryRegion};
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_occupancy_map() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(3 * GB + 1000);
+        let frame = frame_alloc.allocate_frame().unwrap();
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        frame_alloc.reserve_range(1100, 400).unwrap();
+        frame_alloc.offline_region(1).unwrap();
+
+        let map = frame_alloc.occupancy_map(512);
+        assert_eq!(map.len(), 3 * 512 + 2);
+        assert_eq!(
+            map[frame / 512],
+            Occupancy {
+                free: 511,
+                allocated_4kb: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(map[big_page / 512].allocated_2mb, 512);
+        assert_eq!(map[2].allocated_4kb, 400);
+        assert_eq!(map[2].free, 112);
+        assert_eq!(map[GB / 512], Occupancy::default());
+        assert_eq!(map[huge_page / 512].allocated_1gb, 512);
+        assert_eq!(map[3 * 512 + 1].free, 488);
+
+        let map = frame_alloc.occupancy_map(GB);
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[0].free, GB - 1 - 512 - 400);
+        assert_eq!(map[0].allocated_4kb, 401);
+        assert_eq!(map[huge_page / GB].allocated_1gb, GB);
+        assert_eq!(map[3].free, 1000);
+
+        // buckets of an arbitrary size cross 2Mb blocks
+        let map = frame_alloc.occupancy_map(1000);
+        assert_eq!(map.len(), (3 * GB + 1000).div_ceil(1000));
+        assert_eq!(map[0].allocated_4kb, 1);
+        assert_eq!(map[0].allocated_2mb, 488);
+        assert_eq!(map[1].allocated_2mb, 24);
+        assert_eq!(map[1].allocated_4kb, 400);
+        let free: usize = map.iter().map(|bucket| bucket.free).sum();
+        assert_eq!(free, frame_alloc.free_pages(TreeType::Tree4kb));
+
+        let map = frame_alloc.occupancy_map(1);
+        assert_eq!(map.len(), 3 * GB + 1000);
+        assert_eq!(map[frame].allocated_4kb, 1);
+        assert_eq!(map[frame + 1].free, 1);
+        assert_eq!(map[1099].free, 1);
+        assert_eq!(map[1100].allocated_4kb, 1);
+    }
+
+    #[test]
+    fn test_occupancy_of_holes() {
+        let regions = [
+            MemoryRegion {
+                start: 0,
+                length: 0x9F000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x9F000,
+                length: 0x61000,
+                kind: MemoryKind::Reserved,
+            },
+            MemoryRegion {
+                start: 0x100000,
+                length: 0x100000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x200000,
+                length: 0x200000,
+                kind: MemoryKind::Mmio,
+            },
+            MemoryRegion {
+                start: 0x400000,
+                length: 0x200000,
+                kind: MemoryKind::Usable,
+            },
+        ];
+        let mut frame_alloc = BuddyAllocator::from_memory_map(&regions);
+        frame_alloc.allocate_frame_at(0).unwrap();
+
+        // holes are neither free nor allocated
+        let map = frame_alloc.occupancy_map(512);
+        assert_eq!(
+            map[0],
+            Occupancy {
+                free: 0x9F + 0x100 - 1,
+                allocated_4kb: 1,
+                holes: 0x61,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            map[1],
+            Occupancy {
+                holes: 512,
+                ..Default::default()
+            }
+        );
+        assert_eq!(map[2].free, 512);
+    }
+}
@@ -0,0 +1,348 @@
+//! Owner of allocated pages
+//!
+//! Pages may be tagged with the id of their owner (e.g. a VM), so that all of them are freed at
+//! once when the owner goes away. The optional table holds one 16 bits owner id per 4Kb frame,
+//! four per 64 bits word, big and huge pages use the id of their first frame. Id 0 means no
+//! owner, ids are cleared when a page is freed.
+
+use crate::error::OwnerAllocError;
+use crate::storage::TreeStorage;
+use crate::{BuddyAllocator, PlacementPolicy, TreeType};
+
+impl BuddyAllocator {
+    /**
+     * Return the number of 64 bits words of the owner ids of `num_frames` frames
+     */
+    pub fn owners_size(num_frames: usize) -> usize {
+        num_frames.div_ceil(4)
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Enable owner ids, see `set_owner` and `free_all`
+     */
+    #[cfg(feature = "alloc")]
+    pub fn with_owners(mut self) -> Self {
+        let size = <BuddyAllocator>::owners_size(self.nb_pages);
+        self.owners = Some(TreeStorage::boxed(size));
+        self
+    }
+
+    /**
+     * Same as `with_owners` but owner ids are placed in `storage`
+     * `storage` must hold at least `owners_size(capacity())` words
+     */
+    pub fn with_owners_in(mut self, storage: &'static mut [u64]) -> Self {
+        let size = <BuddyAllocator>::owners_size(self.nb_pages);
+        assert!(storage.len() >= size);
+        self.owners = Some(TreeStorage::borrowed(&mut storage[..size]));
+        self
+    }
+
+    /**
+     * Allocate a frame owned by `owner`
+     * return an error if no frame is free, if `owner` would exceed its hard limit or if owner
+     * ids are not enabled
+     */
+    pub fn allocate_frame_for(&mut self, owner: u16) -> Result<usize, OwnerAllocError> {
+        self.check_owners()?;
+        self.allocate_charged(TreeType::Tree4kb, owner, Self::claim_frame)
+    }
+
+    /**
+     * Allocate a big page owned by `owner`
+     * return an error if no big page is free, if `owner` would exceed its hard limit or if owner
+     * ids are not enabled
+     */
+    pub fn allocate_big_page_for(&mut self, owner: u16) -> Result<usize, OwnerAllocError> {
+        self.check_owners()?;
+        self.allocate_charged(TreeType::Tree2mb, owner, Self::claim_big_page)
+    }
+
+    /**
+     * Allocate a huge page owned by `owner`
+     * return an error if no huge page is free, if `owner` would exceed its hard limit or if
+     * owner ids are not enabled
+     */
+    pub fn allocate_huge_page_for(&mut self, owner: u16) -> Result<usize, OwnerAllocError> {
+        self.check_owners()?;
+        self.allocate_charged(TreeType::Tree1gb, owner, Self::claim_huge_page)
+    }
+
+    /**
+     * Tag the pages allocated by `allocate_frame`, `allocate_big_page`, `allocate_huge_page`
+     * and their zone variants with `owner` and charge them to it, 0 stops tagging
+     * return an error if owner ids are not enabled
+     */
+    pub fn set_current_owner(&mut self, owner: u16) -> Result<(), OwnerAllocError> {
+        self.check_owners()?;
+        self.current_owner = owner;
+        Ok(())
+    }
+
+    /**
+     * Return the owner of the pages allocated without explicit owner, None if there is none
+     */
+    pub fn current_owner(&self) -> Option<u16> {
+        match self.current_owner {
+            0 => None,
+            owner => Some(owner),
+        }
+    }
+
+    /**
+     * Allocate a page of `size` with `claim`, tagged with `owner` unless it is 0
+     * the page allocation functions and their zone variants go through here so that hard
+     * limits are checked before the trees are touched
+     */
+    pub(crate) fn allocate_charged(
+        &mut self,
+        size: TreeType,
+        owner: u16,
+        claim: impl FnOnce(&mut Self) -> Option<usize>,
+    ) -> Result<usize, OwnerAllocError> {
+        self.check_quota(owner, size.nb_frames())?;
+        let frame_id = claim(self).ok_or(OwnerAllocError::OutOfMemory)?;
+        if owner != 0 {
+            self.charge(owner, size.nb_frames(), true);
+            self.write_owner(frame_id, owner);
+        }
+        Ok(frame_id)
+    }
+
+    /**
+     * Return an error if owner ids are not enabled
+     */
+    pub(crate) fn check_owners(&self) -> Result<(), OwnerAllocError> {
+        match self.owners {
+            Some(_) => Ok(()),
+            None => Err(OwnerAllocError::Disabled),
+        }
+    }
+
+    /**
+     * Give the page starting at frame `frame_id` to `owner`, 0 removes its owner
+     * return an error if the page is not allocated, if `owner` would exceed its hard limit or
+     * if owner ids are not enabled
+     */
+    pub fn set_owner(&mut self, frame_id: usize, owner: u16) -> Result<(), OwnerAllocError> {
+        self.check_owners()?;
+        let nb_frames = self.allocated_page(frame_id)?.nb_frames();
+        let old_owner = self.read_owner(frame_id);
+        if owner != old_owner {
+            self.check_quota(owner, nb_frames)?;
+        }
+        self.charge(old_owner, nb_frames, false);
+        self.charge(owner, nb_frames, true);
+        self.write_owner(frame_id, owner);
+        Ok(())
+    }
+
+    /**
+     * Return the owner of the page starting at frame `frame_id`, None if it has no owner
+     */
+    pub fn owner(&self, frame_id: usize) -> Option<u16> {
+        assert!(frame_id < self.nb_pages);
+        match self.read_owner(frame_id) {
+            0 => None,
+            owner => Some(owner),
+        }
+    }
+
+    /**
+     * Iterate over the pages of `owner` in increasing order, along with their size
+     */
+    pub fn allocations_of(&self, owner: u16) -> impl Iterator<Item = (usize, TreeType)> + '_ {
+        assert!(owner != 0);
+        let owners: &[u64] = self.owners.as_deref().unwrap_or(&[]);
+        owners
+            .iter()
+            .enumerate()
+            .filter(|(_, &word)| word != 0)
+            .flat_map(|(i, _)| 4 * i..4 * i + 4)
+            .filter(move |&frame_id| self.read_owner(frame_id) == owner)
+            .map(|frame_id| (frame_id, self.allocation_size(frame_id).unwrap()))
+    }
+
+    /**
+     * Drop the reference of `owner` on each of its pages, see `put_frame`
+     * a page still shared by other users stays allocated and no longer belongs to `owner`
+     * return the number of pages released
+     */
+    pub fn free_all(&mut self, owner: u16) -> usize {
+        assert!(owner != 0);
+        let nb_words = self.owners.as_ref().map_or(0, |owners| owners.len());
+        let mut nb_pages = 0;
+        for i in 0..nb_words {
+            if self.owners.as_ref().unwrap()[i] == 0 {
+                continue;
+            }
+            for frame_id in 4 * i..4 * i + 4 {
+                if self.read_owner(frame_id) != owner {
+                    continue;
+                }
+                if self.put_frame(frame_id).unwrap() > 0 {
+                    self.set_owner(frame_id, 0).unwrap();
+                }
+                nb_pages += 1;
+            }
+        }
+        nb_pages
+    }
+
+    /**
+     * Give the owner of the page starting at `frame_id` to each of its pages of `page_frames`
+     * frames once it is split in `nb_frames / page_frames` pages
+     */
+    pub(crate) fn split_owner(&mut self, frame_id: usize, nb_frames: usize, page_frames: usize) {
+        let owner = self.read_owner(frame_id);
+        if owner != 0 {
+            for page in (frame_id..frame_id + nb_frames).step_by(page_frames) {
+                self.write_owner(page, owner);
+            }
+        }
+    }
+
+    /**
+     * Give the `nb_frames` frames starting at `frame_id` to the owner of their first page of
+     * `page_frames` frames once they are merged
+     */
+    pub(crate) fn merge_owner(&mut self, frame_id: usize, nb_frames: usize, page_frames: usize) {
+        if self.owners.is_some() {
+            let owner = self.read_owner(frame_id);
+            for page in (frame_id + page_frames..frame_id + nb_frames).step_by(page_frames) {
+                self.charge(self.read_owner(page), page_frames, false);
+                self.charge(owner, page_frames, true);
+                self.write_owner(page, 0);
+            }
+        }
+    }
+
+    /**
+     * Forget the owner of the `nb_frames` frames page starting at frame `frame_id` once it is
+     * freed
+     */
+    pub(crate) fn clear_owner(&mut self, frame_id: usize, nb_frames: usize) {
+        if self.owners.is_some() {
+            self.charge(self.read_owner(frame_id), nb_frames, false);
+            self.write_owner(frame_id, 0);
+        }
+    }
+
+    /**
+     * Return the owner id of frame `frame_id`, 0 if owner ids are not enabled
+     */
+    pub(crate) fn read_owner(&self, frame_id: usize) -> u16 {
+        self.owners.as_ref().map_or(0, |owners| {
+            (owners[frame_id / 4] >> (16 * (frame_id % 4))) as u16
+        })
+    }
+
+    /**
+     * Set the owner id of frame `frame_id`
+     */
+    fn write_owner(&mut self, frame_id: usize, owner: u16) {
+        let owners = self.owners.as_mut().unwrap();
+        let shift = 16 * (frame_id % 4);
+        owners[frame_id / 4] &= !(0xFFFF << shift);
+        owners[frame_id / 4] |= (owner as u64) << shift;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DeallocError;
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_free_all_by_owner() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(3 * GB).with_owners();
+        let frame = frame_alloc.allocate_frame_for(1).unwrap();
+        let big_page = frame_alloc.allocate_big_page_for(2).unwrap();
+        let huge_page = frame_alloc.allocate_huge_page_for(1).unwrap();
+        let other = frame_alloc.allocate_frame().unwrap();
+        frame_alloc.set_owner(other, 1).unwrap();
+        let untagged = frame_alloc.allocate_frame().unwrap();
+        assert_eq!(frame_alloc.owner(big_page), Some(2));
+        assert_eq!(frame_alloc.owner(untagged), None);
+        assert_eq!(
+            frame_alloc.set_owner(big_page + 1, 1),
+            Err(OwnerAllocError::Page(DeallocError::Misaligned))
+        );
+
+        let pages: Vec<_> = frame_alloc.allocations_of(1).collect();
+        assert_eq!(
+            pages,
+            [
+                (frame, TreeType::Tree4kb),
+                (other, TreeType::Tree4kb),
+                (huge_page, TreeType::Tree1gb)
+            ]
+        );
+        assert_eq!(frame_alloc.free_all(1), 3);
+        assert_eq!(frame_alloc.allocations_of(1).count(), 0);
+        assert_eq!(frame_alloc.free_all(1), 0);
+        assert_eq!(frame_alloc.owner(frame), None);
+        assert_eq!(frame_alloc.allocate_frame(), Some(frame));
+        frame_alloc.deallocate_frame(frame).unwrap();
+
+        assert_eq!(frame_alloc.free_all(2), 1);
+        frame_alloc.deallocate_frame(untagged).unwrap();
+        assert_eq!(frame_alloc.stat_free_memory(), (3, 0, 0));
+    }
+
+    #[test]
+    fn test_free_all_with_shared_pages_or_disabled() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB)
+            .with_owners()
+            .with_refcounts();
+        let shared = frame_alloc.allocate_big_page_for(1).unwrap();
+        let frame = frame_alloc.allocate_frame_for(1).unwrap();
+        assert_eq!(frame_alloc.get_frame(shared), Ok(2));
+
+        // the shared page only loses the reference of its owner
+        assert_eq!(frame_alloc.free_all(1), 2);
+        assert_eq!(frame_alloc.ref_count(shared), 1);
+        assert_eq!(frame_alloc.ref_count(frame), 0);
+        assert_eq!(frame_alloc.owner(shared), None);
+        assert_eq!(frame_alloc.free_all(1), 0);
+        assert_eq!(frame_alloc.put_frame(shared), Ok(0));
+        assert_eq!(frame_alloc.stat_free_memory(), (1, 0, 0));
+
+        // nothing is allocated without owner ids
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB);
+        assert_eq!(
+            frame_alloc.allocate_frame_for(1),
+            Err(OwnerAllocError::Disabled)
+        );
+        assert_eq!(
+            frame_alloc.allocate_big_page_for(1),
+            Err(OwnerAllocError::Disabled)
+        );
+        assert_eq!(
+            frame_alloc.allocate_huge_page_for(1),
+            Err(OwnerAllocError::Disabled)
+        );
+        assert_eq!(frame_alloc.stat_free_memory(), (1, 0, 0));
+    }
+
+    #[test]
+    fn test_owner_of_split_and_merged_pages() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB).with_owners();
+        let huge_page = frame_alloc.allocate_huge_page_for(7).unwrap();
+        frame_alloc.split_huge_page(huge_page).unwrap();
+        frame_alloc.split_big_page(huge_page + 512).unwrap();
+        assert_eq!(frame_alloc.owner(huge_page + 1024), Some(7));
+        assert_eq!(frame_alloc.owner(huge_page + 513), Some(7));
+        assert_eq!(frame_alloc.allocations_of(7).count(), 511 + 512);
+
+        frame_alloc.merge_to_big_page(huge_page + 512).unwrap();
+        frame_alloc.merge_to_huge_page(huge_page).unwrap();
+        assert_eq!(frame_alloc.allocations_of(7).count(), 1);
+        assert_eq!(frame_alloc.free_all(7), 1);
+        assert_eq!(frame_alloc.stat_free_memory(), (1, 0, 0));
+    }
+}
@@ -0,0 +1,375 @@
+//! Placement policies
+//!
+//! At each level of the trees, several blocks may be able to hold the page being allocated. The
+//! placement policy of an allocator chooses one of them, which decides how fast 2Mb and 1Gb
+//! blocks get broken by smaller allocations.
+
+#[cfg(feature = "alloc")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{BuddyAllocator, Level, TreeType};
+
+/**
+ * Choose the block receiving an allocation among the candidates of one 512 bits block
+ */
+pub trait PlacementPolicy {
+    /**
+     * Return the index (0..512) of one of the candidates
+     */
+    fn select(&mut self, candidates: &Candidates) -> usize;
+}
+
+/**
+ * Blocks able to hold the page being allocated, at one level of the trees
+ * `intact` blocks are entirely free, allocating in them breaks a larger page
+ * at level 0 no block is reported intact
+ */
+pub struct Candidates<'a> {
+    page: TreeType,
+    level: Level,
+    free: &'a [u64],
+    intact: &'a [u64],
+    frames: Option<Counters<'a>>,
+}
+
+/**
+ * Free frame counters of the candidate blocks, see `counters.rs` for their layout
+ */
+#[derive(Copy, Clone)]
+pub(crate) enum Counters<'a> {
+    Words(&'a [u64]),
+    #[cfg(feature = "alloc")]
+    Atomics(&'a [AtomicU64]),
+}
+
+impl Counters<'_> {
+    /**
+     * Return counter word `i`
+     */
+    #[inline(always)]
+    fn word(&self, i: usize) -> u64 {
+        match self {
+            Counters::Words(words) => words[i],
+            #[cfg(feature = "alloc")]
+            Counters::Atomics(words) => words[i].load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<'a> Candidates<'a> {
+    /**
+     * Gather the candidates of one 512 bits block, `free` and `intact` hold 8 words
+     */
+    pub(crate) fn new(
+        page: TreeType,
+        level: Level,
+        free: &'a [u64],
+        intact: &'a [u64],
+        frames: Option<Counters<'a>>,
+    ) -> Self {
+        Self {
+            page,
+            level,
+            free,
+            intact,
+            frames,
+        }
+    }
+
+    /**
+     * Return the size of the page being allocated
+     */
+    pub fn page(&self) -> TreeType {
+        self.page
+    }
+
+    /**
+     * Return the level of the candidate blocks
+     */
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /**
+     * Return true if block `idx` can hold the page
+     */
+    pub fn is_free(&self, idx: usize) -> bool {
+        self.free[idx / 64] & (1u64 << (idx % 64)) != 0
+    }
+
+    /**
+     * Return true if block `idx` is entirely free
+     */
+    pub fn is_intact(&self, idx: usize) -> bool {
+        self.intact[idx / 64] & (1u64 << (idx % 64)) != 0
+    }
+
+    /**
+     * Return the number of free 4Kb frames of block `idx`
+     * only known for 1Gb blocks (level 1), 2Mb blocks (level 2) and frames (level 3)
+     */
+    pub fn free_frames(&self, idx: usize) -> Option<usize> {
+        match self.level {
+            Level::Level1 => self.frames.map(|frames| frames.word(idx) as usize),
+            Level::Level2 => self
+                .frames
+                .map(|frames| ((frames.word(idx / 4) >> (16 * (idx % 4))) & 0xFFFF) as usize),
+            Level::Level3 => Some(self.is_free(idx) as usize),
+            Level::Level0 => None,
+        }
+    }
+
+    /**
+     * Return the number of candidates
+     */
+    pub fn count(&self) -> usize {
+        self.free
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /**
+     * Return the lowest candidate
+     */
+    pub fn first(&self) -> usize {
+        let i = self.free.iter().position(|&word| word != 0).unwrap();
+        <BuddyAllocator>::bsf(self.free[i]) + 64 * i
+    }
+
+    /**
+     * Return the highest candidate
+     */
+    pub fn last(&self) -> usize {
+        let i = self.free.iter().rposition(|&word| word != 0).unwrap();
+        <BuddyAllocator>::bsr(self.free[i]) + 64 * i
+    }
+
+    /**
+     * Iterate over the candidates in increasing order
+     */
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..512).filter(|&idx| self.is_free(idx))
+    }
+}
+
+/**
+ * Lowest block first, except 1Gb pages taken from the highest block so that they do not compete
+ * with smaller pages, this is the default policy
+ */
+#[derive(Copy, Clone, Default, Debug)]
+pub struct FirstFitLow;
+
+impl PlacementPolicy for FirstFitLow {
+    #[inline(always)]
+    fn select(&mut self, candidates: &Candidates) -> usize {
+        if candidates.page == TreeType::Tree1gb {
+            candidates.last()
+        } else {
+            candidates.first()
+        }
+    }
+}
+
+/**
+ * Mirror of `FirstFitLow`: highest block first, except 1Gb pages taken from the lowest block
+ */
+#[derive(Copy, Clone, Default, Debug)]
+pub struct FirstFitHigh;
+
+impl PlacementPolicy for FirstFitHigh {
+    fn select(&mut self, candidates: &Candidates) -> usize {
+        if candidates.page == TreeType::Tree1gb {
+            candidates.first()
+        } else {
+            candidates.last()
+        }
+    }
+}
+
+/**
+ * Partially used blocks first, the most occupied 1Gb and 2Mb blocks first, so that intact blocks
+ * are only broken when no partially used block is left
+ * 1Gb pages are placed as with `FirstFitLow`
+ */
+#[derive(Copy, Clone, Default, Debug)]
+pub struct BestFit;
+
+impl PlacementPolicy for BestFit {
+    fn select(&mut self, candidates: &Candidates) -> usize {
+        if candidates.page == TreeType::Tree1gb {
+            return candidates.last();
+        }
+        let mut partial = candidates.iter().filter(|&idx| !candidates.is_intact(idx));
+        match candidates.level {
+            Level::Level1 | Level::Level2 => partial
+                .min_by_key(|&idx| candidates.free_frames(idx))
+                .unwrap_or_else(|| candidates.first()),
+            _ => partial.next().unwrap_or_else(|| candidates.first()),
+        }
+    }
+}
+
+/**
+ * Uniformly random candidate, from a xorshift generator
+ */
+#[derive(Copy, Clone, Debug)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    /**
+     * Create a generator from a non-zero seed
+     */
+    pub fn new(seed: u64) -> Self {
+        assert!(seed != 0);
+        Self { state: seed }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+impl PlacementPolicy for Random {
+    fn select(&mut self, candidates: &Candidates) -> usize {
+        let nth = (self.next() % candidates.count() as u64) as usize;
+        candidates.iter().nth(nth).unwrap()
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Ask the policy for a block at a given level of the tree of `page`
+     * return None if no block of the 512 ones can hold the page
+     */
+    #[inline(always)]
+    pub(crate) fn select_block(
+        &mut self,
+        page: TreeType,
+        level: Level,
+        l1_idx: usize,
+        l2_idx: usize,
+    ) -> Option<usize> {
+        let start_idx = self.compute_first_block_index(l1_idx, l2_idx, level);
+        let free: [u64; 8] = self.tree(page)[start_idx..start_idx + 8]
+            .try_into()
+            .unwrap();
+        if free == [0; 8] {
+            return None;
+        }
+        Some(self.select_among(page, level, l1_idx, l2_idx, &free))
+    }
+
+    /**
+     * Ask the policy for one of the blocks set in `free`, 8 words covering the 512 blocks at a
+     * given level of the tree of `page` (e.g. only the blocks holding a free run)
+     */
+    #[inline(always)]
+    pub(crate) fn select_among(
+        &mut self,
+        page: TreeType,
+        level: Level,
+        l1_idx: usize,
+        l2_idx: usize,
+        free: &[u64],
+    ) -> usize {
+        let start_idx = self.compute_first_block_index(l1_idx, l2_idx, level);
+        // free counters, see `counters.rs` for their layout
+        let counters: &[u64] = &self.counters;
+        let frames = match level {
+            Level::Level1 => Some(Counters::Words(&counters[l1_idx..self.nb_gb])),
+            Level::Level2 => Some(Counters::Words(
+                &counters[self.nb_gb + 128 * l1_idx..][..128],
+            )),
+            _ => None,
+        };
+        let tree_2mb: &[u64] = &self.tree_2mb;
+        let tree_1gb: &[u64] = &self.tree_1gb;
+        let intact = match level {
+            Level::Level0 => &[0u64; 8][..],
+            Level::Level1 => &tree_1gb[start_idx..start_idx + 8],
+            Level::Level2 => &tree_2mb[start_idx..start_idx + 8],
+            Level::Level3 => free,
+        };
+        let candidates = Candidates::new(page, level, free, intact, frames);
+        let idx = self.policy.select(&candidates);
+        assert!(candidates.is_free(idx));
+        idx
+    }
+
+    /**
+     * Select a level 1 bit set, going through level 0
+     */
+    #[inline(always)]
+    pub(crate) fn select_level1(&mut self, tree_type: TreeType) -> Option<usize> {
+        let l0_idx = self.select_block(tree_type, Level::Level0, 0, 0)?;
+        let l1_idx_found = self.select_block(tree_type, Level::Level1, l0_idx << 9, 0);
+        assert!(l1_idx_found.is_some());
+        Some((l0_idx << 9) + l1_idx_found.unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_first_fit() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB);
+        assert_eq!(frame_alloc.allocate_frame(), Some(0));
+        assert_eq!(frame_alloc.allocate_big_page(), Some(512));
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(GB));
+
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB).with_policy(FirstFitHigh);
+        assert_eq!(frame_alloc.allocate_frame(), Some(2 * GB - 1));
+        assert_eq!(frame_alloc.allocate_big_page(), Some(2 * GB - 1024));
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(0));
+        assert_eq!(frame_alloc.allocate_huge_page(), None);
+    }
+
+    #[test]
+    fn test_best_fit_fills_most_occupied_block() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB).with_policy(BestFit);
+        frame_alloc.reserve_range(0, 1024).unwrap();
+        frame_alloc.deallocate_frame(3).unwrap();
+        frame_alloc.deallocate_frame(512).unwrap();
+        frame_alloc.deallocate_frame(513).unwrap();
+        frame_alloc.deallocate_frame(1000).unwrap();
+
+        // the 2Mb block 0 has a single free frame left, then block 1, then an intact block
+        assert_eq!(frame_alloc.allocate_frame(), Some(3));
+        assert_eq!(frame_alloc.allocate_frame(), Some(512));
+        assert_eq!(frame_alloc.allocate_frame(), Some(513));
+        assert_eq!(frame_alloc.allocate_frame(), Some(1000));
+        assert_eq!(frame_alloc.allocate_frame(), Some(1024));
+
+        // big pages go to the already broken 1Gb block
+        assert_eq!(frame_alloc.allocate_big_page(), Some(1536));
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(GB));
+    }
+
+    #[test]
+    fn test_random_policy() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB).with_policy(Random::new(42));
+        let mut frames = Vec::new();
+        for _ in 0..4096 {
+            frames.push(frame_alloc.allocate_frame().unwrap());
+        }
+        frames.sort();
+        frames.dedup();
+        assert_eq!(frames.len(), 4096);
+        assert!(frames.last().unwrap() >= &4096);
+
+        for frame in frames {
+            frame_alloc.deallocate_frame(frame).unwrap();
+        }
+        assert_eq!(frame_alloc.stat_free_memory(), (1, 0, 0));
+    }
+}
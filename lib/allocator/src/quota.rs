@@ -0,0 +1,327 @@
+//! Memory quotas of owners
+//!
+//! Pages allocated for an owner (see `owner.rs`) are charged to it, in 4Kb frames: a 2Mb page
+//! counts for 512 frames and a 1Gb page for 512 * 512 frames. An allocation or a change of owner
+//! that would take an owner above its hard limit fails, going above the soft limit is only
+//! reported so that the caller can reclaim memory. Allocations without explicit owner are charged
+//! to the current owner, see `set_current_owner`. The optional table holds three words per owner
+//! id: usage, soft limit and hard limit.
+
+use crate::error::OwnerAllocError;
+use crate::storage::TreeStorage;
+use crate::{BuddyAllocator, PlacementPolicy};
+
+const NB_OWNERS: usize = 1 << 16;
+
+impl BuddyAllocator {
+    /**
+     * Return the number of 64 bits words of the quotas of all owner ids
+     */
+    pub fn quotas_size() -> usize {
+        3 * NB_OWNERS
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Enable quotas, see `set_quota`, owners have no limit until one is set
+     * return an error if owner ids are not enabled
+     */
+    #[cfg(feature = "alloc")]
+    pub fn with_quotas(mut self) -> Result<Self, OwnerAllocError> {
+        self.check_owners()?;
+        self.quotas = Some(TreeStorage::boxed(<BuddyAllocator>::quotas_size()));
+        self.init_quotas();
+        Ok(self)
+    }
+
+    /**
+     * Same as `with_quotas` but quotas are placed in `storage`
+     * `storage` must hold at least `quotas_size()` words
+     */
+    pub fn with_quotas_in(mut self, storage: &'static mut [u64]) -> Result<Self, OwnerAllocError> {
+        self.check_owners()?;
+        let size = <BuddyAllocator>::quotas_size();
+        assert!(storage.len() >= size);
+        self.quotas = Some(TreeStorage::borrowed(&mut storage[..size]));
+        self.init_quotas();
+        Ok(self)
+    }
+
+    /**
+     * Set the soft and hard limits of `owner`, in 4Kb frames
+     * return an error if quotas are not enabled or if `owner` is 0, pages without owner are
+     * never charged
+     */
+    pub fn set_quota(
+        &mut self,
+        owner: u16,
+        soft_limit: usize,
+        hard_limit: usize,
+    ) -> Result<(), OwnerAllocError> {
+        let quota = self.quota_index(owner)?;
+        let quotas = self.quotas.as_mut().unwrap();
+        quotas[quota + 1] = soft_limit as u64;
+        quotas[quota + 2] = hard_limit as u64;
+        Ok(())
+    }
+
+    /**
+     * Return the number of 4Kb frames charged to `owner`
+     * return an error if quotas are not enabled or if `owner` is 0
+     */
+    pub fn quota_usage(&self, owner: u16) -> Result<usize, OwnerAllocError> {
+        let quota = self.quota_index(owner)?;
+        Ok(self.quotas.as_ref().unwrap()[quota] as usize)
+    }
+
+    /**
+     * Return true if `owner` uses more than its soft limit
+     * return an error if quotas are not enabled or if `owner` is 0
+     */
+    pub fn is_over_soft_limit(&self, owner: u16) -> Result<bool, OwnerAllocError> {
+        let quota = self.quota_index(owner)?;
+        let quotas = self.quotas.as_ref().unwrap();
+        Ok(quotas[quota] > quotas[quota + 1])
+    }
+
+    /**
+     * Return the index of the first word of the quota of `owner`
+     * return an error if quotas are not enabled or if `owner` is 0
+     */
+    fn quota_index(&self, owner: u16) -> Result<usize, OwnerAllocError> {
+        match self.quotas {
+            Some(_) if owner != 0 => Ok(3 * owner as usize),
+            _ => Err(OwnerAllocError::Disabled),
+        }
+    }
+
+    /**
+     * Return an error if charging `nb_frames` frames to `owner` takes it above its hard limit
+     */
+    pub(crate) fn check_quota(&self, owner: u16, nb_frames: usize) -> Result<(), OwnerAllocError> {
+        match &self.quotas {
+            Some(quotas) if owner != 0 => {
+                let usage = quotas[3 * owner as usize];
+                if usage + nb_frames as u64 > quotas[3 * owner as usize + 2] {
+                    return Err(OwnerAllocError::QuotaExceeded);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /**
+     * Charge (charged == true) or release `nb_frames` frames to `owner`
+     */
+    pub(crate) fn charge(&mut self, owner: u16, nb_frames: usize, charged: bool) {
+        if let Some(quotas) = self.quotas.as_mut() {
+            if owner != 0 {
+                if charged {
+                    quotas[3 * owner as usize] += nb_frames as u64;
+                } else {
+                    quotas[3 * owner as usize] -= nb_frames as u64;
+                }
+            }
+        }
+    }
+
+    /**
+     * Remove the limits of every owner and charge the pages they already own
+     */
+    fn init_quotas(&mut self) {
+        let quotas = self.quotas.as_mut().unwrap();
+        for quota in quotas.chunks_exact_mut(3) {
+            quota.copy_from_slice(&[0, u64::MAX, u64::MAX]);
+        }
+
+        let nb_words = self.owners.as_ref().unwrap().len();
+        for i in 0..nb_words {
+            if self.owners.as_ref().unwrap()[i] == 0 {
+                continue;
+            }
+            for frame_id in 4 * i..4 * i + 4 {
+                let owner = self.read_owner(frame_id);
+                if owner != 0 {
+                    let nb_frames = self.allocation_size(frame_id).unwrap().nb_frames();
+                    self.charge(owner, nb_frames, true);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::FRAME_SIZE;
+    use crate::error::DeallocError;
+    use crate::{TreeType, Zone};
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_hard_and_soft_limits() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB).with_owners();
+        let frame = frame_alloc.allocate_frame_for(1).unwrap();
+        let mut frame_alloc = frame_alloc.with_quotas().unwrap();
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), 1);
+        frame_alloc.deallocate_frame(frame).unwrap();
+
+        frame_alloc.set_quota(1, 512, 1024).unwrap();
+        let frame = frame_alloc.allocate_frame_for(1).unwrap();
+        let big_page = frame_alloc.allocate_big_page_for(1).unwrap();
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), 513);
+        assert!(frame_alloc.is_over_soft_limit(1).unwrap());
+        assert_eq!(
+            frame_alloc.allocate_big_page_for(1),
+            Err(OwnerAllocError::QuotaExceeded)
+        );
+        assert_eq!(
+            frame_alloc.allocate_huge_page_for(1),
+            Err(OwnerAllocError::QuotaExceeded)
+        );
+
+        // other owners are not limited
+        let huge_page = frame_alloc.allocate_huge_page_for(2).unwrap();
+        assert_eq!(frame_alloc.quota_usage(2).unwrap(), GB);
+        assert_eq!(
+            frame_alloc.allocate_huge_page_for(2),
+            Err(OwnerAllocError::OutOfMemory)
+        );
+
+        frame_alloc.deallocate_frame(frame).unwrap();
+        assert!(!frame_alloc.is_over_soft_limit(1).unwrap());
+        for _ in 0..512 {
+            frame_alloc.allocate_frame_for(1).unwrap();
+        }
+        assert_eq!(
+            frame_alloc.allocate_frame_for(1),
+            Err(OwnerAllocError::QuotaExceeded)
+        );
+        assert_eq!(frame_alloc.free_all(1), 513);
+        assert_eq!(frame_alloc.owner(big_page), None);
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), 0);
+
+        // usage follows the owner of a page, within the hard limit of its new owner
+        assert_eq!(
+            frame_alloc.set_owner(huge_page, 1),
+            Err(OwnerAllocError::QuotaExceeded)
+        );
+        frame_alloc.set_quota(1, 512, GB).unwrap();
+        frame_alloc.set_owner(huge_page, 1).unwrap();
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), GB);
+        assert_eq!(frame_alloc.quota_usage(2).unwrap(), 0);
+        frame_alloc.split_huge_page(huge_page).unwrap();
+        frame_alloc.set_owner(huge_page + 512, 2).unwrap();
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), GB - 512);
+        frame_alloc.merge_to_huge_page(huge_page).unwrap();
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), GB);
+        assert_eq!(frame_alloc.quota_usage(2).unwrap(), 0);
+        assert_eq!(
+            frame_alloc.allocations_of(1).collect::<Vec<_>>(),
+            [(huge_page, TreeType::Tree1gb)]
+        );
+        frame_alloc.deallocate_huge_page(huge_page).unwrap();
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_quotas_on_every_allocation() {
+        assert!(matches!(
+            BuddyAllocator::with_capacity(GB).with_quotas(),
+            Err(OwnerAllocError::Disabled)
+        ));
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB).with_owners();
+        assert_eq!(
+            frame_alloc.set_quota(1, 512, 513),
+            Err(OwnerAllocError::Disabled)
+        );
+        assert_eq!(frame_alloc.quota_usage(1), Err(OwnerAllocError::Disabled));
+        assert_eq!(
+            frame_alloc.is_over_soft_limit(1),
+            Err(OwnerAllocError::Disabled)
+        );
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB)
+            .with_owners()
+            .with_quotas()
+            .unwrap();
+        frame_alloc.set_quota(1, 512, 513).unwrap();
+        assert_eq!(frame_alloc.quota_usage(0), Err(OwnerAllocError::Disabled));
+
+        // pages allocated without explicit owner are charged to the current owner
+        frame_alloc.set_current_owner(1).unwrap();
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        assert_eq!(frame_alloc.owner(big_page), Some(1));
+        let frame = frame_alloc.allocate_frame_in(Zone::Dma).unwrap();
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), 513);
+        assert_eq!(frame_alloc.allocate_frame(), None);
+        assert_eq!(frame_alloc.allocate_frame_in(Zone::Dma32), None);
+        assert_eq!(frame_alloc.allocate_huge_page(), None);
+        assert_eq!(
+            frame_alloc.try_allocate_frame(),
+            Err(OwnerAllocError::QuotaExceeded)
+        );
+        assert_eq!(
+            frame_alloc.try_allocate_big_page_in(Zone::Normal),
+            Err(OwnerAllocError::QuotaExceeded)
+        );
+        assert_eq!(frame_alloc.free_pages(TreeType::Tree4kb), 2 * GB - 513);
+
+        frame_alloc.set_current_owner(0).unwrap();
+        let other = frame_alloc.allocate_frame().unwrap();
+        assert_eq!(frame_alloc.owner(other), None);
+        assert_eq!(
+            frame_alloc.try_allocate_huge_page_in(Zone::Dma),
+            Err(OwnerAllocError::OutOfMemory)
+        );
+
+        // giving a page to an owner respects its hard limit too
+        assert_eq!(
+            frame_alloc.set_owner(other, 1),
+            Err(OwnerAllocError::QuotaExceeded)
+        );
+        frame_alloc.set_owner(frame, 2).unwrap();
+        frame_alloc.set_owner(other, 1).unwrap();
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), 513);
+        assert_eq!(frame_alloc.quota_usage(2).unwrap(), 1);
+        assert_eq!(
+            frame_alloc.set_owner(other + 1, 1),
+            Err(OwnerAllocError::Page(DeallocError::NotAllocated))
+        );
+    }
+
+    #[test]
+    fn test_uncharged_allocations() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB)
+            .with_owners()
+            .with_quotas()
+            .unwrap();
+        frame_alloc.set_quota(1, 1, 1).unwrap();
+        frame_alloc.set_current_owner(1).unwrap();
+        let frame = frame_alloc.allocate_frame().unwrap();
+        assert_eq!(frame_alloc.allocate_frame(), None);
+
+        // runs, pages at a given index and reserved ranges are not charged
+        let run = frame_alloc
+            .allocate_contiguous(4, FRAME_SIZE, FRAME_SIZE)
+            .unwrap();
+        frame_alloc.allocate_big_page_at(GB).unwrap();
+        frame_alloc.reserve_range(GB + 512, 3).unwrap();
+        assert_eq!(frame_alloc.owner(run), None);
+        assert_eq!(frame_alloc.owner(GB), None);
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), 1);
+
+        frame_alloc
+            .deallocate_contiguous(run, 4, FRAME_SIZE)
+            .unwrap();
+        frame_alloc.deallocate_big_page(GB).unwrap();
+        for frame_id in GB + 512..GB + 515 {
+            frame_alloc.deallocate_frame(frame_id).unwrap();
+        }
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), 1);
+        frame_alloc.deallocate_frame(frame).unwrap();
+        assert_eq!(frame_alloc.quota_usage(1).unwrap(), 0);
+    }
+}
@@ -0,0 +1,241 @@
+//! Iterators over free and allocated ranges
+//!
+//! The managed frames are walked in increasing order as runs of frames in the same state: free,
+//! allocated as 4Kb, 2Mb or 1Gb pages (consecutive pages of the same size form one run), or in a
+//! hole of the memory map. Summary bits are used to step over a whole 1Gb or 2Mb block when it is
+//! fully free, fully used or a single page, only 2Mb blocks holding both free and allocated 4Kb
+//! frames or a hole are walked frame by frame. Offline 1Gb blocks are neither free nor
+//! allocated, they end the current run.
+
+use crate::{BuddyAllocator, Level, PlacementPolicy, TreeType};
+
+/**
+ * State of the frames of a range
+ * Hole: frames missing from the memory map (firmware, MMIO), never handed out nor freed
+ */
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RangeKind {
+    Free,
+    Allocated(TreeType),
+    Hole,
+}
+
+/**
+ * Iterator over the runs of frames in the same state, see `BuddyAllocator::ranges`
+ */
+struct Ranges<'a, P> {
+    allocator: &'a BuddyAllocator<P>,
+    frame_id: usize,
+    run: Option<(usize, usize, RangeKind)>,
+}
+
+impl<P: PlacementPolicy> Iterator for Ranges<'_, P> {
+    type Item = (usize, usize, RangeKind);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.frame_id < self.allocator.nb_pages {
+            let (len, kind) = self.allocator.block_state(self.frame_id);
+            let start = self.frame_id;
+            self.frame_id += len;
+
+            match (self.run.as_mut(), kind) {
+                (Some(run), Some(kind)) if run.2 == kind => run.1 += len,
+                (_, kind) => {
+                    let run = kind.map(|kind| (start, len, kind));
+                    if let Some(done) = core::mem::replace(&mut self.run, run) {
+                        return Some(done);
+                    }
+                }
+            }
+        }
+        self.run.take()
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Iterate over the runs of frames in the same state in increasing order, as
+     * (first frame, number of frames, state)
+     */
+    pub fn ranges(&self) -> impl Iterator<Item = (usize, usize, RangeKind)> + '_ {
+        Ranges {
+            allocator: self,
+            frame_id: 0,
+            run: None,
+        }
+    }
+
+    /**
+     * Iterate over the runs of free frames in increasing order, as
+     * (first frame, number of frames, `RangeKind::Free`)
+     */
+    pub fn free_ranges(&self) -> impl Iterator<Item = (usize, usize, RangeKind)> + '_ {
+        self.ranges()
+            .filter(|&(_, _, kind)| kind == RangeKind::Free)
+    }
+
+    /**
+     * Iterate over the runs of pages of size `size` in increasing order, as
+     * (first frame, number of frames, `RangeKind::Allocated(size)`)
+     */
+    pub fn allocated_ranges(
+        &self,
+        size: TreeType,
+    ) -> impl Iterator<Item = (usize, usize, RangeKind)> + '_ {
+        self.ranges()
+            .filter(move |&(_, _, kind)| kind == RangeKind::Allocated(size))
+    }
+
+    /**
+     * Return the number of frames starting at `frame_id` that are in the same state, along with
+     * this state (None for an offline block)
+     * a whole 1Gb or 2Mb block is returned when possible
+     */
+    fn block_state(&self, frame_id: usize) -> (usize, Option<RangeKind>) {
+        if frame_id.is_multiple_of(512 * 512) {
+            let len = (512 * 512).min(self.nb_pages - frame_id);
+            if self.is_offline(frame_id) {
+                return (len, None);
+            }
+            if self.get_bit_level_index(TreeType::Tree1gb, Level::Level1, frame_id) {
+                return (len, Some(RangeKind::Free));
+            }
+            if self.allocation_size(frame_id) == Some(TreeType::Tree1gb) {
+                return (len, Some(RangeKind::Allocated(TreeType::Tree1gb)));
+            }
+        }
+
+        if frame_id.is_multiple_of(512) {
+            let len = 512.min(self.nb_pages - frame_id);
+            if self.get_bit_level_index(TreeType::Tree2mb, Level::Level2, frame_id) {
+                return (len, Some(RangeKind::Free));
+            }
+            // a big page or a block whose 4Kb frames are all allocated
+            if !self.get_bit_level_index(TreeType::Tree4kb, Level::Level2, frame_id)
+                && !self.has_holes_in_2mb(frame_id)
+            {
+                let size = self.allocation_size(frame_id).unwrap();
+                return (len, Some(RangeKind::Allocated(size)));
+            }
+        }
+
+        let kind = if self.get_bit_level_index(TreeType::Tree4kb, Level::Level3, frame_id) {
+            RangeKind::Free
+        } else if self.is_hole(frame_id) {
+            RangeKind::Hole
+        } else {
+            RangeKind::Allocated(TreeType::Tree4kb)
+        };
+        (1, Some(kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryKind, MemoryRegion};
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_ranges() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(3 * GB + 1000);
+        assert_eq!(
+            frame_alloc.ranges().collect::<Vec<_>>(),
+            [(0, 3 * GB + 1000, RangeKind::Free)]
+        );
+
+        let frame = frame_alloc.allocate_frame().unwrap();
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        frame_alloc.allocate_big_page_at(big_page + 512).unwrap();
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        frame_alloc.reserve_range(1600, 1000).unwrap();
+        frame_alloc.offline_region(1).unwrap();
+        assert_eq!(
+            frame_alloc.ranges().collect::<Vec<_>>(),
+            [
+                (frame, 1, RangeKind::Allocated(TreeType::Tree4kb)),
+                (1, 511, RangeKind::Free),
+                (big_page, 1024, RangeKind::Allocated(TreeType::Tree2mb)),
+                (1536, 64, RangeKind::Free),
+                (1600, 1000, RangeKind::Allocated(TreeType::Tree4kb)),
+                (2600, GB - 2600, RangeKind::Free),
+                (huge_page, GB, RangeKind::Allocated(TreeType::Tree1gb)),
+                (3 * GB, 1000, RangeKind::Free),
+            ]
+        );
+
+        let free: usize = frame_alloc.free_ranges().map(|(_, len, _)| len).sum();
+        assert_eq!(free, frame_alloc.free_pages(TreeType::Tree4kb));
+        assert_eq!(
+            frame_alloc
+                .allocated_ranges(TreeType::Tree2mb)
+                .collect::<Vec<_>>(),
+            [(big_page, 1024, RangeKind::Allocated(TreeType::Tree2mb))]
+        );
+
+        // a split huge page is made of big pages
+        frame_alloc.split_huge_page(huge_page).unwrap();
+        frame_alloc.deallocate_big_page(huge_page + 512).unwrap();
+        assert_eq!(
+            frame_alloc
+                .allocated_ranges(TreeType::Tree2mb)
+                .skip(1)
+                .collect::<Vec<_>>(),
+            [
+                (huge_page, 512, RangeKind::Allocated(TreeType::Tree2mb)),
+                (
+                    huge_page + 1024,
+                    GB - 1024,
+                    RangeKind::Allocated(TreeType::Tree2mb)
+                ),
+            ]
+        );
+        assert_eq!(frame_alloc.allocated_ranges(TreeType::Tree1gb).count(), 0);
+    }
+
+    #[test]
+    fn test_ranges_with_holes() {
+        let regions = [
+            MemoryRegion {
+                start: 0,
+                length: 0x9F000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x9F000,
+                length: 0x61000,
+                kind: MemoryKind::Reserved,
+            },
+            MemoryRegion {
+                start: 0x100000,
+                length: 0x100000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x200000,
+                length: 0x200000,
+                kind: MemoryKind::Mmio,
+            },
+            MemoryRegion {
+                start: 0x400000,
+                length: 0x200000,
+                kind: MemoryKind::Usable,
+            },
+        ];
+        let mut frame_alloc = BuddyAllocator::from_memory_map(&regions);
+        frame_alloc.allocate_frame_at(0x9E).unwrap();
+        frame_alloc.reserve_range(0x100, 0x100).unwrap();
+        assert_eq!(
+            frame_alloc.ranges().collect::<Vec<_>>(),
+            [
+                (0, 0x9E, RangeKind::Free),
+                (0x9E, 1, RangeKind::Allocated(TreeType::Tree4kb)),
+                (0x9F, 0x61, RangeKind::Hole),
+                (0x100, 0x100, RangeKind::Allocated(TreeType::Tree4kb)),
+                (0x200, 0x200, RangeKind::Hole),
+                (0x400, 0x200, RangeKind::Free),
+            ]
+        );
+    }
+}
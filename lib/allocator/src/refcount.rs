@@ -0,0 +1,291 @@
+//! Reference counts of shared pages
+//!
+//! Pages shared between several users (e.g. copy-on-write guest memory) are only freed when their
+//! last reference is dropped, `deallocate_*` refuse a page with other references. The optional
+//! table holds one 16 bits counter per 4Kb frame, four per 64 bits word, big and huge pages use
+//! the counter of their first frame. A counter holds the number of references beyond the first
+//! one, so that an allocation starts with one reference.
+//! A shared page cannot be split or merged, counters of frames inside a page are thus always 0.
+
+use crate::error::{DeallocError, RefCountError};
+use crate::storage::TreeStorage;
+use crate::{BuddyAllocator, PlacementPolicy, TreeType};
+
+impl BuddyAllocator {
+    /**
+     * Return the number of 64 bits words of the reference counts of `num_frames` frames
+     */
+    pub fn refcounts_size(num_frames: usize) -> usize {
+        num_frames.div_ceil(4)
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Enable reference counts, see `get_frame` and `put_frame`
+     */
+    #[cfg(feature = "alloc")]
+    pub fn with_refcounts(mut self) -> Self {
+        let size = <BuddyAllocator>::refcounts_size(self.nb_pages);
+        self.refcounts = Some(TreeStorage::boxed(size));
+        self
+    }
+
+    /**
+     * Same as `with_refcounts` but counts are placed in `storage`
+     * `storage` must hold at least `refcounts_size(capacity())` words
+     */
+    pub fn with_refcounts_in(mut self, storage: &'static mut [u64]) -> Self {
+        let size = <BuddyAllocator>::refcounts_size(self.nb_pages);
+        assert!(storage.len() >= size);
+        self.refcounts = Some(TreeStorage::borrowed(&mut storage[..size]));
+        self
+    }
+
+    /**
+     * Take a reference on the page starting at frame `frame_id`, whatever its size
+     * return the number of references of the page, or an error if it is not allocated, if
+     * reference counts are not enabled or if the page already has 65536 references
+     */
+    pub fn get_frame(&mut self, frame_id: usize) -> Result<usize, RefCountError> {
+        if self.refcounts.is_none() {
+            return Err(RefCountError::Disabled);
+        }
+        self.allocated_page(frame_id)?;
+
+        let extra_refs = self.extra_refs(frame_id) + 1;
+        if extra_refs > 0xFFFF {
+            return Err(RefCountError::Overflow);
+        }
+        self.set_extra_refs(frame_id, extra_refs);
+        Ok(extra_refs + 1)
+    }
+
+    /**
+     * Drop a reference on the page starting at frame `frame_id`, the page is deallocated when its
+     * last reference is dropped
+     * return the number of references left, or an error if the page is not allocated
+     */
+    pub fn put_frame(&mut self, frame_id: usize) -> Result<usize, DeallocError> {
+        let size = self.allocated_page(frame_id)?;
+
+        let extra_refs = self.extra_refs(frame_id);
+        if extra_refs > 0 {
+            self.set_extra_refs(frame_id, extra_refs - 1);
+            return Ok(extra_refs);
+        }
+        match size {
+            TreeType::Tree4kb => self.deallocate_frame(frame_id)?,
+            TreeType::Tree2mb => self.deallocate_big_page(frame_id)?,
+            TreeType::Tree1gb => self.deallocate_huge_page(frame_id)?,
+        }
+        Ok(0)
+    }
+
+    /**
+     * Return the number of references of the page starting at frame `frame_id`, 0 if it is not
+     * allocated
+     */
+    pub fn ref_count(&self, frame_id: usize) -> usize {
+        match self.allocated_page(frame_id) {
+            Ok(_) => self.extra_refs(frame_id) + 1,
+            Err(_) => 0,
+        }
+    }
+
+    /**
+     * Return true if the page starting at frame `frame_id` has more than one reference
+     */
+    pub(crate) fn is_shared(&self, frame_id: usize) -> bool {
+        self.extra_refs(frame_id) > 0
+    }
+
+    /**
+     * Return an error if the page starting at frame `frame_id` has more than one reference
+     * `deallocate_*` only free a page holding its last reference, see `put_frame`
+     */
+    pub(crate) fn check_unshared(&self, frame_id: usize) -> Result<(), DeallocError> {
+        if self.is_shared(frame_id) {
+            return Err(DeallocError::Shared);
+        }
+        Ok(())
+    }
+
+    /**
+     * Return the size of the page starting at frame `frame_id`
+     * return an error if no allocated page starts at this frame
+     */
+    pub(crate) fn allocated_page(&self, frame_id: usize) -> Result<TreeType, DeallocError> {
+        if frame_id >= self.nb_pages || self.is_offline(frame_id) || self.is_hole(frame_id) {
+            return Err(DeallocError::OutOfRange);
+        }
+        let size = self
+            .allocation_size(frame_id)
+            .ok_or(DeallocError::NotAllocated)?;
+        if !frame_id.is_multiple_of(size.nb_frames()) {
+            return Err(DeallocError::Misaligned);
+        }
+        Ok(size)
+    }
+
+    /**
+     * Return the number of references beyond the first one of the page starting at `frame_id`
+     */
+    fn extra_refs(&self, frame_id: usize) -> usize {
+        self.refcounts.as_ref().map_or(0, |refcounts| {
+            ((refcounts[frame_id / 4] >> (16 * (frame_id % 4))) & 0xFFFF) as usize
+        })
+    }
+
+    /**
+     * Set the number of references beyond the first one of the page starting at `frame_id`
+     */
+    fn set_extra_refs(&mut self, frame_id: usize, extra_refs: usize) {
+        let refcounts = self.refcounts.as_mut().unwrap();
+        let shift = 16 * (frame_id % 4);
+        refcounts[frame_id / 4] &= !(0xFFFF << shift);
+        refcounts[frame_id / 4] |= (extra_refs as u64) << shift;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::BIG_PAGE_SIZE;
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_shared_pages() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB).with_refcounts();
+        let frame = frame_alloc.allocate_frame().unwrap();
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        assert_eq!(frame_alloc.ref_count(frame), 1);
+        assert_eq!(frame_alloc.get_frame(frame), Ok(2));
+        assert_eq!(frame_alloc.get_frame(frame), Ok(3));
+        assert_eq!(frame_alloc.get_frame(big_page), Ok(2));
+        assert_eq!(frame_alloc.get_frame(huge_page), Ok(2));
+        assert_eq!(
+            frame_alloc.get_frame(big_page + 1),
+            Err(RefCountError::Page(DeallocError::Misaligned))
+        );
+        assert_eq!(
+            frame_alloc.get_frame(1),
+            Err(RefCountError::Page(DeallocError::NotAllocated))
+        );
+
+        // pages stay allocated until their last reference is dropped
+        assert_eq!(frame_alloc.put_frame(frame), Ok(2));
+        assert_eq!(frame_alloc.put_frame(frame), Ok(1));
+        assert_eq!(frame_alloc.allocate_frame(), Some(1));
+        assert_eq!(frame_alloc.put_frame(frame), Ok(0));
+        assert_eq!(frame_alloc.ref_count(frame), 0);
+        assert_eq!(
+            frame_alloc.put_frame(frame),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(frame_alloc.put_frame(big_page), Ok(1));
+        assert_eq!(frame_alloc.put_frame(big_page), Ok(0));
+        assert_eq!(frame_alloc.put_frame(huge_page), Ok(1));
+        assert_eq!(frame_alloc.put_frame(huge_page), Ok(0));
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(huge_page));
+
+        // a page with references left cannot be deallocated
+        assert_eq!(frame_alloc.get_frame(1), Ok(2));
+        assert_eq!(frame_alloc.deallocate_frame(1), Err(DeallocError::Shared));
+        assert_eq!(frame_alloc.ref_count(1), 2);
+        assert_eq!(frame_alloc.put_frame(1), Ok(1));
+        frame_alloc.deallocate_frame(1).unwrap();
+        assert_eq!(frame_alloc.allocate_frame(), Some(0));
+        assert_eq!(frame_alloc.allocate_frame(), Some(1));
+        assert_eq!(frame_alloc.ref_count(1), 1);
+        assert_eq!(frame_alloc.put_frame(1), Ok(0));
+
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        assert_eq!(frame_alloc.get_frame(big_page), Ok(2));
+        assert_eq!(
+            frame_alloc.deallocate_big_page(big_page),
+            Err(DeallocError::Shared)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_contiguous(big_page, 1, BIG_PAGE_SIZE),
+            Err(DeallocError::Shared)
+        );
+        assert_eq!(frame_alloc.get_frame(huge_page), Ok(2));
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(huge_page),
+            Err(DeallocError::Shared)
+        );
+        assert_eq!(frame_alloc.put_frame(big_page), Ok(1));
+        assert_eq!(frame_alloc.put_frame(big_page), Ok(0));
+    }
+
+    #[test]
+    fn test_refcounts_in_storage_or_disabled() {
+        let storage = Box::leak(vec![!0u64; BuddyAllocator::refcounts_size(GB)].into_boxed_slice());
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB).with_refcounts_in(storage);
+        let frame = frame_alloc.allocate_frame().unwrap();
+        assert_eq!(frame_alloc.get_frame(frame), Ok(2));
+        assert_eq!(frame_alloc.put_frame(frame), Ok(1));
+
+        for _ in 2..=0x10000 {
+            frame_alloc.get_frame(frame).unwrap();
+        }
+        assert_eq!(frame_alloc.ref_count(frame), 0x10000);
+        assert_eq!(frame_alloc.get_frame(frame), Err(RefCountError::Overflow));
+
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB);
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        assert_eq!(
+            frame_alloc.get_frame(big_page),
+            Err(RefCountError::Disabled)
+        );
+        assert_eq!(frame_alloc.ref_count(big_page), 1);
+        assert_eq!(frame_alloc.put_frame(big_page), Ok(0));
+        assert_eq!(frame_alloc.stat_free_memory(), (1, 0, 0));
+    }
+
+    #[test]
+    fn test_shared_pages_are_not_split_or_merged() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB).with_refcounts();
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        assert_eq!(frame_alloc.get_frame(huge_page), Ok(2));
+        assert_eq!(
+            frame_alloc.split_huge_page(huge_page),
+            Err(DeallocError::Shared)
+        );
+        assert_eq!(frame_alloc.put_frame(huge_page), Ok(1));
+        frame_alloc.split_huge_page(huge_page).unwrap();
+
+        let big_page = huge_page + 512;
+        assert_eq!(frame_alloc.get_frame(big_page), Ok(2));
+        assert_eq!(
+            frame_alloc.split_big_page(big_page),
+            Err(DeallocError::Shared)
+        );
+        assert_eq!(
+            frame_alloc.merge_to_huge_page(huge_page),
+            Err(DeallocError::Shared)
+        );
+        assert_eq!(frame_alloc.put_frame(big_page), Ok(1));
+        frame_alloc.split_big_page(big_page).unwrap();
+
+        // a shared frame keeps its block split
+        assert_eq!(frame_alloc.get_frame(big_page + 5), Ok(2));
+        assert_eq!(
+            frame_alloc.merge_to_big_page(big_page),
+            Err(DeallocError::Shared)
+        );
+        assert_eq!(frame_alloc.put_frame(big_page + 5), Ok(1));
+        frame_alloc.merge_to_big_page(big_page).unwrap();
+        frame_alloc.merge_to_huge_page(huge_page).unwrap();
+
+        // frames of the merged page start again with one reference
+        frame_alloc.deallocate_huge_page(huge_page).unwrap();
+        for frame_id in 0..1024 {
+            assert_eq!(frame_alloc.allocate_frame(), Some(frame_id));
+            assert_eq!(frame_alloc.ref_count(frame_id), 1);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Allocation of pages at a given index and reservation of frame ranges (kernel image,
+//! firmware tables, fixed MMIO backing)
+//!
+//! These pages belong to no owner: they are neither tagged with the current owner nor charged
+//! to its quota, and freeing them leaves every quota unchanged.
+
+use crate::error::AllocError;
+use crate::{BuddyAllocator, Level, PlacementPolicy, TreeType};
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Allocate the 4Kb frame `frame_id`, it has no owner and is not charged to the current owner
+     * return an error and do nothing if the frame is not free
+     */
+    pub fn allocate_frame_at(&mut self, frame_id: usize) -> Result<(), AllocError> {
+        if frame_id >= self.nb_pages || self.is_offline(frame_id) {
+            return Err(AllocError::OutOfRange);
+        }
+        if self.allocation_size(frame_id).is_some() {
+            return Err(AllocError::Overlap);
+        }
+
+        let (l1_idx, l2_idx, l3_idx) = Self::split_index(frame_id);
+        self.mark_frame_allocated(l1_idx, l2_idx, l3_idx);
+        Ok(())
+    }
+
+    /**
+     * Allocate the 2Mb page starting at frame `frame_id`, not charged to the current owner
+     * return an error and do nothing if one of its frames is not free
+     */
+    pub fn allocate_big_page_at(&mut self, frame_id: usize) -> Result<(), AllocError> {
+        if !frame_id.is_multiple_of(512) {
+            return Err(AllocError::Misaligned);
+        }
+        if frame_id + 512 > self.nb_pages || self.is_offline(frame_id) {
+            return Err(AllocError::OutOfRange);
+        }
+        // level 2 of the 2Mb tree is left untouched inside an allocated 1Gb page
+        if self.allocation_size(frame_id).is_some()
+            || !self.get_bit_level_index(TreeType::Tree2mb, Level::Level2, frame_id)
+        {
+            return Err(AllocError::Overlap);
+        }
+
+        let (l1_idx, l2_idx, _) = Self::split_index(frame_id);
+        self.mark_big_page_allocated(l1_idx, l2_idx);
+        Ok(())
+    }
+
+    /**
+     * Allocate the 1Gb page starting at frame `frame_id`, not charged to the current owner
+     * return an error and do nothing if one of its frames is not free
+     */
+    pub fn allocate_huge_page_at(&mut self, frame_id: usize) -> Result<(), AllocError> {
+        if !frame_id.is_multiple_of(512 * 512) {
+            return Err(AllocError::Misaligned);
+        }
+        if frame_id + 512 * 512 > self.nb_pages || self.is_offline(frame_id) {
+            return Err(AllocError::OutOfRange);
+        }
+        if !self.get_bit_level_index(TreeType::Tree1gb, Level::Level1, frame_id) {
+            return Err(AllocError::Overlap);
+        }
+
+        let (l1_idx, _, _) = Self::split_index(frame_id);
+        self.mark_huge_page_allocated(l1_idx);
+        Ok(())
+    }
+
+    /**
+     * Reserve the `len` frames starting at frame `start`, they are allocated as 4Kb frames and
+     * can be released with `deallocate_frame`, unlike the holes of a firmware memory map
+     * the frames have no owner and are not charged to the current owner
+     * return an error and do nothing if one of the frames is not free or is offline
+     */
+    pub fn reserve_range(&mut self, start: usize, len: usize) -> Result<(), AllocError> {
+        let end = start.checked_add(len).ok_or(AllocError::OutOfRange)?;
+        if end > self.nb_pages
+            || (start >> 18..end.div_ceil(512 * 512))
+                .any(|gb_index| !self.is_region_online(gb_index))
+        {
+            return Err(AllocError::OutOfRange);
+        }
+        if (start..end).any(|frame_id| self.allocation_size(frame_id).is_some()) {
+            return Err(AllocError::Overlap);
+        }
+
+        for frame_id in start..end {
+            let (l1_idx, l2_idx, l3_idx) = Self::split_index(frame_id);
+            self.mark_frame_allocated(l1_idx, l2_idx, l3_idx);
+        }
+        Ok(())
+    }
+
+    /**
+     * Split a frame index into its level 1, level 2 and level 3 block indexes
+     */
+    pub(crate) fn split_index(frame_id: usize) -> (usize, usize, usize) {
+        (frame_id >> 18, (frame_id >> 9) & 0x1FF, frame_id & 0x1FF)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_at() {
+        let mut frame_alloc = Box::new(BuddyAllocator::with_capacity(3 * 512 * 512));
+        frame_alloc.allocate_frame_at(5).unwrap();
+        assert_eq!(frame_alloc.allocate_frame_at(5), Err(AllocError::Overlap));
+        assert_eq!(
+            frame_alloc.allocate_big_page_at(0),
+            Err(AllocError::Overlap)
+        );
+        assert_eq!(
+            frame_alloc.allocate_huge_page_at(0),
+            Err(AllocError::Overlap)
+        );
+        assert_eq!(
+            frame_alloc.allocate_big_page_at(513),
+            Err(AllocError::Misaligned)
+        );
+        assert_eq!(
+            frame_alloc.allocate_huge_page_at(512),
+            Err(AllocError::Misaligned)
+        );
+        assert_eq!(
+            frame_alloc.allocate_frame_at(3 * 512 * 512),
+            Err(AllocError::OutOfRange)
+        );
+
+        frame_alloc.allocate_big_page_at(1024).unwrap();
+        assert_eq!(
+            frame_alloc.allocate_frame_at(1030),
+            Err(AllocError::Overlap)
+        );
+        frame_alloc.allocate_huge_page_at(2 * 512 * 512).unwrap();
+        assert_eq!(
+            frame_alloc.allocate_big_page_at(2 * 512 * 512 + 512),
+            Err(AllocError::Overlap)
+        );
+        frame_alloc.check_integrity();
+
+        // the lowest free frame and the highest free 1Gb block are skipped
+        assert_eq!(frame_alloc.allocate_frame(), Some(0));
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(512 * 512));
+        assert!(frame_alloc.allocate_huge_page().is_none());
+
+        frame_alloc.deallocate_frame(5).unwrap();
+        frame_alloc.deallocate_big_page(1024).unwrap();
+        frame_alloc.deallocate_huge_page(2 * 512 * 512).unwrap();
+        frame_alloc.check_integrity();
+    }
+
+    #[test]
+    fn test_reserve_range() {
+        let mut frame_alloc = Box::new(BuddyAllocator::with_capacity(512 * 512));
+        frame_alloc.reserve_range(100, 1000).unwrap();
+        frame_alloc.check_integrity();
+        assert_eq!(
+            frame_alloc.reserve_range(1050, 100),
+            Err(AllocError::Overlap)
+        );
+        assert_eq!(
+            frame_alloc.reserve_range(512 * 512 - 10, 11),
+            Err(AllocError::OutOfRange)
+        );
+        assert_eq!(
+            frame_alloc.reserve_range(10, usize::MAX),
+            Err(AllocError::OutOfRange)
+        );
+        // a failed reservation leaves frames free
+        assert_eq!(frame_alloc.allocation_size(1100), None);
+        frame_alloc.reserve_range(1100, 0).unwrap();
+
+        // 2Mb blocks 0, 1 and 2 are broken by the reservation
+        assert_eq!(frame_alloc.allocate_big_page(), Some(3 * 512));
+        assert!(frame_alloc.allocate_huge_page().is_none());
+        for frame_id in 100..1100 {
+            frame_alloc.deallocate_frame(frame_id).unwrap();
+        }
+        frame_alloc.check_integrity();
+        assert_eq!(frame_alloc.allocate_big_page(), Some(0));
+    }
+}
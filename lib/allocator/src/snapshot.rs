@@ -0,0 +1,327 @@
+//! Binary snapshot of the allocator state
+//!
+//! A snapshot keeps the allocator across a kexec or a live update, or is attached to a bug
+//! report. It is a sequence of little endian 64 bits words:
+//! |magic|version|nb_pages|base|tree_4kb len|tree_2mb len|tree_1gb len|offline len|holes len|
+//! |tree_4kb|tree_2mb|tree_1gb|offline|holes|checksum|
+//! the checksum is the FNV-1a hash of every byte before it. Free counters are not stored, they
+//! are computed again from the trees on restore. Reference counts and owner ids are not
+//! stored either.
+
+#[cfg(feature = "alloc")]
+use alloc::{vec, vec::Vec};
+
+use crate::addr::HUGE_PAGE_SIZE;
+use crate::error::SnapshotError;
+use crate::storage::TreeStorage;
+use crate::{BuddyAllocator, PhysAddr, PlacementPolicy, MAX_PAGES};
+
+const MAGIC: u64 = u64::from_le_bytes(*b"BUDDYSNP");
+const VERSION: u64 = 2;
+const HEADER_WORDS: usize = 9;
+
+impl BuddyAllocator {
+    /**
+     * Create an allocator from a snapshot taken with `snapshot` or `write_snapshot`
+     * return an error if the snapshot is malformed or if its trees fail the integrity check
+     */
+    #[cfg(feature = "alloc")]
+    pub fn restore(data: &[u8]) -> Result<Self, SnapshotError> {
+        let (tree_4kb, tree_2mb, tree_1gb, counters, offline, holes) =
+            Self::boxed_trees(Self::snapshot_capacity(data)?);
+        Self::restore_trees(data, tree_4kb, tree_2mb, tree_1gb, counters, offline, holes)
+    }
+
+    /**
+     * Same as `restore` but trees are placed in `storage`
+     * `storage` must hold at least `storage_size(snapshot_capacity(data))` words
+     */
+    pub fn restore_in(data: &[u8], storage: &'static mut [u64]) -> Result<Self, SnapshotError> {
+        let (tree_4kb, tree_2mb, tree_1gb, counters, offline, holes) =
+            Self::borrowed_trees(Self::snapshot_capacity(data)?, storage);
+        Self::restore_trees(data, tree_4kb, tree_2mb, tree_1gb, counters, offline, holes)
+    }
+
+    /**
+     * Return the number of 4Kb frames managed by the allocator of a snapshot
+     * return an error if the header, the length or the checksum of the snapshot is wrong
+     */
+    pub fn snapshot_capacity(data: &[u8]) -> Result<usize, SnapshotError> {
+        if data.len() < 8 * (HEADER_WORDS + 1) {
+            return Err(SnapshotError::Truncated);
+        }
+        if read_word(data, 0) != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        if read_word(data, 1) != VERSION {
+            return Err(SnapshotError::UnsupportedVersion);
+        }
+
+        let nb_pages = read_word(data, 2);
+        if nb_pages == 0 || nb_pages > MAX_PAGES as u64 {
+            return Err(SnapshotError::BadGeometry);
+        }
+        if !read_word(data, 3).is_multiple_of(HUGE_PAGE_SIZE) {
+            return Err(SnapshotError::BadGeometry);
+        }
+        let nb_pages = nb_pages as usize;
+        let sizes = Self::snapshot_sizes(nb_pages);
+        if (0..5).any(|i| read_word(data, 4 + i) != sizes[i] as u64) {
+            return Err(SnapshotError::BadGeometry);
+        }
+
+        let len = 8 * (HEADER_WORDS + sizes.iter().sum::<usize>() + 1);
+        if data.len() != len {
+            return Err(SnapshotError::Truncated);
+        }
+        if checksum(&data[..len - 8]) != read_word(data, len / 8 - 1) {
+            return Err(SnapshotError::BadChecksum);
+        }
+        Ok(nb_pages)
+    }
+
+    /**
+     * Fill the trees from a snapshot checked by `snapshot_capacity`
+     */
+    fn restore_trees(
+        data: &[u8],
+        tree_4kb: TreeStorage,
+        tree_2mb: TreeStorage,
+        tree_1gb: TreeStorage,
+        counters: TreeStorage,
+        offline: TreeStorage,
+        holes: TreeStorage,
+    ) -> Result<Self, SnapshotError> {
+        let num_frames = read_word(data, 2) as usize;
+        let mut allocator = Self::with_empty_trees(
+            num_frames, tree_4kb, tree_2mb, tree_1gb, counters, offline, holes,
+        );
+        allocator.base = PhysAddr::new(read_word(data, 3));
+
+        let mut words = (HEADER_WORDS..).map(|i| read_word(data, i));
+        for tree in [
+            &mut allocator.tree_4kb,
+            &mut allocator.tree_2mb,
+            &mut allocator.tree_1gb,
+            &mut allocator.offline,
+            &mut allocator.holes,
+        ] {
+            tree.iter_mut()
+                .zip(&mut words)
+                .for_each(|(word, value)| *word = value);
+        }
+
+        if !allocator.is_integrity_ensured() {
+            return Err(SnapshotError::Corrupted);
+        }
+        allocator.init_free_counters();
+        Ok(allocator)
+    }
+
+    /**
+     * Return the number of 64 bits words of the stored bitmaps in the following order
+     * (4kb, 2mb, 1gb, offline, holes)
+     */
+    fn snapshot_sizes(num_frames: usize) -> [usize; 5] {
+        let (tree_4kb_size, tree_2mb_size, tree_1gb_size) = Self::trees_size(num_frames);
+        [
+            tree_4kb_size,
+            tree_2mb_size,
+            tree_1gb_size,
+            Self::offline_size(num_frames),
+            Self::holes_size(num_frames),
+        ]
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Return the number of bytes of a snapshot of the allocator
+     */
+    pub fn snapshot_size(&self) -> usize {
+        let nb_words: usize = <BuddyAllocator>::snapshot_sizes(self.nb_pages).iter().sum();
+        8 * (HEADER_WORDS + nb_words + 1)
+    }
+
+    /**
+     * Write a snapshot of the allocator at the beginning of `buf` and return its length in bytes
+     * `buf` must hold at least `snapshot_size()` bytes
+     */
+    pub fn write_snapshot(&self, buf: &mut [u8]) -> usize {
+        let len = self.snapshot_size();
+        assert!(buf.len() >= len);
+
+        let sizes = <BuddyAllocator>::snapshot_sizes(self.nb_pages);
+        let header = [
+            MAGIC,
+            VERSION,
+            self.nb_pages as u64,
+            self.base.as_u64(),
+            sizes[0] as u64,
+            sizes[1] as u64,
+            sizes[2] as u64,
+            sizes[3] as u64,
+            sizes[4] as u64,
+        ];
+        let words = header
+            .iter()
+            .chain(self.tree_4kb.iter())
+            .chain(self.tree_2mb.iter())
+            .chain(self.tree_1gb.iter())
+            .chain(self.offline.iter())
+            .chain(self.holes.iter());
+        for (bytes, word) in buf.chunks_exact_mut(8).zip(words) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+
+        let checksum = checksum(&buf[..len - 8]);
+        buf[len - 8..len].copy_from_slice(&checksum.to_le_bytes());
+        len
+    }
+
+    /**
+     * Return a snapshot of the allocator, see `restore`
+     */
+    #[cfg(feature = "alloc")]
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; self.snapshot_size()];
+        self.write_snapshot(&mut buf);
+        buf
+    }
+}
+
+/**
+ * Return the word `idx` of a snapshot
+ */
+fn read_word(data: &[u8], idx: usize) -> u64 {
+    u64::from_le_bytes(data[8 * idx..8 * idx + 8].try_into().unwrap())
+}
+
+/**
+ * FNV-1a hash of `data`
+ */
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeallocError, MemoryKind, MemoryRegion};
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut frame_alloc =
+            BuddyAllocator::with_capacity(3 * GB + 1000).with_base_address(PhysAddr::new(1 << 30));
+        let frame = frame_alloc.allocate_frame().unwrap();
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        frame_alloc.offline_region(1).unwrap();
+
+        let data = frame_alloc.snapshot();
+        assert_eq!(data.len(), frame_alloc.snapshot_size());
+        assert_eq!(BuddyAllocator::snapshot_capacity(&data), Ok(3 * GB + 1000));
+        let mut restored = BuddyAllocator::restore(&data).unwrap();
+        assert_eq!(restored.base_address(), PhysAddr::new(1 << 30));
+        assert_eq!(restored.stat_free_memory(), frame_alloc.stat_free_memory());
+        assert_eq!(restored.free_frames_in_1gb(0), GB - 513);
+        assert!(!restored.is_region_online(1));
+        assert_eq!(restored.snapshot(), data);
+
+        restored.deallocate_frame(frame).unwrap();
+        restored.deallocate_big_page(big_page).unwrap();
+        restored.deallocate_huge_page(huge_page).unwrap();
+        restored.online_region(1).unwrap();
+        assert_eq!(restored.stat_free_memory(), (3, 1, 488));
+
+        // caller provided storage
+        let storage =
+            Box::leak(vec![0u64; BuddyAllocator::storage_size(3 * GB + 1000)].into_boxed_slice());
+        let restored = BuddyAllocator::restore_in(&data, storage).unwrap();
+        assert_eq!(restored.stat_free_memory(), frame_alloc.stat_free_memory());
+    }
+
+    #[test]
+    fn test_restore_rejects_bad_snapshots() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB);
+        frame_alloc.allocate_frame().unwrap();
+        let data = frame_alloc.snapshot();
+
+        let restore = |data: &[u8]| BuddyAllocator::restore(data).err();
+        assert_eq!(restore(&data[..40]), Some(SnapshotError::Truncated));
+        assert_eq!(
+            restore(&data[..data.len() - 8]),
+            Some(SnapshotError::Truncated)
+        );
+
+        let mut bad = data.clone();
+        bad[0] ^= 1;
+        assert_eq!(restore(&bad), Some(SnapshotError::BadMagic));
+        let mut bad = data.clone();
+        bad[8] = 1;
+        assert_eq!(restore(&bad), Some(SnapshotError::UnsupportedVersion));
+        let mut bad = data.clone();
+        bad[16] = 1;
+        assert_eq!(restore(&bad), Some(SnapshotError::BadGeometry));
+        let mut bad = data.clone();
+        bad[24] = 1;
+        assert_eq!(restore(&bad), Some(SnapshotError::BadGeometry));
+        let mut bad = data.clone();
+        bad[100] ^= 1;
+        assert_eq!(restore(&bad), Some(SnapshotError::BadChecksum));
+
+        // frame 0 is allocated in the 4Kb tree but free in the 2Mb tree
+        let first_block_l2 = frame_alloc.compute_first_block_index(0, 0, crate::Level::Level2);
+        frame_alloc.tree_2mb[first_block_l2] |= 1;
+        assert_eq!(
+            restore(&frame_alloc.snapshot()),
+            Some(SnapshotError::Corrupted)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_keeps_holes() {
+        let regions = [
+            MemoryRegion {
+                start: 0,
+                length: 0x9F000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x9F000,
+                length: 0x61000,
+                kind: MemoryKind::Reserved,
+            },
+            MemoryRegion {
+                start: 0x100000,
+                length: 0x3FF00000,
+                kind: MemoryKind::Usable,
+            },
+        ];
+        let mut frame_alloc = BuddyAllocator::from_memory_map(&regions);
+        let frame = frame_alloc.allocate_frame().unwrap();
+
+        let data = frame_alloc.snapshot();
+        let mut restored = BuddyAllocator::restore(&data).unwrap();
+        restored.check_integrity();
+        assert_eq!(restored.snapshot(), data);
+        assert_eq!(restored.stat_free_memory(), frame_alloc.stat_free_memory());
+
+        // frames of the holes are never freed nor handed out after a restore
+        for hole in [0x9F, 0xFF] {
+            assert_eq!(
+                restored.deallocate_frame(hole),
+                Err(DeallocError::OutOfRange)
+            );
+        }
+        restored.deallocate_frame(frame).unwrap();
+        for _ in 0..0x9F + 0x100 {
+            let frame = restored.allocate_frame().unwrap();
+            assert!(!(0x9F..0x100).contains(&frame));
+        }
+    }
+}
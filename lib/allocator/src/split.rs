@@ -0,0 +1,262 @@
+//! Split and merge of allocated pages
+//!
+//! A 2Mb page becomes 512 allocated 4Kb frames (and back), a 1Gb page becomes 512 allocated 2Mb
+//! pages (and back). Bits are rewritten in place, the frames are never seen free in between.
+
+use crate::error::DeallocError;
+use crate::{BuddyAllocator, Level, PlacementPolicy, TreeType};
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Turn the 2Mb page starting at frame `frame_id` into 512 allocated 4Kb frames, each of them
+     * is then freed with `deallocate_frame`
+     * return an error and do nothing if the page is not allocated as a 2Mb page or is shared
+     */
+    pub fn split_big_page(&mut self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_block(frame_id, 512)?;
+        match self.allocation_size(frame_id) {
+            Some(TreeType::Tree2mb) => (),
+            Some(_) => return Err(DeallocError::WrongSize),
+            None if self.get_bit_level_index(TreeType::Tree2mb, Level::Level2, frame_id) => {
+                return Err(DeallocError::NotAllocated)
+            }
+            None => return Err(DeallocError::WrongSize),
+        }
+        if self.is_shared(frame_id) {
+            return Err(DeallocError::Shared);
+        }
+
+        // 4Kb level 2 and 2Mb level 2 bits are already cleared, as for a full block of frames
+        let (l1_idx, l2_idx, _) = Self::split_index(frame_id);
+        let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+        self.tree_4kb[first_block_l3..first_block_l3 + 8].fill(0);
+        self.split_owner(frame_id, 512, 1);
+        Ok(())
+    }
+
+    /**
+     * Turn the 512 4Kb frames starting at frame `frame_id` into one allocated 2Mb page
+     * return an error and do nothing if one of the frames is not allocated as a 4Kb frame or is
+     * shared
+     */
+    pub fn merge_to_big_page(&mut self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_block(frame_id, 512)?;
+        for id in frame_id..frame_id + 512 {
+            self.check_allocated(id, TreeType::Tree4kb)?;
+        }
+        if (frame_id..frame_id + 512).any(|id| self.is_shared(id)) {
+            return Err(DeallocError::Shared);
+        }
+
+        let (l1_idx, l2_idx, _) = Self::split_index(frame_id);
+        let first_block_l3 = self.compute_first_block_index(l1_idx, l2_idx, Level::Level3);
+        self.tree_4kb[first_block_l3..first_block_l3 + 8].fill(!0);
+        self.merge_owner(frame_id, 512, 1);
+        Ok(())
+    }
+
+    /**
+     * Turn the 1Gb page starting at frame `frame_id` into 512 allocated 2Mb pages, each of them
+     * is then freed with `deallocate_big_page` or split again with `split_big_page`
+     * return an error and do nothing if the page is not allocated as a 1Gb page or is shared
+     */
+    pub fn split_huge_page(&mut self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_block(frame_id, 512 * 512)?;
+        match self.allocation_size(frame_id) {
+            Some(TreeType::Tree1gb) => (),
+            Some(_) => return Err(DeallocError::WrongSize),
+            None if self.get_bit_level_index(TreeType::Tree1gb, Level::Level1, frame_id) => {
+                return Err(DeallocError::NotAllocated)
+            }
+            None => return Err(DeallocError::WrongSize),
+        }
+        if self.is_shared(frame_id) {
+            return Err(DeallocError::Shared);
+        }
+
+        // level 1 bits are already cleared, as for a full block of 2Mb pages
+        let (l1_idx, _, _) = Self::split_index(frame_id);
+        let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+        self.tree_4kb[first_block_l2..first_block_l2 + 8].fill(0);
+        self.tree_2mb[first_block_l2..first_block_l2 + 8].fill(0);
+        self.set_2mb_counters(l1_idx, 0);
+        self.split_owner(frame_id, 512 * 512, 512);
+        Ok(())
+    }
+
+    /**
+     * Turn the 512 2Mb pages starting at frame `frame_id` into one allocated 1Gb page
+     * return an error and do nothing if one of the pages is not allocated as a 2Mb page or is
+     * shared
+     */
+    pub fn merge_to_huge_page(&mut self, frame_id: usize) -> Result<(), DeallocError> {
+        self.check_block(frame_id, 512 * 512)?;
+        for id in (frame_id..frame_id + 512 * 512).step_by(512) {
+            match self.allocation_size(id) {
+                Some(TreeType::Tree2mb) => (),
+                Some(_) => return Err(DeallocError::WrongSize),
+                None => return Err(DeallocError::NotAllocated),
+            }
+        }
+        if (frame_id..frame_id + 512 * 512)
+            .step_by(512)
+            .any(|id| self.is_shared(id))
+        {
+            return Err(DeallocError::Shared);
+        }
+
+        let (l1_idx, _, _) = Self::split_index(frame_id);
+        let first_block_l2 = self.compute_first_block_index(l1_idx, 0, Level::Level2);
+        self.tree_4kb[first_block_l2..first_block_l2 + 8].fill(!0);
+        self.tree_2mb[first_block_l2..first_block_l2 + 8].fill(!0);
+        self.set_2mb_counters(l1_idx, 512);
+        self.merge_owner(frame_id, 512 * 512, 512);
+        Ok(())
+    }
+
+    /**
+     * Check that the `nb_frames` frames starting at frame `frame_id` are an aligned block inside
+     * the managed range
+     */
+    fn check_block(&self, frame_id: usize, nb_frames: usize) -> Result<(), DeallocError> {
+        if frame_id >= self.nb_pages || self.is_offline(frame_id) {
+            return Err(DeallocError::OutOfRange);
+        }
+        if !frame_id.is_multiple_of(nb_frames) {
+            return Err(DeallocError::Misaligned);
+        }
+        if frame_id + nb_frames > self.nb_pages {
+            return Err(DeallocError::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemoryKind, MemoryRegion};
+
+    const GB: usize = 512 * 512;
+
+    #[test]
+    fn test_split_and_merge_big_page() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(GB);
+        let big_page = frame_alloc.allocate_big_page().unwrap();
+        assert_eq!(
+            frame_alloc.merge_to_big_page(big_page),
+            Err(DeallocError::WrongSize)
+        );
+        frame_alloc.split_big_page(big_page).unwrap();
+        assert_eq!(
+            frame_alloc.split_big_page(big_page),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(
+            frame_alloc.deallocate_big_page(big_page),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(frame_alloc.free_frames_in_2mb(big_page), 0);
+        frame_alloc.check_integrity();
+
+        // the frames are freed one by one, the first free frame is then the first one freed
+        frame_alloc.deallocate_frame(big_page + 7).unwrap();
+        assert_eq!(frame_alloc.allocate_frame(), Some(big_page + 7));
+        frame_alloc.merge_to_big_page(big_page).unwrap();
+        frame_alloc.check_integrity();
+        frame_alloc.deallocate_big_page(big_page).unwrap();
+        assert_eq!(frame_alloc.stat_free_memory(), (1, 0, 0));
+
+        // merging requires the 512 frames to be allocated as 4Kb frames
+        frame_alloc.reserve_range(0, 511).unwrap();
+        assert_eq!(
+            frame_alloc.merge_to_big_page(0),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(
+            frame_alloc.merge_to_big_page(1),
+            Err(DeallocError::Misaligned)
+        );
+        assert_eq!(frame_alloc.split_big_page(0), Err(DeallocError::WrongSize));
+        assert_eq!(
+            frame_alloc.split_big_page(512),
+            Err(DeallocError::NotAllocated)
+        );
+        frame_alloc.allocate_frame_at(511).unwrap();
+        frame_alloc.merge_to_big_page(0).unwrap();
+        assert_eq!(frame_alloc.allocate_frame(), Some(512));
+    }
+
+    #[test]
+    fn test_split_and_merge_huge_page() {
+        let mut frame_alloc = BuddyAllocator::with_capacity(2 * GB);
+        let huge_page = frame_alloc.allocate_huge_page().unwrap();
+        frame_alloc.split_huge_page(huge_page).unwrap();
+        assert_eq!(
+            frame_alloc.deallocate_huge_page(huge_page),
+            Err(DeallocError::WrongSize)
+        );
+        assert_eq!(frame_alloc.allocate_huge_page(), Some(0));
+        assert_eq!(frame_alloc.allocate_big_page(), None);
+        frame_alloc.check_integrity();
+
+        // a 2Mb page of the split 1Gb page is split again and freed frame by frame
+        let big_page = huge_page + 1024;
+        frame_alloc.split_big_page(big_page).unwrap();
+        assert_eq!(
+            frame_alloc.merge_to_huge_page(huge_page),
+            Err(DeallocError::WrongSize)
+        );
+        for frame in big_page..big_page + 512 {
+            frame_alloc.deallocate_frame(frame).unwrap();
+        }
+        assert_eq!(frame_alloc.free_frames_in_2mb(big_page), 512);
+        assert_eq!(frame_alloc.free_frames_in_1gb(big_page), 512);
+        assert_eq!(
+            frame_alloc.merge_to_huge_page(huge_page),
+            Err(DeallocError::NotAllocated)
+        );
+        assert_eq!(frame_alloc.allocate_big_page(), Some(big_page));
+
+        frame_alloc.merge_to_huge_page(huge_page).unwrap();
+        assert_eq!(frame_alloc.free_frames_in_1gb(huge_page), 0);
+        frame_alloc.check_integrity();
+        frame_alloc.deallocate_huge_page(0).unwrap();
+        frame_alloc.deallocate_huge_page(huge_page).unwrap();
+        assert_eq!(frame_alloc.free_frames_in_2mb(big_page), 512);
+        assert_eq!(frame_alloc.stat_free_memory(), (2, 0, 0));
+    }
+
+    #[test]
+    fn test_merge_with_holes() {
+        let regions = [
+            MemoryRegion {
+                start: 0,
+                length: 0x9F000,
+                kind: MemoryKind::Usable,
+            },
+            MemoryRegion {
+                start: 0x9F000,
+                length: 0x61000,
+                kind: MemoryKind::Reserved,
+            },
+            MemoryRegion {
+                start: 0x100000,
+                length: 0x3FF00000,
+                kind: MemoryKind::Usable,
+            },
+        ];
+        let mut frame_alloc = BuddyAllocator::from_memory_map(&regions);
+        for _ in 0..0x9F + 0x100 {
+            let frame = frame_alloc.allocate_frame().unwrap();
+            assert!(frame < 0x9F || (0x100..0x200).contains(&frame));
+        }
+
+        // every usable frame of the first 2Mb block is allocated, the holes are not
+        assert_eq!(
+            frame_alloc.merge_to_big_page(0),
+            Err(DeallocError::OutOfRange)
+        );
+        frame_alloc.check_integrity();
+    }
+}
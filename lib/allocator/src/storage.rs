@@ -0,0 +1,68 @@
+//! Backing storage of the allocator trees, free counters, offline 1Gb blocks and memory map holes
+
+use core::ops::{Deref, DerefMut};
+
+#[cfg(feature = "alloc")]
+use alloc::{boxed::Box, vec};
+
+/**
+ * Bitmap of one tree, either allocated on the heap (hosted use) or placed in memory provided
+ * by the caller (e.g. carved out of physical memory during boot)
+ */
+pub(crate) enum TreeStorage {
+    #[cfg(feature = "alloc")]
+    Boxed(Box<[u64]>),
+    Borrowed(&'static mut [u64]),
+}
+
+/**
+ * Storage of an allocator in the following order (4kb, 2mb, 1gb, counters, offline, holes)
+ */
+pub(crate) type Trees = (
+    TreeStorage,
+    TreeStorage,
+    TreeStorage,
+    TreeStorage,
+    TreeStorage,
+    TreeStorage,
+);
+
+impl TreeStorage {
+    /**
+     * Allocate a zeroed bitmap of `size` words on the heap
+     */
+    #[cfg(feature = "alloc")]
+    pub(crate) fn boxed(size: usize) -> Self {
+        TreeStorage::Boxed(vec![0u64; size].into_boxed_slice())
+    }
+
+    /**
+     * Use the given memory as a bitmap, it is zeroed
+     */
+    pub(crate) fn borrowed(storage: &'static mut [u64]) -> Self {
+        storage.fill(0);
+        TreeStorage::Borrowed(storage)
+    }
+}
+
+impl Deref for TreeStorage {
+    type Target = [u64];
+
+    fn deref(&self) -> &[u64] {
+        match self {
+            #[cfg(feature = "alloc")]
+            TreeStorage::Boxed(tree) => tree,
+            TreeStorage::Borrowed(tree) => tree,
+        }
+    }
+}
+
+impl DerefMut for TreeStorage {
+    fn deref_mut(&mut self) -> &mut [u64] {
+        match self {
+            #[cfg(feature = "alloc")]
+            TreeStorage::Boxed(tree) => tree,
+            TreeStorage::Borrowed(tree) => tree,
+        }
+    }
+}
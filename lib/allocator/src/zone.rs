@@ -0,0 +1,446 @@
+//! Memory zones constraining the physical address of allocated pages
+//!
+//! Legacy devices can only address memory below 16Mb (`Dma`) and 32 bits devices memory below
+//! 4Gb (`Dma32`). Ordinary allocations should use `Normal` so that low memory stays available
+//! for those devices.
+
+use core::ops::Range;
+
+use crate::addr::FRAME_SIZE;
+use crate::error::OwnerAllocError;
+use crate::{BuddyAllocator, Level, PlacementPolicy, TreeType};
+
+/**
+ * Physical memory zone
+ * Dma: [0, 16Mb)
+ * Dma32: [16Mb, 4Gb)
+ * Normal: [4Gb, end of memory)
+ */
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Zone {
+    Dma,
+    Dma32,
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    /**
+     * Return the physical address range covered by the zone
+     */
+    pub fn address_range(self) -> Range<u64> {
+        match self {
+            Zone::Dma => 0..16 << 20,
+            Zone::Dma32 => 16 << 20..4 << 30,
+            Zone::Normal => 4 << 30..u64::MAX,
+        }
+    }
+
+    /**
+     * Return the zone containing physical address `addr`
+     */
+    pub fn of_address(addr: u64) -> Zone {
+        if addr < Zone::Dma.address_range().end {
+            Zone::Dma
+        } else if addr < Zone::Dma32.address_range().end {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+}
+
+impl<P: PlacementPolicy> BuddyAllocator<P> {
+    /**
+     * Return the range of frame indexes of a zone, empty if the zone is not managed
+     */
+    pub fn zone_frames(&self, zone: Zone) -> Range<usize> {
+        let range = zone.address_range();
+        let to_frame = |addr: u64| {
+            (addr.saturating_sub(self.base.as_u64()) / FRAME_SIZE).min(self.nb_pages as u64)
+                as usize
+        };
+        to_frame(range.start)..to_frame(range.end)
+    }
+
+    /**
+     * Return the number of free 4Kb frames in a zone
+     */
+    pub fn zone_free_frames(&self, zone: Zone) -> usize {
+        self.zone_free[zone as usize]
+    }
+
+    /**
+     * Allocate 4kb page inside a zone, the frame is chosen by the placement policy
+     * the page is charged to the current owner (see `set_current_owner`)
+     * return None if allocation fails or if the owner would exceed its hard limit
+     */
+    pub fn allocate_frame_in(&mut self, zone: Zone) -> Option<usize> {
+        self.try_allocate_frame_in(zone).ok()
+    }
+
+    /**
+     * Same as `allocate_frame_in` but return an error telling whether no frame of the zone is free
+     * or the current owner would exceed its hard limit
+     */
+    pub fn try_allocate_frame_in(&mut self, zone: Zone) -> Result<usize, OwnerAllocError> {
+        self.allocate_charged(TreeType::Tree4kb, self.current_owner, |allocator| {
+            allocator.claim_frame_in(zone)
+        })
+    }
+
+    /**
+     * Take a free page of `allocate_frame_in` in the trees
+     */
+    fn claim_frame_in(&mut self, zone: Zone) -> Option<usize> {
+        let range = self.zone_frames(zone);
+        let page = TreeType::Tree4kb;
+        self.try_level1_in(page, &range, |allocator, l1_idx| {
+            let l2_window = Self::window(&range, l1_idx << 18, 9);
+            allocator.try_blocks_in(
+                page,
+                Level::Level2,
+                l1_idx,
+                0,
+                l2_window,
+                |allocator, l2_idx| {
+                    let l3_window = Self::window(&range, (l1_idx << 18) + (l2_idx << 9), 0);
+                    let l3_idx = allocator.select_block_in(
+                        page,
+                        Level::Level3,
+                        l1_idx,
+                        l2_idx,
+                        l3_window,
+                    )?;
+                    allocator.mark_frame_allocated(l1_idx, l2_idx, l3_idx);
+                    Some((l1_idx << 18) + (l2_idx << 9) + l3_idx)
+                },
+            )
+        })
+    }
+
+    /**
+     * Allocate 2Mb page entirely inside a zone, the page is chosen by the placement policy
+     * the page is charged to the current owner (see `set_current_owner`)
+     * return None if allocation fails or if the owner would exceed its hard limit
+     */
+    pub fn allocate_big_page_in(&mut self, zone: Zone) -> Option<usize> {
+        self.try_allocate_big_page_in(zone).ok()
+    }
+
+    /**
+     * Same as `allocate_big_page_in` but return an error telling whether no big page of the
+     * zone is free or the current owner would exceed its hard limit
+     */
+    pub fn try_allocate_big_page_in(&mut self, zone: Zone) -> Result<usize, OwnerAllocError> {
+        self.allocate_charged(TreeType::Tree2mb, self.current_owner, |allocator| {
+            allocator.claim_big_page_in(zone)
+        })
+    }
+
+    /**
+     * Take a free page of `allocate_big_page_in` in the trees
+     */
+    fn claim_big_page_in(&mut self, zone: Zone) -> Option<usize> {
+        // only 2Mb blocks entirely inside the zone
+        let range = self.zone_frames(zone);
+        let range = range.start.next_multiple_of(512)..range.end / 512 * 512;
+        let page = TreeType::Tree2mb;
+        self.try_level1_in(page, &range, |allocator, l1_idx| {
+            let l2_window = Self::window(&range, l1_idx << 18, 9);
+            let l2_idx = allocator.select_block_in(page, Level::Level2, l1_idx, 0, l2_window)?;
+            allocator.mark_big_page_allocated(l1_idx, l2_idx);
+            Some((l1_idx << 18) + (l2_idx << 9))
+        })
+    }
+
+    /**
+     * Allocate 1Gb page entirely inside a zone, the page is chosen by the placement policy
+     * the page is charged to the current owner (see `set_current_owner`)
+     * return None if allocation fails or if the owner would exceed its hard limit
+     */
+    pub fn allocate_huge_page_in(&mut self, zone: Zone) -> Option<usize> {
+        self.try_allocate_huge_page_in(zone).ok()
+    }
+
+    /**
+     * Same as `allocate_huge_page_in` but return an error telling whether no huge page of the
+     * zone is free or the current owner would exceed its hard limit
+     */
+    pub fn try_allocate_huge_page_in(&mut self, zone: Zone) -> Result<usize, OwnerAllocError> {
+        self.allocate_charged(TreeType::Tree1gb, self.current_owner, |allocator| {
+            allocator.claim_huge_page_in(zone)
+        })
+    }
+
+    /**
+     * Take a free page of `allocate_huge_page_in` in the trees
+     */
+    fn claim_huge_page_in(&mut self, zone: Zone) -> Option<usize> {
+        // only 1Gb blocks entirely inside the zone
+        let range = self.zone_frames(zone);
+        let range = range.start.next_multiple_of(512 * 512)..range.end / (512 * 512) * (512 * 512);
+        self.try_level1_in(TreeType::Tree1gb, &range, |allocator, l1_idx| {
+            allocator.mark_huge_page_allocated(l1_idx);
+            Some(l1_idx << 18)
+        })
+    }
+
+    /**
+     * Same as `try_blocks_in` for the 1Gb blocks intersecting the frames of `range`, going
+     * through level 0, `found` is called with the index of the 1Gb block
+     */
+    fn try_level1_in<T>(
+        &mut self,
+        page: TreeType,
+        range: &Range<usize>,
+        mut found: impl FnMut(&mut Self, usize) -> Option<T>,
+    ) -> Option<T> {
+        let l0_window = Self::window(range, 0, 27);
+        self.try_blocks_in(page, Level::Level0, 0, 0, l0_window, |allocator, l0_idx| {
+            let l1_window = Self::window(range, l0_idx << 27, 18);
+            allocator.try_blocks_in(
+                page,
+                Level::Level1,
+                l0_idx << 9,
+                0,
+                l1_window,
+                |allocator, idx| found(allocator, (l0_idx << 9) + idx),
+            )
+        })
+    }
+
+    /**
+     * Ask the placement policy for one block of a 512 bits block of the tree of `page` at a
+     * position in `window`
+     */
+    fn select_block_in(
+        &mut self,
+        page: TreeType,
+        level: Level,
+        l1_idx: usize,
+        l2_idx: usize,
+        window: Range<usize>,
+    ) -> Option<usize> {
+        self.try_blocks_in(page, level, l1_idx, l2_idx, window, |_, idx| Some(idx))
+    }
+
+    /**
+     * Ask the placement policy for blocks of a 512 bits block of the tree of `page`, only
+     * blocks at a position in `window` are candidates
+     * `found` is called on each selected block until it returns Some, the block is then no
+     * longer a candidate
+     */
+    pub(crate) fn try_blocks_in<T>(
+        &mut self,
+        page: TreeType,
+        level: Level,
+        l1_idx: usize,
+        l2_idx: usize,
+        window: Range<usize>,
+        mut found: impl FnMut(&mut Self, usize) -> Option<T>,
+    ) -> Option<T> {
+        let start_idx = self.compute_first_block_index(l1_idx, l2_idx, level);
+        let block = &self.tree(page)[start_idx..start_idx + 8];
+        let mut free = [0u64; 8];
+        for (i, word) in free.iter_mut().enumerate() {
+            let lo = window.start.clamp(64 * i, 64 * i + 64) - 64 * i;
+            let hi = window.end.clamp(64 * i, 64 * i + 64) - 64 * i;
+            if lo < hi {
+                *word = block[i] & (!0u64 >> (64 - (hi - lo))) << lo;
+            }
+        }
+        while free != [0; 8] {
+            let idx = self.select_among(page, level, l1_idx, l2_idx, &free);
+            if let Some(result) = found(self, idx) {
+                return Some(result);
+            }
+            free[idx / 64] &= !(1u64 << (idx % 64));
+        }
+        None
+    }
+
+    /**
+     * Return the positions of the blocks of a 512 bits block intersecting the frames of
+     * `range`, the block covers frames from `first_frame` and each of its blocks
+     * `1 << shift` frames
+     */
+    fn window(range: &Range<usize>, first_frame: usize, shift: usize) -> Range<usize> {
+        if range.end <= first_frame.max(range.start) {
+            return 0..0;
+        }
+        let lo = range.start.saturating_sub(first_frame) >> shift;
+        let hi = ((range.end - 1 - first_frame) >> shift) + 1;
+        lo.min(512)..hi.min(512)
+    }
+
+    /**
+     * Update the free frames of the zones when `nb_frames` frames starting at `first_frame` are
+     * freed or allocated, a page spans at most two zones
+     */
+    pub(crate) fn account_zone_frames(
+        &mut self,
+        first_frame: usize,
+        nb_frames: usize,
+        freed: bool,
+    ) {
+        let end = first_frame + nb_frames;
+        let mut start = first_frame;
+        while start < end {
+            let zone = Zone::of_address(self.base.as_u64() + start as u64 * FRAME_SIZE);
+            let zone_end = end.min(self.zone_frames(zone).end);
+            if freed {
+                self.zone_free[zone as usize] += zone_end - start;
+            } else {
+                self.zone_free[zone as usize] -= zone_end - start;
+            }
+            start = zone_end;
+        }
+    }
+
+    /**
+     * Compute the free frames of the zones from the trees
+     */
+    pub(crate) fn init_zone_counters(&mut self) {
+        for zone in Zone::ALL {
+            self.zone_free[zone as usize] = self.count_free_frames(self.zone_frames(zone));
+        }
+    }
+
+    /**
+     * Count free 4Kb frames in a range of frame indexes by scanning the trees
+     */
+    pub(crate) fn count_free_frames(&self, range: Range<usize>) -> usize {
+        let first_block_l3 = self.compute_first_block_index(0, 0, Level::Level3);
+        let mut nb_free = 0;
+        let mut i = range.start;
+        while i < range.end {
+            // frames of big and huge pages keep their level 3 bit set
+            if self
+                .allocation_size(i)
+                .is_some_and(|size| size != TreeType::Tree4kb)
+            {
+                i = (i / 512 + 1) * 512;
+                continue;
+            }
+            let nb_bits = (64 - i % 64).min(range.end - i).min(512 - i % 512);
+            let mask = (!0u64 >> (64 - nb_bits)) << (i % 64);
+            nb_free += (self.tree_4kb[first_block_l3 + i / 64] & mask).count_ones() as usize;
+            i += nb_bits;
+        }
+        nb_free
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::HUGE_PAGE_SIZE;
+    use crate::{FirstFitHigh, PhysAddr};
+
+    const GB: usize = 512 * 512;
+
+    fn assert_counters<P: PlacementPolicy>(frame_alloc: &BuddyAllocator<P>) {
+        for zone in Zone::ALL {
+            let range = frame_alloc.zone_frames(zone);
+            assert_eq!(
+                frame_alloc.zone_free_frames(zone),
+                frame_alloc.count_free_frames(range)
+            );
+        }
+    }
+
+    #[test]
+    fn test_zone_allocation() {
+        let mut frame_alloc = Box::new(BuddyAllocator::with_capacity(6 * GB));
+        assert_eq!(frame_alloc.zone_frames(Zone::Dma), 0..4096);
+        assert_eq!(frame_alloc.zone_frames(Zone::Dma32), 4096..4 * GB);
+        assert_eq!(frame_alloc.zone_frames(Zone::Normal), 4 * GB..6 * GB);
+        assert_eq!(frame_alloc.zone_free_frames(Zone::Dma), 4096);
+        assert_eq!(frame_alloc.zone_free_frames(Zone::Dma32), 4 * GB - 4096);
+        assert_eq!(frame_alloc.zone_free_frames(Zone::Normal), 2 * GB);
+
+        assert_eq!(frame_alloc.allocate_frame_in(Zone::Normal), Some(4 * GB));
+        assert_eq!(frame_alloc.allocate_frame_in(Zone::Dma32), Some(4096));
+        assert_eq!(frame_alloc.allocate_frame_in(Zone::Dma), Some(0));
+        assert_eq!(frame_alloc.allocate_big_page_in(Zone::Dma), Some(512));
+        assert_eq!(
+            frame_alloc.allocate_big_page_in(Zone::Dma32),
+            Some(4096 + 512)
+        );
+        // the first 1Gb block is split between Dma and Dma32
+        assert_eq!(frame_alloc.allocate_huge_page_in(Zone::Dma), None);
+        assert_eq!(frame_alloc.allocate_huge_page_in(Zone::Dma32), Some(3 * GB));
+        assert_eq!(
+            frame_alloc.allocate_huge_page_in(Zone::Normal),
+            Some(5 * GB)
+        );
+        assert_eq!(frame_alloc.allocate_huge_page_in(Zone::Normal), None);
+        frame_alloc.check_integrity();
+        assert_counters(&frame_alloc);
+
+        // exhaust Dma, other zones are untouched
+        for _ in 0..4096 - 1 - 512 {
+            let frame = frame_alloc.allocate_frame_in(Zone::Dma).unwrap();
+            assert!(frame < 4096);
+        }
+        assert_eq!(frame_alloc.allocate_frame_in(Zone::Dma), None);
+        assert_eq!(frame_alloc.allocate_big_page_in(Zone::Dma), None);
+        assert_eq!(frame_alloc.zone_free_frames(Zone::Dma), 0);
+        assert_counters(&frame_alloc);
+
+        frame_alloc.deallocate_big_page(512).unwrap();
+        frame_alloc.deallocate_huge_page(3 * GB).unwrap();
+        frame_alloc.deallocate_frame(4 * GB).unwrap();
+        assert_eq!(frame_alloc.zone_free_frames(Zone::Dma), 512);
+        assert_eq!(frame_alloc.zone_free_frames(Zone::Normal), GB);
+        frame_alloc.check_integrity();
+        assert_counters(&frame_alloc);
+    }
+
+    #[test]
+    fn test_zones_with_base_address() {
+        let base = PhysAddr::new(2 * HUGE_PAGE_SIZE);
+        let mut frame_alloc =
+            Box::new(BuddyAllocator::with_capacity(4 * GB).with_base_address(base));
+        assert!(frame_alloc.zone_frames(Zone::Dma).is_empty());
+        assert_eq!(frame_alloc.zone_frames(Zone::Dma32), 0..2 * GB);
+        assert_eq!(frame_alloc.zone_free_frames(Zone::Normal), 2 * GB);
+        assert_eq!(frame_alloc.allocate_frame_in(Zone::Dma), None);
+        assert_eq!(frame_alloc.allocate_big_page_in(Zone::Normal), Some(2 * GB));
+        assert_eq!(frame_alloc.allocate_huge_page_in(Zone::Dma32), Some(GB));
+        assert_counters(&frame_alloc);
+    }
+
+    #[test]
+    fn test_zone_allocation_with_policy() {
+        let mut frame_alloc =
+            Box::new(BuddyAllocator::with_capacity(6 * GB).with_policy(FirstFitHigh));
+        assert_eq!(Zone::of_address(16 << 20), Zone::Dma32);
+        assert_eq!(Zone::of_address(HUGE_PAGE_SIZE * 4), Zone::Normal);
+
+        assert_eq!(frame_alloc.allocate_frame_in(Zone::Dma), Some(4095));
+        assert_eq!(frame_alloc.allocate_frame_in(Zone::Dma32), Some(4 * GB - 1));
+        assert_eq!(
+            frame_alloc.allocate_big_page_in(Zone::Dma),
+            Some(4096 - 1024)
+        );
+        assert_eq!(
+            frame_alloc.allocate_big_page_in(Zone::Dma32),
+            Some(4 * GB - 1024)
+        );
+        assert_eq!(frame_alloc.allocate_huge_page_in(Zone::Dma32), Some(GB));
+        assert_eq!(
+            frame_alloc.allocate_huge_page_in(Zone::Normal),
+            Some(4 * GB)
+        );
+        frame_alloc.check_integrity();
+        assert_counters(&frame_alloc);
+
+        frame_alloc.deallocate_frame(4095).unwrap();
+        frame_alloc.deallocate_huge_page(GB).unwrap();
+        assert_eq!(frame_alloc.zone_free_frames(Zone::Dma), 4096 - 512);
+        assert_counters(&frame_alloc);
+    }
+}
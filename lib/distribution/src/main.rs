@@ -1,18 +1,19 @@
+use allocator::{BestFit, FirstFitHigh, FirstFitLow, PlacementPolicy, Random};
 use csv::Writer;
-use image::{ImageBuffer, Rgb, RgbImage};
 use indicatif::ProgressBar;
 use rand::distributions::{Bernoulli, Distribution};
 use rand::prelude::IteratorRandom;
-use rand::rngs::{StdRng, ThreadRng};
+use rand::rngs::StdRng;
 use rand::SeedableRng;
 use statrs::distribution::DiscreteCDF;
 use statrs::distribution::Poisson;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
 fn main() {
     //save_plot_distribution(70.0);
     //no_internal_fragmentation(70.0, 512);
-    custom_allocator(70.0);
+    //custom_allocator(70.0, FirstFitLow, "first_fit_low");
+    compare_placement_policies(70.0);
 }
 
 /**
@@ -24,22 +25,54 @@ fn choose(raw: &mut Vec<usize>, rnd: &mut StdRng) -> Option<usize> {
     Some(raw.swap_remove(i))
 }
 
+/**
+ * Simulate the custom buddy allocator with each placement policy on the same workload
+ * the 1gb_free and 2mb_free columns of each csv show which policy keeps the most blocks intact
+ *
+ * lambda: threshold memory objective  (0;100)
+ */
+#[allow(dead_code)]
+fn compare_placement_policies(lambda: f64) {
+    let results = [
+        custom_allocator(lambda, FirstFitLow, "first_fit_low"),
+        custom_allocator(lambda, FirstFitHigh, "first_fit_high"),
+        custom_allocator(lambda, BestFit, "best_fit"),
+        custom_allocator(lambda, Random::new(222), "random"),
+    ];
+
+    for (name, (free_1gb, free_2mb, free_4kb)) in results {
+        println!(
+            "{}: {} free 1gb, {} free 2mb, {} free 4kb",
+            name, free_1gb, free_2mb, free_4kb
+        );
+    }
+}
+
 /**
  * Simulate the custom buddy allocator
+ * results are saved in custom_allocator_<name>.csv and output_<name>.png
+ * return the name and the free memory at the end of the simulation
  *
  * lambda: threshold memory objective  (0;100)
+ * policy: placement policy of the allocator
  */
 #[allow(dead_code)]
-fn custom_allocator(lambda: f64) {
+fn custom_allocator<P: PlacementPolicy>(
+    lambda: f64,
+    policy: P,
+    name: &str,
+) -> (&str, (u64, u64, u64)) {
     assert!(0.0 < lambda && lambda < 100.0);
 
     let num_gb = 512;
 
-    let mut frame_alloc = Box::new(allocator::BuddyAllocator::new());
+    let mut frame_alloc = Box::new(
+        allocator::BuddyAllocator::with_capacity((num_gb * 512 * 512) as usize).with_policy(policy),
+    );
 
     let poisson = Poisson::new(lambda).unwrap();
-    let mut wtr = Writer::from_path("custom_allocator.csv").unwrap();
-    wtr.write_record(&[
+    let mut wtr = Writer::from_path(format!("custom_allocator_{}.csv", name)).unwrap();
+    wtr.write_record([
         "time",
         "4kb_alloc",
         "2mb_alloc",
@@ -84,7 +117,7 @@ fn custom_allocator(lambda: f64) {
         if t % (number_iterations / 1000) == 0 {
             bar.inc(1);
 
-            let (free_1gb, free_2mb, free_4kb) = frame_alloc.stat_free_memory();
+            let (free_1gb, free_2mb, free_4kb) = frame_alloc.free_memory();
             wtr.write_record(&[
                 t.to_string(),
                 allocated_4kb.to_string(),
@@ -96,34 +129,29 @@ fn custom_allocator(lambda: f64) {
             ])
             .unwrap();
 
-            let spatial_stats = frame_alloc.spatial_stat_memory();
-            for idx in (0..spatial_stats.len()).step_by(512) {
-                let mut freq_free = 0;
-                let mut freq_4kb = 0;
-                let mut freq_2mb = 0;
-                let mut freq_1gb = 0;
-
-                for i in 0..512 {
-                    let blk_type = *spatial_stats.get(idx + i).unwrap();
-                    match blk_type {
-                        0 => freq_free += 1,
-                        1 => freq_4kb += 1,
-                        2 => freq_2mb += 1,
-                        3 => freq_1gb += 1,
-                        _ => (),
-                    };
-                }
-
-                let r = (255.0 * (freq_free + freq_2mb + freq_1gb) as f32 / 512.0) as u8;
-                let g = (((255 * freq_free) + (69 * freq_4kb) + (66 * freq_2mb) + (212 * freq_1gb))
-                    as f32
-                    / 512.0) as u8;
-                let b = (((255 * freq_free) + (134 * freq_4kb) + (14 * freq_2mb) + (32 * freq_1gb))
-                    as f32
-                    / 512.0) as u8;
-
-                *(imgbuf.get_pixel_mut(img_x, (idx / 512).try_into().unwrap())) =
-                    image::Rgb([r, g, b])
+            // one row per 1Gb block, colored by the share of frames in each state, holes are black
+            let occupancy = frame_alloc.occupancy_map(512 * 512);
+            for (idx, bucket) in occupancy.iter().enumerate() {
+                let nb_frames = (bucket.free
+                    + bucket.allocated_4kb
+                    + bucket.allocated_2mb
+                    + bucket.allocated_1gb
+                    + bucket.holes) as f32;
+
+                let r = (255.0 * (bucket.free + bucket.allocated_2mb + bucket.allocated_1gb) as f32
+                    / nb_frames) as u8;
+                let g = (((255 * bucket.free)
+                    + (69 * bucket.allocated_4kb)
+                    + (66 * bucket.allocated_2mb)
+                    + (212 * bucket.allocated_1gb)) as f32
+                    / nb_frames) as u8;
+                let b = (((255 * bucket.free)
+                    + (134 * bucket.allocated_4kb)
+                    + (14 * bucket.allocated_2mb)
+                    + (32 * bucket.allocated_1gb)) as f32
+                    / nb_frames) as u8;
+
+                *(imgbuf.get_pixel_mut(img_x, idx.try_into().unwrap())) = image::Rgb([r, g, b])
             }
             img_x += 1;
         }
@@ -138,8 +166,8 @@ fn custom_allocator(lambda: f64) {
                 let start = Instant::now();
                 let frame = frame_alloc.allocate_frame();
                 tot_time += start.elapsed().as_nanos();
-                if frame.is_some() {
-                    allocated_4kb_ids.push(frame.unwrap());
+                if let Some(frame) = frame {
+                    allocated_4kb_ids.push(frame);
                     free_num_4kb_blocks -= 1;
                     allocated_4kb += 1;
                 } else {
@@ -149,8 +177,8 @@ fn custom_allocator(lambda: f64) {
                 let start = Instant::now();
                 let frame = frame_alloc.allocate_big_page();
                 tot_time += start.elapsed().as_nanos();
-                if frame.is_some() {
-                    allocated_2mb_ids.push(frame.unwrap());
+                if let Some(frame) = frame {
+                    allocated_2mb_ids.push(frame);
                     free_num_4kb_blocks -= 512;
                     allocated_2mb += 1;
                 } else {
@@ -160,8 +188,8 @@ fn custom_allocator(lambda: f64) {
                 let start = Instant::now();
                 let frame = frame_alloc.allocate_huge_page();
                 tot_time += start.elapsed().as_nanos();
-                if frame.is_some() {
-                    allocated_1gb_ids.push(frame.unwrap());
+                if let Some(frame) = frame {
+                    allocated_1gb_ids.push(frame);
                     free_num_4kb_blocks -= 512 * 512;
                     allocated_1gb += 1;
                 } else {
@@ -172,7 +200,9 @@ fn custom_allocator(lambda: f64) {
             if prob_4kb_ber.sample(&mut rng) {
                 if allocated_4kb > 0 {
                     let start = Instant::now();
-                    frame_alloc.deallocate_frame(choose(&mut allocated_4kb_ids, &mut rng).unwrap());
+                    frame_alloc
+                        .deallocate_frame(choose(&mut allocated_4kb_ids, &mut rng).unwrap())
+                        .unwrap();
                     tot_time += start.elapsed().as_nanos();
                     free_num_4kb_blocks += 1;
                     allocated_4kb -= 1;
@@ -181,7 +211,8 @@ fn custom_allocator(lambda: f64) {
                 if allocated_2mb > 0 {
                     let start = Instant::now();
                     frame_alloc
-                        .deallocate_big_page(choose(&mut allocated_2mb_ids, &mut rng).unwrap());
+                        .deallocate_big_page(choose(&mut allocated_2mb_ids, &mut rng).unwrap())
+                        .unwrap();
                     tot_time += start.elapsed().as_nanos();
                     free_num_4kb_blocks += 512;
                     allocated_2mb -= 1;
@@ -191,7 +222,8 @@ fn custom_allocator(lambda: f64) {
                 if allocated_1gb > 0 {
                     let start = Instant::now();
                     frame_alloc
-                        .deallocate_huge_page(choose(&mut allocated_1gb_ids, &mut rng).unwrap());
+                        .deallocate_huge_page(choose(&mut allocated_1gb_ids, &mut rng).unwrap())
+                        .unwrap();
                     tot_time += start.elapsed().as_nanos();
                     free_num_4kb_blocks += 512 * 512;
                     allocated_1gb -= 1;
@@ -202,13 +234,15 @@ fn custom_allocator(lambda: f64) {
     }
 
     // Save the image as “fractal.png”, the format is deduced from the path
-    imgbuf.save("output.png").unwrap();
+    imgbuf.save(format!("output_{}.png", name)).unwrap();
 
     bar.finish();
 
     println!("time taken in nano: {}", tot_time);
 
     wtr.flush().unwrap();
+
+    (name, frame_alloc.free_memory())
 }
 
 /**
@@ -225,7 +259,7 @@ fn no_internal_fragmentation(lambda: f64, num_gb: u64) {
 
     let poisson = Poisson::new(lambda).unwrap();
     let mut wtr = Writer::from_path("no_internal_frag_usage.csv").unwrap();
-    let _ = wtr.write_record(&[
+    let _ = wtr.write_record([
         "time",
         "4kb_alloc",
         "2mb_alloc",
@@ -337,10 +371,10 @@ fn save_plot_distribution(lambda: f64) {
     let n = Poisson::new(lambda).unwrap();
 
     let mut wtr = Writer::from_path("distribution.csv").unwrap();
-    let _ = wtr.write_record(&["percentage used", "prob"]);
+    let _ = wtr.write_record(["percentage used", "prob"]);
 
     for i in 0..101 {
-        let _ = wtr.write_record(&[i.to_string(), n.sf(i).to_string()]);
+        let _ = wtr.write_record([i.to_string(), n.sf(i).to_string()]);
     }
 
     let _ = wtr.flush();